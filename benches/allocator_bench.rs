@@ -4,12 +4,31 @@ use criterion::{
   criterion_main,
 };
 use std::{
-  alloc::Layout,
+  alloc::{
+    GlobalAlloc,
+    Layout,
+  },
   hint::black_box,
   ptr::NonNull,
 };
+use tinyalloc::TinyAlloc;
 use tinyalloc_alloc::heap::Heap;
 
+const VEC_GROWTH_LIMIT: usize = 8 * 1024 * 1024;
+
+// Forwards to TinyAlloc but keeps the default alloc-copy-free realloc.
+struct CopyRealloc;
+
+unsafe impl GlobalAlloc for CopyRealloc {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    unsafe { TinyAlloc.alloc(layout) }
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe { TinyAlloc.dealloc(ptr, layout) }
+  }
+}
+
 fn small_allocations(c: &mut Criterion) {
   c.bench_function("small_allocations", |b| {
     let mut heap = Heap::new();
@@ -122,12 +141,84 @@ fn size_class_distribution(c: &mut Criterion) {
   });
 }
 
+// What the heap lock taken on every operation since heaps became shareable
+// costs the owning thread: the same small alloc/free pair without the lock,
+// as before, with it, and through the whole GlobalAlloc path.
+fn heap_lock(c: &mut Criterion) {
+  let mut group = c.benchmark_group("heap_lock");
+  let layout = Layout::from_size_align(64, 8).unwrap();
+
+  group.bench_function("unlocked", |b| {
+    let mut heap = Heap::new();
+    b.iter(|| {
+      let ptr = black_box(heap.allocate(layout).unwrap());
+      heap.deallocate(ptr.cast(), layout).unwrap();
+    });
+  });
+
+  group.bench_function("locked", |b| {
+    let mut heap = Heap::new();
+    // Locks through a raw pointer, like `with_heap`.
+    let heap = NonNull::from(&mut heap);
+    b.iter(|| unsafe {
+      let ptr = {
+        let _guard = heap.as_ref().lock();
+        black_box((*heap.as_ptr()).allocate(layout).unwrap())
+      };
+      let _guard = heap.as_ref().lock();
+      (*heap.as_ptr()).deallocate(ptr.cast(), layout).unwrap();
+    });
+  });
+
+  group.bench_function("global_alloc", |b| {
+    b.iter(|| unsafe {
+      let ptr = black_box(TinyAlloc.alloc(layout));
+      TinyAlloc.dealloc(ptr, layout);
+    });
+  });
+
+  group.finish();
+}
+
+// Mirrors the capacity doubling of a Vec<u64> that is pushed to until it
+// holds VEC_GROWTH_LIMIT bytes.
+fn grow_vec(allocator: &impl GlobalAlloc) {
+  let mut layout = Layout::from_size_align(32, 8).unwrap();
+  let mut ptr = unsafe { allocator.alloc(layout) };
+
+  while layout.size() < VEC_GROWTH_LIMIT {
+    let new_size = layout.size() * 2;
+    ptr = unsafe { allocator.realloc(ptr, layout, new_size) };
+    unsafe { ptr.add(new_size - 8).cast::<u64>().write(new_size as u64) };
+    layout = Layout::from_size_align(new_size, 8).unwrap();
+  }
+
+  black_box(ptr);
+  unsafe { allocator.dealloc(ptr, layout) };
+}
+
+fn vec_growth(c: &mut Criterion) {
+  let mut group = c.benchmark_group("vec_growth");
+
+  group.bench_function("tinyalloc_realloc", |b| {
+    b.iter(|| grow_vec(&TinyAlloc));
+  });
+
+  group.bench_function("alloc_copy_free", |b| {
+    b.iter(|| grow_vec(&CopyRealloc));
+  });
+
+  group.finish();
+}
+
 criterion_group!(
   benches,
   small_allocations,
   medium_allocations,
   large_allocations,
   mixed_workload,
-  size_class_distribution
+  size_class_distribution,
+  heap_lock,
+  vec_growth
 );
 criterion_main!(benches);
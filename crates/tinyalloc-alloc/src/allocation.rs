@@ -2,11 +2,13 @@ use std::{
   alloc::Layout,
   mem,
   ptr::NonNull,
-  thread::ThreadId,
 };
 
 use getset::CloneGetters;
-use tinyalloc_config::helper::{align_up, MAX_ALIGN};
+use tinyalloc_config::helper::{
+  MAX_ALIGN,
+  align_up,
+};
 use tinyalloc_list::{
   HasLink,
   Link,
@@ -15,6 +17,7 @@ use tinyalloc_list::{
 use crate::heap::Heap;
 
 const ALLOCATION_CANARY: u64 = 0xDEADBEEFCAFEBABE;
+const OFFSET_SIZE: usize = mem::size_of::<usize>();
 
 #[derive(Clone)]
 pub enum AllocationOwner {
@@ -59,52 +62,167 @@ impl Allocation {
     }
 
     let user_addr = ptr as usize;
-    if user_addr < mem::size_of::<Self>() + MAX_ALIGN {
+    if user_addr < Self::user_offset_min()
+      || !user_addr.is_multiple_of(mem::align_of::<usize>())
+    {
       return None;
     }
 
-    let header_size = mem::size_of::<Self>();
-    let max_header_end = match user_addr.checked_sub(MAX_ALIGN - 1) {
-      Some(end) => end,
-      None => return None,
-    };
-
-    let header_start = match max_header_end.checked_sub(header_size) {
-      Some(start) => start,
-      None => return None,
-    };
+    let marker_ptr = (user_addr - OFFSET_SIZE) as *const usize;
+    let offset = unsafe { marker_ptr.read() };
+    if offset < Self::user_offset_min() || offset > user_addr {
+      return None;
+    }
 
-    if header_start % mem::align_of::<Self>() != 0 {
+    let header_start = user_addr - offset;
+    if !header_start.is_multiple_of(mem::align_of::<Self>()) {
       return None;
     }
 
     let header_ptr = header_start as *mut Self;
+    let allocation = unsafe { &*header_ptr };
+    if allocation.canary != ALLOCATION_CANARY || allocation.user_ptr != ptr {
+      return None;
+    }
+
+    Some(header_ptr)
+  }
 
-    if header_ptr.is_null() {
+  /// Reads the header written at `header_ptr`, the start of a block
+  /// allocated with one, if it is intact.
+  pub fn at(header_ptr: *mut u8) -> Option<*mut Self> {
+    if header_ptr.is_null()
+      || !(header_ptr as usize).is_multiple_of(mem::align_of::<Self>())
+    {
       return None;
     }
 
-    let allocation = unsafe { &*header_ptr };
-    if allocation.canary != ALLOCATION_CANARY {
+    let header = header_ptr as *mut Self;
+    let allocation = unsafe { &*header };
+    if allocation.canary != ALLOCATION_CANARY
+      || allocation.alloc_ptr != header_ptr
+    {
       return None;
     }
 
-    Some(header_ptr)
+    Some(header)
+  }
+
+  /// Bytes usable from the user pointer to the end of the block.
+  pub fn usable_size(&self) -> usize {
+    self.full.size() - (self.user_ptr as usize - self.alloc_ptr as usize)
   }
 
   pub fn total_size(user_layout: Layout) -> usize {
-    let header_size = mem::size_of::<Self>();
-    let user_size = user_layout.size();
-    let padding = MAX_ALIGN - 1;
-    header_size + padding + user_size
+    let padding = Self::user_align(user_layout) - 1;
+    Self::user_offset_min() + padding + user_layout.size()
+  }
+
+  pub fn total_layout(user_layout: Layout) -> Option<Layout> {
+    Layout::from_size_align(
+      Self::total_size(user_layout),
+      mem::align_of::<Self>(),
+    )
+    .ok()
   }
 
-  pub fn calc_user_ptr(header_ptr: *const Self) -> *mut u8 {
+  pub fn calc_user_ptr(
+    header_ptr: *const Self,
+    user_layout: Layout,
+  ) -> *mut u8 {
     let header_addr = header_ptr as usize;
-    let user_addr = align_up(header_addr + mem::size_of::<Self>(), MAX_ALIGN);
+    let user_addr = align_up(
+      header_addr + Self::user_offset_min(),
+      Self::user_align(user_layout),
+    );
     user_addr as *mut u8
   }
 
+  /// Writes the header at `header_ptr` and the back-offset marker right
+  /// before the user pointer, returning the user pointer.
+  ///
+  /// # Safety
+  ///
+  /// `header_ptr` must point to at least `total_size(user_layout)` writable
+  /// bytes aligned for `Self`.
+  pub unsafe fn write(
+    header_ptr: *mut Self,
+    owned: AllocationOwner,
+    full: Layout,
+    user_layout: Layout,
+  ) -> *mut u8 {
+    let user_ptr = Self::calc_user_ptr(header_ptr, user_layout);
+    unsafe { Self::write_at(header_ptr, owned, full, user_ptr) }
+  }
+
+  /// Like [`Allocation::write`], but works back from the end of `full` so
+  /// the user block ends as close to it as the alignment allows, against
+  /// a guard page that follows the block.
+  ///
+  /// # Safety
+  ///
+  /// As for [`Allocation::write`], with `full` spanning at least
+  /// `total_size(user_layout)` bytes.
+  pub unsafe fn write_at_end(
+    header_ptr: *mut Self,
+    owned: AllocationOwner,
+    full: Layout,
+    user_layout: Layout,
+  ) -> *mut u8 {
+    let end = header_ptr as usize + full.size();
+    let user_addr =
+      (end - user_layout.size()) & !(Self::user_align(user_layout) - 1);
+    unsafe { Self::write_at(header_ptr, owned, full, user_addr as *mut u8) }
+  }
+
+  unsafe fn write_at(
+    header_ptr: *mut Self,
+    owned: AllocationOwner,
+    full: Layout,
+    user_ptr: *mut u8,
+  ) -> *mut u8 {
+    let offset = user_ptr as usize - header_ptr as usize;
+
+    unsafe {
+      header_ptr.write(Self::new(owned, full, header_ptr as *mut u8, user_ptr));
+      (user_ptr.sub(OFFSET_SIZE) as *mut usize).write(offset);
+    }
+    user_ptr
+  }
+
+  /// Re-anchors a header that was moved together with its block, keeping
+  /// the user pointer at the same offset, and returns the new user pointer.
+  ///
+  /// # Safety
+  ///
+  /// `header_ptr` must point to a header previously written by
+  /// [`Allocation::write`] whose block was relocated as a whole.
+  pub unsafe fn rebase(
+    header_ptr: *mut Self,
+    owned: AllocationOwner,
+    full: Layout,
+  ) -> *mut u8 {
+    let header = unsafe { &mut *header_ptr };
+    let offset = header.user_ptr as usize - header.alloc_ptr as usize;
+
+    header.owned = owned;
+    header.full = full;
+    header.alloc_ptr = header_ptr as *mut u8;
+    header.user_ptr = unsafe { header.alloc_ptr.add(offset) };
+    header.user_ptr
+  }
+
+  const fn user_offset_min() -> usize {
+    mem::size_of::<Self>() + OFFSET_SIZE
+  }
+
+  fn user_align(user_layout: Layout) -> usize {
+    user_layout.align().max(MAX_ALIGN)
+  }
+
+  /// # Safety
+  ///
+  /// The owning heap must still be alive.
   pub unsafe fn heap_ptr(&self) -> Option<&Heap> {
     match self.owned {
       AllocationOwner::Heap(heap_ptr) => Some(unsafe { &*heap_ptr }),
@@ -112,19 +230,15 @@ impl Allocation {
     }
   }
 
+  /// # Safety
+  ///
+  /// The header must have been written by [`Allocation::write`].
   pub unsafe fn map_range(&self) -> Option<NonNull<[u8]>> {
     match self.owned {
       AllocationOwner::Mapper(ref slice_ptr) => Some(*slice_ptr),
       AllocationOwner::Heap(_) => None,
     }
   }
-
-  pub fn thread(&self) -> Option<ThreadId> {
-    match unsafe { self.heap_ptr() } {
-      Some(heap) => Some(heap.thread()),
-      None => None,
-    }
-  }
 }
 
 impl HasLink<Allocation> for Allocation {
@@ -136,3 +250,54 @@ impl HasLink<Allocation> for Allocation {
     &mut self.link
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn roundtrip(size: usize, align: usize) {
+    let user_layout = Layout::from_size_align(size, align).unwrap();
+    let total = Allocation::total_layout(user_layout).unwrap();
+    let mut buffer = vec![0u64; total.size().div_ceil(8)];
+    let header_ptr = buffer.as_mut_ptr() as *mut Allocation;
+    let owner = AllocationOwner::Heap(core::ptr::null_mut());
+
+    let user_ptr =
+      unsafe { Allocation::write(header_ptr, owner, total, user_layout) };
+    assert_eq!(user_ptr as usize % align, 0);
+    assert!(
+      user_ptr as usize + size <= header_ptr as usize + total.size(),
+      "user block must fit inside the total layout"
+    );
+    assert_eq!(Allocation::from(user_ptr), Some(header_ptr));
+
+    let owner = AllocationOwner::Heap(core::ptr::null_mut());
+    let user_ptr = unsafe {
+      Allocation::write_at_end(header_ptr, owner, total, user_layout)
+    };
+    let end = header_ptr as usize + total.size();
+    assert_eq!(user_ptr as usize % align, 0);
+    assert!(user_ptr as usize + size <= end);
+    assert!(end - (user_ptr as usize + size) < align.max(MAX_ALIGN));
+    assert_eq!(Allocation::from(user_ptr), Some(header_ptr));
+  }
+
+  #[test]
+  fn allocation_recovers_header_for_all_alignments() {
+    let mut align = 1;
+    while align <= 64 * 1024 {
+      roundtrip(1, align);
+      roundtrip(align, align);
+      roundtrip(3 * align + 1, align);
+      align <<= 1;
+    }
+  }
+
+  #[test]
+  fn allocation_rejects_foreign_pointers() {
+    let mut buffer = vec![0u64; 64];
+    let ptr = unsafe { buffer.as_mut_ptr().add(32) } as *mut u8;
+    assert!(Allocation::from(ptr).is_none());
+    assert!(Allocation::from(core::ptr::null_mut()).is_none());
+  }
+}
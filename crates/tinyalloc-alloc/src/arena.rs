@@ -3,6 +3,10 @@ use std::{
   num::NonZeroUsize,
   ptr::NonNull,
   slice,
+  time::{
+    Duration,
+    Instant,
+  },
 };
 
 use spin::Mutex;
@@ -30,16 +34,22 @@ use tinyalloc_config::{
 
 #[cfg(feature = "metrics")]
 use tinyalloc_config::metrics::MetricId;
+use tinyalloc_list::{
+  HasLink,
+  Link,
+};
 use tinyalloc_sys::{
   MapError,
   mapper::Protection,
   region::Region,
-  size::page_size,
 };
 
-use crate::segment::{
-  Segment,
-  SegmentError,
+use crate::{
+  pagemap,
+  segment::{
+    Segment,
+    SegmentError,
+  },
 };
 
 #[derive(Debug)]
@@ -59,6 +69,10 @@ pub struct Arena {
   user: UnsafeCell<&'static mut [u8]>,
   max_segments: usize,
   cache: UnsafeCell<Array<usize, ARENA_CACHE_SIZE>>,
+  /// When the last active segment was released, `None` while any segment
+  /// is in use.
+  idle_since: UnsafeCell<Option<Instant>>,
+  link: Link<Arena>,
   lock: Mutex<()>,
 }
 
@@ -67,7 +81,13 @@ impl Arena {
     metric!(MetricId::ArenaNew);
 
     let nonz = NonZeroUsize::new(size).ok_or(ArenaError::SizeIsZero)?;
-    let region = Region::new(nonz).map_err(ArenaError::MapError)?;
+    // Reserve one extra segment so aligning the user space below does not
+    // cost a usable slot.
+    let reserved = size
+      .checked_add(SEGMENT_SIZE)
+      .and_then(NonZeroUsize::new)
+      .ok_or(ArenaError::Insufficient)?;
+    let region = Region::new(reserved).map_err(ArenaError::MapError)?;
 
     let arena_size = core::mem::size_of::<Self>();
     let total_size = align_up(arena_size, WORD);
@@ -77,7 +97,8 @@ impl Arena {
     }
 
     let base_ptr = region.as_ptr();
-    let full_slice = unsafe { slice::from_raw_parts_mut(base_ptr, nonz.get()) };
+    let full_slice =
+      unsafe { slice::from_raw_parts_mut(base_ptr, reserved.get()) };
     let (arena_slice, rest) = full_slice.split_at_mut(total_size);
 
     let activation_range = NonNull::new(arena_slice as *mut [u8]).unwrap();
@@ -100,7 +121,9 @@ impl Arena {
     }
 
     let (bitmap_region, user_region) = aligned_rest.split_at_mut(bitmap_bytes);
-    let user_space = align_slice(user_region, page_size());
+    // Segments start on `SEGMENT_SIZE` boundaries so masking any pointer
+    // into one yields its header.
+    let user_space = align_slice(user_region, SEGMENT_SIZE);
     let segment_count = user_space.len() / SEGMENT_SIZE;
     if segment_count == 0 {
       return Err(ArenaError::Insufficient);
@@ -127,6 +150,8 @@ impl Arena {
       user: UnsafeCell::new(user_space),
       max_segments: segment_count,
       cache: UnsafeCell::new(Array::new()),
+      idle_since: UnsafeCell::new(Some(Instant::now())),
+      link: Link::new(),
       lock: Mutex::new(()),
     };
 
@@ -186,11 +211,14 @@ impl Arena {
       .map_err(ArenaError::MapError)?;
 
     metric!(MetricId::ArenaSegmentActivation);
-    let segment =
+    let mut segment =
       Segment::new(class, segment_slice).map_err(ArenaError::Segment)?;
+    unsafe { segment.as_mut() }.set_arena(self);
+    pagemap::insert(segment.cast())?;
 
     metric!(MetricId::ArenaBitmapOperations);
     let _ = bitmap.set(segment_index);
+    unsafe { *self.idle_since.get() = None };
 
     metric!(MetricId::ArenaAllocateSuccess);
     Ok(segment)
@@ -232,6 +260,8 @@ impl Arena {
       unsafe { slice::from_raw_parts_mut(segment_ptr, SEGMENT_SIZE) };
     let segment_range = NonNull::new(segment_slice as *mut [u8]).unwrap();
 
+    pagemap::remove(segment.cast());
+
     metric!(MetricId::ArenaSegmentDeactivation);
     self
       .region
@@ -241,6 +271,9 @@ impl Arena {
     let _ = cache.push(segment_index);
     metric!(MetricId::ArenaBitmapOperations);
     let _ = bitmap.clear(segment_index);
+    if bitmap.is_clear() {
+      unsafe { *self.idle_since.get() = Some(Instant::now()) };
+    }
 
     metric!(MetricId::ArenaDeallocateSuccess);
     Ok(())
@@ -263,6 +296,51 @@ impl Arena {
     has_space
   }
 
+  /// How long the arena has had no active segment, if it has none.
+  pub fn idle_for(&self) -> Option<Duration> {
+    let _guard = self.lock.lock();
+    let idle_since = unsafe { *self.idle_since.get() };
+    idle_since.map(|since| since.elapsed())
+  }
+
+  /// Number of segments currently handed out.
+  pub fn active_segments(&self) -> usize {
+    let _guard = self.lock.lock();
+    let bitmap = unsafe { &*self.bitmap.get() };
+    bitmap
+      .store()
+      .iter()
+      .map(|word| word.count_ones() as usize)
+      .sum()
+  }
+
+  /// Blocks waiting on the thread-free stacks of this arena's segments.
+  /// Holding the lock keeps the segments from being released meanwhile.
+  pub fn pending_frees(&self) -> usize {
+    let _guard = self.lock.lock();
+    let bitmap = unsafe { &*self.bitmap.get() };
+    let user = unsafe { &*self.user.get() };
+
+    let mut pending = 0;
+    for (word_index, &word) in bitmap.store().iter().enumerate() {
+      let mut bits = word;
+      while bits != 0 {
+        let index =
+          word_index * usize::BITS as usize + bits.trailing_zeros() as usize;
+        bits &= bits - 1;
+        let segment =
+          unsafe { user.as_ptr().add(index * SEGMENT_SIZE) } as *const Segment;
+        pending += unsafe { &*segment }.thread_free().count();
+      }
+    }
+    pending
+  }
+
+  /// Total bytes reserved for the arena.
+  pub fn size(&self) -> usize {
+    self.region.data().len()
+  }
+
   pub fn user_start(&self) -> *const u8 {
     let user = unsafe { &*self.user.get() };
     user.as_ptr()
@@ -274,6 +352,16 @@ impl Arena {
   }
 }
 
+impl HasLink<Arena> for Arena {
+  fn link(&self) -> &Link<Arena> {
+    &self.link
+  }
+
+  fn link_mut(&mut self) -> &mut Link<Arena> {
+    &mut self.link
+  }
+}
+
 impl Drop for Arena {
   fn drop(&mut self) {
     let _guard = self.lock.lock();
@@ -297,6 +385,25 @@ mod tests {
     assert!(arena_result.is_ok());
   }
 
+  #[test]
+  fn test_segments_are_aligned_and_mapped() {
+    let arena_ptr = Arena::new(ARENA_INITIAL_SIZE).unwrap();
+    let arena = unsafe { arena_ptr.as_ref() };
+    let class = &tinyalloc_config::classes::CLASSES[0];
+
+    let segment = arena.allocate(class).unwrap();
+    let addr = segment.as_ptr() as usize;
+    assert_eq!(addr % SEGMENT_SIZE, 0);
+    assert_eq!(
+      pagemap::lookup(addr + SEGMENT_SIZE / 2),
+      Some(segment.cast())
+    );
+    assert!(core::ptr::eq(unsafe { segment.as_ref() }.arena(), arena));
+
+    arena.deallocate(segment).unwrap();
+    assert!(pagemap::lookup(addr).is_none());
+  }
+
   #[test]
   fn test_arena_insufficient_space() {
     let arena_result = Arena::new(1);
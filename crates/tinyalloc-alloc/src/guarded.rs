@@ -0,0 +1,419 @@
+use std::{
+  alloc::Layout,
+  cell::{
+    Cell,
+    UnsafeCell,
+  },
+  fmt::{
+    self,
+    Write,
+  },
+  num::NonZeroUsize,
+  ptr::NonNull,
+  sync::atomic::{
+    AtomicUsize,
+    Ordering,
+  },
+};
+
+use enumset::EnumSet;
+use spin::Mutex;
+use tinyalloc_config::config::GUARDED_SLOTS;
+use tinyalloc_sys::{
+  fault,
+  process::write_stderr,
+  region::Region,
+  size::page_size,
+  thread,
+};
+
+use crate::{
+  options,
+  report::{
+    FreeProblem,
+    invalid_free,
+  },
+};
+
+/// Pages in the pool: one per slot, each flanked by guard pages.
+const PAGES: usize = 2 * GUARDED_SLOTS + 1;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+  Unused,
+  Live,
+  Freed,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+  state: State,
+  address: usize,
+  size: usize,
+  allocated_by: u64,
+  freed_by: u64,
+  /// Order in which the slot was last freed, so the one freed longest ago
+  /// is reused first.
+  freed_at: u64,
+}
+
+impl Slot {
+  const UNUSED: Self = Self {
+    state: State::Unused,
+    address: 0,
+    size: 0,
+    allocated_by: 0,
+    freed_by: 0,
+    freed_at: 0,
+  };
+}
+
+/// Pool of single-object pages laid out as guard, slot, guard, slot, ...,
+/// guard. Each object is pushed against the end of its page, so running
+/// past it touches the next guard page, and its page is made inaccessible
+/// again once freed.
+struct Pool {
+  region: Option<Region>,
+  slots: [Slot; GUARDED_SLOTS],
+  frees: u64,
+  failed: bool,
+}
+
+struct Shared(UnsafeCell<Pool>);
+
+// Writers hold `LOCK`; the fault handler reads without it, as it may run
+// while the lock is held and only needs a best-effort description.
+unsafe impl Sync for Shared {}
+
+static POOL: Shared = Shared(UnsafeCell::new(Pool {
+  region: None,
+  slots: [Slot::UNUSED; GUARDED_SLOTS],
+  frees: 0,
+  failed: false,
+}));
+static LOCK: Mutex<()> = Mutex::new(());
+/// Start of the pool mapping, zero until the first sample maps it.
+static BASE: AtomicUsize = AtomicUsize::new(0);
+
+struct Sampler {
+  countdown: Cell<usize>,
+  seed: Cell<u64>,
+}
+
+thread_local! {
+  static SAMPLER: Sampler = const {
+    Sampler {
+      countdown: Cell::new(0),
+      seed: Cell::new(0),
+    }
+  };
+}
+
+impl Sampler {
+  /// Draws the next gap between samples, uniform in `1..=2 * rate` so the
+  /// average is `rate` without sampling at a fixed stride.
+  fn interval(&self, rate: usize) -> usize {
+    let mut seed = self.seed.get();
+    if seed == 0 {
+      seed = thread::current_id() | 1;
+    }
+    seed ^= seed << 13;
+    seed ^= seed >> 7;
+    seed ^= seed << 17;
+    self.seed.set(seed);
+    1 + (seed % (2 * rate as u64)) as usize
+  }
+
+  fn sample(&self, rate: usize) -> bool {
+    let left = self.countdown.get();
+    if left > 1 {
+      self.countdown.set(left - 1);
+      return false;
+    }
+    self.countdown.set(self.interval(rate));
+    left == 1
+  }
+}
+
+/// Serves `layout` from the guarded pool for roughly one allocation in
+/// every [`options::sample_rate`]. The memory reads as zero.
+pub fn sample(layout: Layout) -> Option<NonNull<u8>> {
+  let rate = options::sample_rate();
+  if rate == 0 {
+    return None;
+  }
+  if !SAMPLER
+    .try_with(|sampler| sampler.sample(rate))
+    .unwrap_or(false)
+  {
+    return None;
+  }
+  allocate(layout)
+}
+
+fn pool_len() -> usize {
+  PAGES * page_size()
+}
+
+/// Whether `ptr` points into the guarded pool.
+pub fn contains(ptr: NonNull<u8>) -> bool {
+  let base = BASE.load(Ordering::Acquire);
+  base != 0 && (ptr.as_ptr() as usize).wrapping_sub(base) < pool_len()
+}
+
+fn page_at(base: usize, index: usize) -> NonNull<[u8]> {
+  let page = page_size();
+  let start = (base + index * page) as *mut u8;
+  NonNull::slice_from_raw_parts(NonNull::new(start).unwrap(), page)
+}
+
+impl Pool {
+  fn base(&mut self) -> Option<usize> {
+    if let Some(region) = &self.region {
+      return Some(region.as_ptr() as usize);
+    }
+    if self.failed {
+      return None;
+    }
+
+    let Ok(region) = Region::new(NonZeroUsize::new(pool_len())?) else {
+      self.failed = true;
+      return None;
+    };
+    let base = region.as_ptr() as usize;
+    self.region = Some(region);
+    BASE.store(base, Ordering::Release);
+    Some(base)
+  }
+
+  /// A never used slot if there is one, else the one freed longest ago.
+  fn pick(&self) -> Option<usize> {
+    self
+      .slots
+      .iter()
+      .position(|slot| slot.state == State::Unused)
+      .or_else(|| {
+        self
+          .slots
+          .iter()
+          .enumerate()
+          .filter(|(_, slot)| slot.state == State::Freed)
+          .min_by_key(|(_, slot)| slot.freed_at)
+          .map(|(index, _)| index)
+      })
+  }
+}
+
+fn allocate(layout: Layout) -> Option<NonNull<u8>> {
+  let page = page_size();
+  if layout.size() > page || layout.align() > page {
+    return None;
+  }
+
+  let _guard = LOCK.lock();
+  let pool = unsafe { &mut *POOL.0.get() };
+  let base = pool.base()?;
+  let index = pool.pick()?;
+  // Checked on every sample, as the runtime replaces the handler when it
+  // starts after the first one.
+  fault::install(describe_fault);
+  let region = pool.region.as_ref()?;
+  region
+    .partial(page_at(base, 2 * index + 1), EnumSet::all())
+    .ok()?;
+
+  let end = base + (2 * index + 2) * page;
+  let address = (end - layout.size()) & !(layout.align() - 1);
+  pool.slots[index] = Slot {
+    state: State::Live,
+    address,
+    size: layout.size(),
+    allocated_by: thread::current_id(),
+    freed_by: 0,
+    freed_at: 0,
+  };
+  NonNull::new(address as *mut u8)
+}
+
+/// Frees a block of the guarded pool and makes its page inaccessible, so
+/// later accesses through stale pointers fault.
+pub fn deallocate(ptr: NonNull<u8>) {
+  let _guard = LOCK.lock();
+  let pool = unsafe { &mut *POOL.0.get() };
+  let Some(base) = pool.base() else {
+    return;
+  };
+
+  let address = ptr.as_ptr() as usize;
+  let index = (address - base) / page_size();
+  let slot = (index % 2 == 1).then(|| &mut pool.slots[index / 2]);
+  let problem = match slot {
+    Some(slot) if slot.state == State::Live && slot.address == address => {
+      slot.state = State::Freed;
+      slot.freed_by = thread::current_id();
+      slot.freed_at = pool.frees;
+      pool.frees += 1;
+      None
+    }
+    Some(slot) if slot.state == State::Live => Some(FreeProblem::Interior),
+    Some(slot) if slot.state == State::Freed && slot.address == address => {
+      Some(FreeProblem::DoubleFree)
+    }
+    _ => Some(FreeProblem::Foreign),
+  };
+
+  match problem {
+    Some(problem) => invalid_free(problem, ptr.as_ptr(), None),
+    None => {
+      if let Some(region) = &pool.region {
+        let _ = region.partial(page_at(base, index), EnumSet::empty());
+      }
+    }
+  }
+}
+
+/// Calls `visit` with the address and size of every live guarded block.
+pub fn for_each_live(mut visit: impl FnMut(NonNull<u8>, usize)) {
+  let _guard = LOCK.lock();
+  let pool = unsafe { &*POOL.0.get() };
+  for slot in pool.slots.iter().filter(|slot| slot.state == State::Live) {
+    if let Some(address) = NonNull::new(slot.address as *mut u8) {
+      visit(address, slot.size);
+    }
+  }
+}
+
+/// Fixed buffer for formatting a report inside a signal handler.
+struct Line {
+  bytes: [u8; 320],
+  len: usize,
+}
+
+impl Write for Line {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    let end = (self.len + s.len()).min(self.bytes.len());
+    self.bytes[self.len..end].copy_from_slice(&s.as_bytes()[..end - self.len]);
+    self.len = end;
+    Ok(())
+  }
+}
+
+fn describe_slot(line: &mut Line, slot: &Slot, address: usize) -> fmt::Result {
+  let start = slot.address;
+  let end = slot.address + slot.size;
+  match slot.state {
+    State::Freed => write!(
+      line,
+      "use after free at offset {} of {start:#x} ({} B), allocated by \
+       thread {}, freed by thread {}",
+      address.wrapping_sub(start) as isize,
+      slot.size,
+      slot.allocated_by,
+      slot.freed_by
+    ),
+    State::Live if address >= end => write!(
+      line,
+      "buffer overflow {} B past the end of {start:#x} ({} B), allocated \
+       by thread {}",
+      address - end,
+      slot.size,
+      slot.allocated_by
+    ),
+    State::Live => write!(
+      line,
+      "buffer underflow {} B before {start:#x} ({} B), allocated by \
+       thread {}",
+      start - address,
+      slot.size,
+      slot.allocated_by
+    ),
+    State::Unused => write!(line, "access to an unused guarded page"),
+  }
+}
+
+/// Fault handler for the guarded pool: reports which sampled block an
+/// access that faulted inside the pool belongs to.
+fn describe_fault(address: usize) -> bool {
+  let base = BASE.load(Ordering::Acquire);
+  let Some(offset) = address
+    .checked_sub(base)
+    .filter(|&o| base != 0 && o < pool_len())
+  else {
+    return false;
+  };
+
+  let pool = unsafe { &*POOL.0.get() };
+  let index = offset / page_size();
+  let slot = if index % 2 == 1 {
+    &pool.slots[index / 2]
+  } else {
+    // A guard page: blame the left neighbour, whose end abuts it, unless
+    // only the right one is live.
+    let left = (index / 2).checked_sub(1).map(|i| &pool.slots[i]);
+    let right = pool.slots.get(index / 2);
+    match (left, right) {
+      (Some(left), _) if left.state == State::Live => left,
+      (_, Some(right)) if right.state == State::Live => right,
+      (Some(left), _) => left,
+      (None, Some(right)) => right,
+      (None, None) => return false,
+    }
+  };
+
+  let mut line = Line {
+    bytes: [0; 320],
+    len: 0,
+  };
+  let _ = write!(
+    line,
+    "tinyalloc: guarded allocation fault at {address:#x}: "
+  );
+  let _ = describe_slot(&mut line, slot, address);
+  let _ = line.write_str("\n");
+  write_stderr(&line.bytes[..line.len]);
+  true
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn guarded_blocks_end_against_their_guard_page() {
+    let page = page_size();
+    let layout = Layout::from_size_align(100, 16).unwrap();
+    let ptr = allocate(layout).expect("pool must map");
+    assert!(contains(ptr));
+
+    let address = ptr.as_ptr() as usize;
+    assert_eq!(address % 16, 0);
+    let guard = (address / page + 1) * page;
+    assert!(guard - address - 100 < 16, "block must end at its page end");
+    assert!(
+      unsafe { core::slice::from_raw_parts(ptr.as_ptr(), 100) }
+        .iter()
+        .all(|&byte| byte == 0)
+    );
+
+    let live = || {
+      let mut live = false;
+      for_each_live(|block, size| live |= block == ptr && size == 100);
+      live
+    };
+    assert!(live());
+
+    deallocate(ptr);
+    assert!(!live());
+    assert!(!contains(NonNull::dangling()));
+  }
+
+  #[test]
+  fn sampler_intervals_average_the_rate() {
+    let sampler = Sampler {
+      countdown: Cell::new(0),
+      seed: Cell::new(0),
+    };
+    let samples = (0..100_000).filter(|_| sampler.sample(100)).count();
+    assert!((500..2000).contains(&samples), "{samples} samples");
+  }
+}
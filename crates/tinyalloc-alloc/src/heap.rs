@@ -2,33 +2,58 @@ use std::{
   alloc::Layout,
   num::NonZeroUsize,
   ptr::NonNull,
-  sync::OnceLock,
-  thread::{
-    self,
-    ThreadId,
+  sync::atomic::{
+    AtomicBool,
+    AtomicU64,
+    Ordering,
   },
 };
 
 use getset::Getters;
-use spin::RwLock;
+use spin::{
+  Mutex,
+  MutexGuard,
+};
 use tinyalloc_config::{
-  classes::{class_init, find_class},
-  config::{LARGE_SC_LIMIT, REMOTE_BATCH_SIZE, REMOTE_CHECK_FREQUENCY, REMOTE_MAX_BATCH, SIZES},
+  classes::{
+    class_init,
+    find_class,
+  },
+  config::{
+    LARGE_SC_LIMIT,
+    SIZES,
+  },
   metric,
 };
 
 #[cfg(feature = "metrics")]
 use tinyalloc_config::metrics::MetricId;
-use tinyalloc_list::List;
+use tinyalloc_list::{
+  HasLink,
+  Link,
+  List,
+};
 
 use crate::{
-  allocation::Allocation,
   arena::ArenaError,
   large::{
     Large,
     LargeError,
   },
+  options,
+  quarantine::Quarantine,
   queue::Queue,
+  remote::RemoteStack,
+  report::{
+    invalid_free,
+    report,
+  },
+  segment::Segment,
+  static_::{
+    free_abandoned,
+    segment_from_ptr,
+  },
+  stats::Counters,
 };
 
 #[derive(Debug)]
@@ -41,33 +66,130 @@ pub enum HeapError {
 
 #[derive(Getters)]
 pub struct Heap {
-  thread: OnceLock<ThreadId>,
+  link: Link<Heap>,
+  claimed: AtomicBool,
+  /// Held around every operation on the heap, so a walker that takes it
+  /// sees the heap at rest.
+  lock: Mutex<()>,
+  /// OS id of the thread that claimed the heap, zero while unclaimed.
+  thread: AtomicU64,
   classes: [Queue; SIZES],
   large: List<Large>,
+  /// Large blocks freed by other threads. Small slots go to the thread-free
+  /// stack of their segment instead.
+  #[getset(get = "pub")]
+  remote: RemoteStack,
   #[getset(get = "pub")]
-  remote: RwLock<List<Allocation>>,
+  counters: Counters,
+  /// Small slots freed through this heap and held back from reuse while
+  /// quarantine is on. They still count as in use to their segments.
+  quarantine: Quarantine,
   operations: usize,
 }
 
 impl Heap {
   pub fn new() -> Self {
-    let classes: [Queue; SIZES] = class_init(|class| Queue::new(class));
+    let classes: [Queue; SIZES] = class_init(Queue::new);
     Self {
-      thread: OnceLock::new(),
+      link: Link::new(),
+      claimed: AtomicBool::new(false),
+      lock: Mutex::new(()),
+      thread: AtomicU64::new(0),
       classes,
       large: List::new(),
-      remote: RwLock::new(List::new()),
+      remote: RemoteStack::new(),
+      counters: Counters::new(),
+      quarantine: Quarantine::new(),
       operations: 0,
     }
   }
 
-  pub fn thread(&self) -> ThreadId {
-    *self.thread.get_or_init(|| thread::current().id())
+  /// Takes exclusive use of the heap. Fails while another thread holds it.
+  pub fn try_claim(&self) -> bool {
+    self
+      .claimed
+      .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+      .is_ok()
+  }
+
+  pub fn unclaim(&self) {
+    self.claimed.store(false, Ordering::Release);
+  }
+
+  pub fn lock(&self) -> MutexGuard<'_, ()> {
+    self.lock.lock()
+  }
+
+  pub fn thread(&self) -> Option<u64> {
+    match self.thread.load(Ordering::Relaxed) {
+      0 => None,
+      thread => Some(thread),
+    }
+  }
+
+  pub fn set_thread(&self, thread: Option<u64>) {
+    self.thread.store(thread.unwrap_or(0), Ordering::Relaxed);
+  }
+
+  /// Every segment the heap owns. The heap must be locked or otherwise
+  /// exclusively held while iterating.
+  pub fn segments(&self) -> impl Iterator<Item = &Segment> {
+    self.classes.iter().flat_map(Queue::segments)
+  }
+
+  pub fn larges(&self) -> impl Iterator<Item = &Large> {
+    self.large.iter()
+  }
+
+  /// Detaches every segment from this heap ahead of its thread exiting.
+  /// Empty segments go back to their arena and live ones are published for
+  /// adoption, so their slots remain usable by other heaps. Large blocks
+  /// stay with the heap, which itself is kept alive for later reuse.
+  pub fn abandon(&mut self) {
+    self.flush_quarantine();
+    self.drain_remote();
+    for queue in self.classes.iter_mut() {
+      queue.abandon();
+    }
+  }
+
+  /// Frees every pending remote block and returns all empty segments to
+  /// their arenas, however many the queues would otherwise keep cached.
+  /// Returns the bytes handed back to the OS.
+  pub fn collect(&mut self) -> usize {
+    self.flush_quarantine();
+    let mut released = self.drain_remote();
+    for queue in self.classes.iter_mut() {
+      released += queue.collect();
+    }
+    released
+  }
+
+  /// Number of empty segments the heap keeps cached across its classes.
+  pub fn free_segments(&self) -> usize {
+    self.classes.iter().map(Queue::free_segments).sum()
   }
 
   pub fn allocate(
     &mut self,
     layout: Layout,
+  ) -> Result<NonNull<[u8]>, HeapError> {
+    self.allocate_in(layout, false)
+  }
+
+  /// Allocates memory that reads as zero. Only recycled slots are cleared;
+  /// memory the heap has never handed out is known to be zero already.
+  pub fn allocate_zeroed(
+    &mut self,
+    layout: Layout,
+  ) -> Result<NonNull<[u8]>, HeapError> {
+    self.allocate_in(layout, true)
+  }
+
+  fn allocate_in(
+    &mut self,
+    layout: Layout,
+    zeroed: bool,
   ) -> Result<NonNull<[u8]>, HeapError> {
     metric!(MetricId::HeapAllocate);
     metric!(MetricId::HeapOperationsCounter);
@@ -85,10 +207,10 @@ impl Heap {
 
     let result = if size > LARGE_SC_LIMIT {
       metric!(MetricId::HeapAllocLarge);
-      self.alloc_large(layout)
+      self.alloc_large(layout, zeroed)
     } else {
       metric!(MetricId::HeapAllocSmall);
-      self.alloc_small(layout)
+      self.alloc_small(layout, zeroed)
     };
 
     match result {
@@ -105,46 +227,127 @@ impl Heap {
   fn alloc_small(
     &mut self,
     layout: Layout,
+    zeroed: bool,
   ) -> Result<NonNull<[u8]>, HeapError> {
     metric!(MetricId::HeapClassLookup);
-    let class = find_class(layout.size(), layout.align())
-      .ok_or_else(|| {
-        metric!(MetricId::HeapClassLookupFail);
-        HeapError::InvalidSize
-      })?;
+    let Some(class) = find_class(layout.size(), layout.align()) else {
+      metric!(MetricId::HeapClassLookupFail);
+      return Err(HeapError::InvalidSize);
+    };
 
     metric!(MetricId::HeapClassLookupSuccess);
+    let owner = self as *mut Heap;
     let queue = &mut self.classes[class.id];
 
     metric!(MetricId::QueueAllocate);
-    let ptr = queue
-      .allocate()
-      .ok_or_else(|| {
-        metric!(MetricId::QueueAllocateFail);
-        HeapError::Arena(ArenaError::Insufficient)
-      })?;
+    let allocated = if zeroed {
+      queue.allocate_zeroed(owner, layout.size())
+    } else {
+      queue.allocate(owner)
+    };
+    let Some(ptr) = allocated else {
+      metric!(MetricId::QueueAllocateFail);
+      return Err(HeapError::Arena(ArenaError::Insufficient));
+    };
 
     metric!(MetricId::QueueAllocateSuccess);
+    self.counters.alloc(class.size.0);
 
     let slice =
       unsafe { core::slice::from_raw_parts_mut(ptr.as_ptr(), layout.size()) };
     NonNull::new(slice as *mut [u8]).ok_or(HeapError::InvalidPointer)
   }
 
+  pub fn allocate_large(
+    &mut self,
+    layout: Layout,
+  ) -> Result<NonNull<[u8]>, HeapError> {
+    self.allocate_large_in(layout, false)
+  }
+
+  pub fn allocate_large_zeroed(
+    &mut self,
+    layout: Layout,
+  ) -> Result<NonNull<[u8]>, HeapError> {
+    self.allocate_large_in(layout, true)
+  }
+
+  fn allocate_large_in(
+    &mut self,
+    layout: Layout,
+    zeroed: bool,
+  ) -> Result<NonNull<[u8]>, HeapError> {
+    metric!(MetricId::HeapAllocate);
+    metric!(MetricId::HeapOperationsCounter);
+
+    self.operations = self.operations.wrapping_add(1);
+    self.free_remote()?;
+
+    metric!(MetricId::HeapAllocLarge);
+    self.alloc_large(layout, zeroed)
+  }
+
   fn alloc_large(
     &mut self,
     layout: Layout,
+    zeroed: bool,
   ) -> Result<NonNull<[u8]>, HeapError> {
     let size =
       NonZeroUsize::new(layout.size()).ok_or(HeapError::InvalidSize)?;
-    let large_ptr = Large::new(size).map_err(HeapError::Large)?;
+    let mut large_ptr =
+      Large::new(size, options::large_guard()).map_err(HeapError::Large)?;
 
-    let slice_ptr = unsafe { large_ptr.as_ref() }.user_slice();
+    let large = unsafe { large_ptr.as_mut() };
+    if zeroed && !large.zeroed() {
+      large.user.fill(0);
+    }
+    large.set_zeroed(false);
+    let slice_ptr = large.user_slice();
+    self.counters.alloc(large.capacity());
+    self.counters.map_large(large.capacity());
 
     self.large.push(large_ptr);
     Ok(slice_ptr)
   }
 
+  /// Grows or shrinks the large block starting at `ptr` to `layout.size()`,
+  /// in place when the mapping allows it and by relocating the mapping
+  /// otherwise. The contents are preserved either way.
+  pub fn reallocate_large(
+    &mut self,
+    ptr: NonNull<u8>,
+    layout: Layout,
+  ) -> Result<NonNull<[u8]>, HeapError> {
+    let size =
+      NonZeroUsize::new(layout.size()).ok_or(HeapError::InvalidSize)?;
+    let large_nn =
+      Large::from_user_ptr(ptr).ok_or(HeapError::InvalidPointer)?;
+    if !self.large.contains(large_nn) {
+      return Err(HeapError::InvalidPointer);
+    }
+    let old = unsafe { large_nn.as_ref() }.capacity();
+
+    if let Ok(resized) = Large::resize(large_nn, size, false) {
+      let resized = unsafe { resized.as_ref() };
+      self.counters.resize_large(old, resized.capacity());
+      return Ok(resized.user_slice());
+    }
+
+    self.large.remove_unchecked(large_nn);
+    match Large::resize(large_nn, size, true) {
+      Ok(moved) => {
+        self.large.push(moved);
+        let moved = unsafe { moved.as_ref() };
+        self.counters.resize_large(old, moved.capacity());
+        Ok(moved.user_slice())
+      }
+      Err(e) => {
+        self.large.push(large_nn);
+        Err(HeapError::Large(e))
+      }
+    }
+  }
+
   pub fn deallocate(
     &mut self,
     ptr: NonNull<u8>,
@@ -169,23 +372,138 @@ impl Heap {
     }
   }
 
-  fn dealloc_small(
+  pub fn deallocate_large(
     &mut self,
     ptr: NonNull<u8>,
-    layout: Layout,
   ) -> Result<(), HeapError> {
-    metric!(MetricId::HeapClassLookup);
-    let class = find_class(layout.size(), layout.align())
-      .ok_or_else(|| {
-        metric!(MetricId::HeapClassLookupFail);
-        HeapError::InvalidSize
-      })?;
+    metric!(MetricId::HeapDeallocate);
+    metric!(MetricId::HeapOperationsCounter);
 
-    metric!(MetricId::HeapClassLookupSuccess);
+    self.operations = self.operations.wrapping_add(1);
+    self.free_remote()?;
+
+    metric!(MetricId::HeapDeallocLarge);
+    let capacity = self.dealloc_large(ptr)?;
+    self.counters.free(capacity);
+    Ok(())
+  }
+
+  /// Frees a small block without a layout, trusting the size class of the
+  /// segment it sits in.
+  pub fn deallocate_small(
+    &mut self,
+    ptr: NonNull<u8>,
+  ) -> Result<(), HeapError> {
+    metric!(MetricId::HeapDeallocate);
+    metric!(MetricId::HeapOperationsCounter);
+
+    self.operations = self.operations.wrapping_add(1);
+    self.free_remote()?;
+
+    metric!(MetricId::HeapDeallocSmall);
+    self.dealloc_small(ptr)
+  }
+
+  fn dealloc_small(&mut self, ptr: NonNull<u8>) -> Result<(), HeapError> {
+    metric!(MetricId::SegmentPtrLookup);
+    let Some(segment) = segment_from_ptr(ptr) else {
+      metric!(MetricId::SegmentPtrLookupFail);
+      metric!(MetricId::HeapInvalidPointer);
+      return Err(HeapError::InvalidPointer);
+    };
+
+    metric!(MetricId::SegmentPtrLookupSuccess);
+    self.free_slot(segment, ptr, options::quarantine_bytes())
+  }
+
+  /// Frees a small slot, or quarantines it while `limit` is non-zero,
+  /// releasing the oldest quarantined slots to stay within `limit` bytes.
+  fn free_slot(
+    &mut self,
+    segment: NonNull<Segment>,
+    ptr: NonNull<u8>,
+    limit: usize,
+  ) -> Result<(), HeapError> {
+    let size = unsafe { segment.as_ref() }.class().size.0;
+    while self.quarantine.over(size, limit) {
+      self.release_quarantined();
+    }
+
+    if limit > 0 {
+      let segment_ref = unsafe { segment.as_ref() };
+      if let Err(problem) = segment_ref.quarantine(ptr) {
+        invalid_free(problem, ptr.as_ptr(), Some(segment_ref.class()));
+        metric!(MetricId::HeapInvalidPointer);
+        return Err(HeapError::InvalidPointer);
+      }
+      self.quarantine.push(ptr, size);
+    } else {
+      self.dealloc_owned(segment, ptr)?;
+    }
+    self.counters.free(size);
+    Ok(())
+  }
+
+  /// Frees the oldest quarantined slot, first reporting it if anything
+  /// wrote to it while it was held. Returns false once quarantine is empty.
+  fn release_quarantined(&mut self) -> bool {
+    let Some((ptr, size)) = self.quarantine.pop() else {
+      return false;
+    };
+    let Some(segment) = segment_from_ptr(ptr) else {
+      return true;
+    };
+
+    if let Some(offset) = Quarantine::check(ptr, size) {
+      let class = unsafe { segment.as_ref() }.class();
+      report(format_args!(
+        "use after free: slot {:p} of class {} ({} B) in segment {:p} was \
+         modified at offset {}",
+        ptr, class.id, size, segment, offset
+      ));
+    }
+    unsafe { segment.as_ref() }.unquarantine(ptr);
+    let _ = self.dealloc_owned(segment, ptr);
+    true
+  }
+
+  fn flush_quarantine(&mut self) {
+    while self.release_quarantined() {}
+  }
+
+  /// Frees `ptr` into `segment` through whichever heap currently owns it.
+  /// The segment may have changed hands since the caller looked it up, when
+  /// its previous heap was abandoned.
+  fn dealloc_owned(
+    &mut self,
+    segment: NonNull<Segment>,
+    ptr: NonNull<u8>,
+  ) -> Result<(), HeapError> {
+    loop {
+      let owner = unsafe { segment.as_ref() }.owner();
+      if core::ptr::eq(owner, self) {
+        return self.dealloc_segment(segment, ptr);
+      }
+      if !owner.is_null() {
+        unsafe { segment.as_ref() }.thread_free().push(ptr);
+        return Ok(());
+      }
+      if free_abandoned(segment, ptr) {
+        return Ok(());
+      }
+    }
+  }
+
+  fn dealloc_segment(
+    &mut self,
+    segment: NonNull<Segment>,
+    ptr: NonNull<u8>,
+  ) -> Result<(), HeapError> {
+    let class = unsafe { segment.as_ref() }.class();
     let queue = &mut self.classes[class.id];
 
     metric!(MetricId::QueueDeallocate);
-    if queue.deallocate(ptr) {
+    if queue.deallocate(segment, ptr) {
       metric!(MetricId::QueueDeallocateSuccess);
       Ok(())
     } else {
@@ -195,13 +513,18 @@ impl Heap {
     }
   }
 
-  fn dealloc_large(&mut self, ptr: NonNull<u8>) -> Result<(), HeapError> {
+  /// Unmaps the large block holding `ptr`, returning its capacity. Frees
+  /// are counted by the caller, as blocks drained from the remote stack
+  /// were already counted by the thread that pushed them.
+  fn dealloc_large(&mut self, ptr: NonNull<u8>) -> Result<usize, HeapError> {
     let large_nn =
       Large::from_user_ptr(ptr).ok_or(HeapError::InvalidPointer)?;
 
     if self.large.remove(large_nn) {
+      let capacity = unsafe { large_nn.as_ref() }.capacity();
       unsafe { core::ptr::drop_in_place(large_nn.as_ptr()) };
-      Ok(())
+      self.counters.unmap_large(capacity);
+      Ok(capacity)
     } else {
       Err(HeapError::InvalidPointer)
     }
@@ -216,61 +539,33 @@ impl Heap {
     }
 
     metric!(MetricId::HeapRemoteBatched);
-    self.free_remote_batched()
+    self.drain_remote();
+    Ok(())
   }
 
   fn should_free_remote(&self) -> Result<bool, HeapError> {
-    let remote_len = {
-      let guard = self.remote.read();
-      guard.count()
-    };
-
-    if remote_len == 0 {
+    if self.remote.is_empty() {
       return Ok(false);
     }
 
-    let should_process = remote_len >= REMOTE_BATCH_SIZE
-      || self.operations % REMOTE_CHECK_FREQUENCY == 0;
+    let should_process = self.remote.count() >= options::remote_batch()
+      || self.operations.is_multiple_of(options::remote_check());
 
     Ok(should_process)
   }
 
-  fn free_remote_batched(&mut self) -> Result<(), HeapError> {
-    let mut guard = match self.remote.try_write() {
-      Some(guard) => guard,
-      None => return Ok(()),
-    };
-
-    let mut processed = 0;
-    while processed < REMOTE_MAX_BATCH && !guard.is_empty() {
-      if let Some(allocation_nn) = guard.pop() {
-        let (header_ptr, layout) = self.extract_info(allocation_nn);
-
-        drop(guard);
-
-        self.deallocate_internal(header_ptr, layout)?;
-
-        guard = match self.remote.try_write() {
-          Some(guard) => guard,
-          None => return Ok(()),
-        };
-
-        processed += 1;
+  /// Takes every pending remote free in one swap and releases them,
+  /// returning the bytes unmapped. A block that fails to free is dropped
+  /// rather than stalling the rest.
+  pub fn drain_remote(&mut self) -> usize {
+    let mut released = 0;
+    for block in self.remote.take() {
+      metric!(MetricId::HeapDeallocLarge);
+      if let Ok(size) = self.dealloc_large(block) {
+        released += size;
       }
     }
-
-    Ok(())
-  }
-
-  fn extract_info(
-    &self,
-    allocation_nn: NonNull<Allocation>,
-  ) -> (NonNull<u8>, Layout) {
-    let allocation_ref = unsafe { allocation_nn.as_ref() };
-    let header_ptr =
-      unsafe { NonNull::new_unchecked(allocation_nn.as_ptr() as *mut u8) };
-    let layout = allocation_ref.full();
-    (header_ptr, layout)
+    released
   }
 
   fn deallocate_internal(
@@ -287,27 +582,38 @@ impl Heap {
 
     if size > LARGE_SC_LIMIT {
       metric!(MetricId::HeapDeallocLarge);
-      return self.dealloc_large(ptr);
+      let capacity = self.dealloc_large(ptr)?;
+      self.counters.free(capacity);
+      return Ok(());
     }
 
     metric!(MetricId::HeapDeallocSmall);
-    self.dealloc_small(ptr, layout)
+    self.dealloc_small(ptr)
+  }
+}
+
+impl Default for Heap {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl HasLink<Heap> for Heap {
+  fn link(&self) -> &Link<Heap> {
+    &self.link
+  }
+
+  fn link_mut(&mut self) -> &mut Link<Heap> {
+    &mut self.link
   }
 }
 
 impl Drop for Heap {
   fn drop(&mut self) {
-    let mut guard = self.remote.write();
-    while let Some(allocation) = guard.pop() {
-      let (header_ptr, layout) = self.extract_info(allocation);
-      drop(guard);
-      let _ = self.deallocate_internal(header_ptr, layout);
-      guard = self.remote.write();
-    }
-    drop(guard);
+    self.abandon();
 
     for large in self.large.drain() {
-      let _ = unsafe { core::ptr::drop_in_place(large.as_ptr()) };
+      unsafe { core::ptr::drop_in_place(large.as_ptr()) };
     }
   }
 }
@@ -315,12 +621,13 @@ impl Drop for Heap {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::quarantine::POISON;
 
   #[test]
   fn test_empty_remote_list() {
     let heap = Heap::new();
     assert!(!heap.should_free_remote().unwrap());
-    assert_eq!(heap.remote.read().count(), 0);
+    assert_eq!(heap.remote.count(), 0);
   }
 
   #[test]
@@ -339,7 +646,128 @@ mod tests {
 
     assert!(!heap.should_free_remote().unwrap());
 
-    heap.operations = REMOTE_CHECK_FREQUENCY;
+    heap.operations = options::remote_check();
     assert!(!heap.should_free_remote().unwrap());
   }
+
+  #[test]
+  fn test_remote_blocks_are_drained() {
+    let mut heap = Heap::new();
+    let layout = Layout::from_size_align(LARGE_SC_LIMIT + 1, 8).unwrap();
+
+    let blocks: Vec<_> = (0..options::remote_batch())
+      .map(|_| heap.allocate(layout).unwrap().cast::<u8>())
+      .collect();
+    for block in blocks {
+      heap.remote().push(block);
+    }
+    assert!(heap.should_free_remote().unwrap());
+
+    let last = heap.allocate(layout).unwrap().cast::<u8>();
+    assert!(heap.remote.is_empty());
+    assert_eq!(heap.large.count(), 1);
+    heap.deallocate(last, layout).unwrap();
+  }
+
+  #[test]
+  fn test_full_segments_reclaim_thread_frees() {
+    let mut heap = Heap::new();
+    let layout = Layout::from_size_align(1500, 8).unwrap();
+
+    let first = heap.allocate(layout).unwrap().cast::<u8>();
+    let segment = segment_from_ptr(first).unwrap();
+    let mut blocks = vec![first];
+    while !unsafe { segment.as_ref() }.is_full() {
+      blocks.push(heap.allocate(layout).unwrap().cast::<u8>());
+    }
+
+    for &block in &blocks {
+      unsafe { segment.as_ref() }.thread_free().push(block);
+    }
+
+    for _ in 0..blocks.len() {
+      let block = heap.allocate(layout).unwrap().cast::<u8>();
+      assert_eq!(segment_from_ptr(block), Some(segment));
+    }
+    assert!(unsafe { segment.as_ref() }.thread_free().is_empty());
+  }
+
+  #[test]
+  fn test_abandoned_segments_are_adopted() {
+    // A class no other test in this crate allocates from, since abandoned
+    // segments are shared process wide.
+    let layout = Layout::from_size_align(3000, 8).unwrap();
+
+    let mut dying = Heap::new();
+    let live = dying.allocate(layout).unwrap().cast::<u8>();
+    let freed = dying.allocate(layout).unwrap().cast::<u8>();
+    let segment = segment_from_ptr(live).unwrap();
+    dying.abandon();
+    assert!(unsafe { segment.as_ref() }.owner().is_null());
+
+    assert!(free_abandoned(segment, freed));
+
+    let mut adopter = Heap::new();
+    let adopted = adopter.allocate(layout).unwrap().cast::<u8>();
+    assert_eq!(segment_from_ptr(adopted), Some(segment));
+    assert!(core::ptr::eq(unsafe { segment.as_ref() }.owner(), &adopter));
+
+    adopter.deallocate(live, layout).unwrap();
+    adopter.deallocate(adopted, layout).unwrap();
+  }
+
+  #[test]
+  fn test_quarantine_delays_reuse() {
+    let mut heap = Heap::new();
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let limit = 4 * 256;
+
+    let first = heap.allocate(layout).unwrap().cast::<u8>();
+    let segment = segment_from_ptr(first).unwrap();
+    heap.free_slot(segment, first, limit).unwrap();
+    assert_eq!(unsafe { first.as_ptr().read() }, POISON);
+
+    let mut held = Vec::new();
+    for _ in 0..3 {
+      let block = heap.allocate(layout).unwrap().cast::<u8>();
+      assert_ne!(block, first);
+      held.push(block);
+    }
+    for &block in &held {
+      heap.free_slot(segment, block, limit).unwrap();
+    }
+    assert_eq!(heap.quarantine.len(), 4);
+
+    // The fifth free pushes the first slot out, back into the segment.
+    let next = heap.allocate(layout).unwrap().cast::<u8>();
+    heap.free_slot(segment, next, limit).unwrap();
+    assert_eq!(heap.quarantine.len(), 4);
+    assert_eq!(heap.allocate(layout).unwrap().cast::<u8>(), first);
+
+    heap.flush_quarantine();
+    assert!(heap.quarantine.is_empty());
+    heap.deallocate(first, layout).unwrap();
+  }
+
+  #[test]
+  fn test_quarantine_catches_double_frees_across_heaps() {
+    let mut owner = Heap::new();
+    let mut other = Heap::new();
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let limit = 4 * 256;
+
+    let block = owner.allocate(layout).unwrap().cast::<u8>();
+    let segment = segment_from_ptr(block).unwrap();
+    owner.free_slot(segment, block, limit).unwrap();
+    assert!(other.free_slot(segment, block, limit).is_err());
+    assert!(other.quarantine.is_empty());
+
+    // Once released, the slot can be handed out and quarantined again.
+    owner.flush_quarantine();
+    let again = owner.allocate(layout).unwrap().cast::<u8>();
+    assert_eq!(again, block);
+    other.free_slot(segment, again, limit).unwrap();
+    other.flush_quarantine();
+    owner.collect();
+  }
 }
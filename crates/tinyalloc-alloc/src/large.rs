@@ -3,8 +3,16 @@ use std::{
   ptr::NonNull,
 };
 
-use getset::Getters;
-use tinyalloc_config::helper::align_up;
+use enumset::EnumSet;
+use getset::{
+  CopyGetters,
+  Getters,
+  Setters,
+};
+use tinyalloc_config::helper::{
+  MAX_ALIGN,
+  align_up,
+};
 use tinyalloc_list::{
   HasLink,
   Link,
@@ -14,43 +22,98 @@ use tinyalloc_sys::{
   region::Region,
   size::{
     cache_line_size,
-    page_align_ptr,
+    page_align,
+    page_size,
   },
 };
 
+use crate::options::LargeGuard;
+
 #[derive(Debug)]
 pub enum LargeError {
   MapError(MapError),
   SizeOverflow,
+  /// Blocks with guard pages end against their trailing guard, so they
+  /// cannot grow or shrink without moving the data.
+  Guarded,
 }
 
-#[derive(Getters)]
+#[derive(Getters, Setters, CopyGetters)]
 pub struct Large {
-  _region: Region,
+  region: Region,
   pub user: &'static mut [u8],
+  /// Whether `user` is known to read as zero, which holds until the block
+  /// is first handed out.
+  #[getset(get_copy = "pub", set = "pub")]
+  zeroed: bool,
+  #[getset(get_copy = "pub")]
+  guard: LargeGuard,
   link: Link<Large>,
 }
 
 impl Large {
-  pub fn new(size: NonZeroUsize) -> Result<NonNull<Self>, LargeError> {
-    let self_size = core::mem::size_of::<Self>();
-    let cache_line = cache_line_size();
-    let user_offset = align_up(self_size, cache_line);
-    let total_size = size
+  fn user_offset() -> usize {
+    align_up(core::mem::size_of::<Self>(), cache_line_size())
+  }
+
+  /// Maps a block whose user slice spans `size` bytes. With `guard`, the
+  /// mapping gains inaccessible pages and the user slice is pushed against
+  /// the trailing one, the header sitting right before it.
+  pub fn new(
+    size: NonZeroUsize,
+    guard: LargeGuard,
+  ) -> Result<NonNull<Self>, LargeError> {
+    let user_offset = Self::user_offset();
+    let page = page_size();
+    let span = size
       .get()
+      .checked_next_multiple_of(MAX_ALIGN)
+      .ok_or(LargeError::SizeOverflow)?;
+    let body = span
       .checked_add(user_offset)
       .ok_or(LargeError::SizeOverflow)?;
+    let (before, body) = match guard {
+      LargeGuard::Off => (0, body),
+      _ => (usize::from(guard.before()) * page, page_align(body)),
+    };
+    let after = usize::from(guard.after()) * page;
+    let total_size = before
+      .checked_add(body)
+      .and_then(|size| size.checked_add(after))
+      .ok_or(LargeError::SizeOverflow)?;
+
     let mut region = Region::new(NonZeroUsize::new(total_size).unwrap())
       .map_err(LargeError::MapError)?;
     region.activate().map_err(LargeError::MapError)?;
-    let ptr = region.as_ptr();
+    let base = region.as_ptr();
+    for (start, len) in [(0, before), (before + body, after)] {
+      if len > 0 {
+        let guard_page = unsafe {
+          NonNull::slice_from_raw_parts(
+            NonNull::new_unchecked(base.add(start)),
+            len,
+          )
+        };
+        region
+          .partial(guard_page, EnumSet::empty())
+          .map_err(LargeError::MapError)?;
+      }
+    }
+
+    let user_start = match guard {
+      LargeGuard::Off => user_offset,
+      _ => before + body - span,
+    };
+    let ptr = unsafe { base.add(user_start - user_offset) };
     let user = unsafe {
-      std::slice::from_raw_parts_mut(ptr.add(user_offset), size.get())
+      std::slice::from_raw_parts_mut(base.add(user_start), size.get())
     };
 
     let large = Self {
-      _region: region,
+      region,
       user,
+      zeroed: true,
+      guard,
       link: Link::new(),
     };
 
@@ -64,16 +127,68 @@ impl Large {
     NonNull::new(self.user as *const [u8] as *mut [u8]).unwrap()
   }
 
+  /// Bytes usable from the start of the user slice up to the end of the
+  /// mapping or its trailing guard page, including the rounding slack past
+  /// `user`.
+  pub fn capacity(&self) -> usize {
+    let after = usize::from(self.guard.after()) * page_size();
+    let end = self.region.as_ptr() as usize + self.region.data().len() - after;
+    end - self.user.as_ptr() as usize
+  }
+
+  /// Resizes the mapping behind `large` so the user slice spans `size`
+  /// bytes. Without `may_move` this only succeeds in place; with it the
+  /// block may be relocated by the kernel, so the returned pointer must
+  /// replace `large` everywhere it is referenced, including list links.
+  pub fn resize(
+    large: NonNull<Self>,
+    size: NonZeroUsize,
+    may_move: bool,
+  ) -> Result<NonNull<Self>, LargeError> {
+    if unsafe { large.as_ref() }.guard != LargeGuard::Off {
+      return Err(LargeError::Guarded);
+    }
+
+    let user_offset = Self::user_offset();
+    let total_size = size
+      .get()
+      .checked_add(user_offset)
+      .ok_or(LargeError::SizeOverflow)?;
+
+    let region = &unsafe { large.as_ref() }.region;
+    let data = if page_align(total_size) == region.data().len() {
+      *region.data()
+    } else {
+      region
+        .remap(NonZeroUsize::new(total_size).unwrap(), may_move)
+        .map_err(LargeError::MapError)?
+    };
+
+    let large_ptr = data.as_ptr() as *mut Self;
+    unsafe {
+      let large = &mut *large_ptr;
+      large.region.rebind(data);
+      large.user = std::slice::from_raw_parts_mut(
+        (large_ptr as *mut u8).add(user_offset),
+        size.get(),
+      );
+    }
+
+    NonNull::new(large_ptr).ok_or(LargeError::SizeOverflow)
+  }
+
   pub fn contains_ptr(&self, ptr: NonNull<u8>) -> bool {
     let user_start = self.user.as_ptr() as *mut u8;
     let user_end = unsafe { user_start.add(self.user.len()) };
     ptr.as_ptr() >= user_start && ptr.as_ptr() < user_end
   }
 
+  /// Finds the header of the block whose user slice starts at `ptr`. It
+  /// always sits `user_offset` bytes before the slice, at the start of the
+  /// mapping unless the block has guard pages.
   pub fn from_user_ptr(ptr: NonNull<u8>) -> Option<NonNull<Self>> {
-    let page_start = page_align_ptr(ptr.as_ptr());
-    let large_ptr = page_start as *mut Self;
-    let large_nn = NonNull::new(large_ptr)?;
+    let header = (ptr.as_ptr() as usize).checked_sub(Self::user_offset())?;
+    let large_nn = NonNull::new(header as *mut Self)?;
 
     let large = unsafe { large_nn.as_ref() };
     if large.contains_ptr(ptr) {
@@ -93,3 +208,36 @@ impl HasLink<Large> for Large {
     &mut self.link
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn guarded_large_ends_against_its_guard_page() {
+    let page = page_size();
+    let size = NonZeroUsize::new(3 * page + 100).unwrap();
+    for guard in [LargeGuard::Off, LargeGuard::After, LargeGuard::Both] {
+      let large_nn = Large::new(size, guard).unwrap();
+      let large = unsafe { large_nn.as_ref() };
+      let user = large.user_slice();
+      let start = user.as_ptr() as *mut u8 as usize;
+
+      assert_eq!(user.len(), size.get());
+      assert_eq!(start % MAX_ALIGN, 0);
+      assert_eq!(
+        Large::from_user_ptr(NonNull::new(start as *mut u8).unwrap()),
+        Some(large_nn)
+      );
+      if guard.after() {
+        assert_eq!((start + large.capacity()) % page, 0);
+        assert!(large.capacity() - size.get() < MAX_ALIGN);
+        assert!(matches!(
+          Large::resize(large_nn, size, true),
+          Err(LargeError::Guarded)
+        ));
+      }
+      unsafe { core::ptr::drop_in_place(large_nn.as_ptr()) };
+    }
+  }
+}
@@ -1,7 +1,15 @@
 pub mod allocation;
 pub mod arena;
+pub mod guarded;
 pub mod heap;
-pub mod large; 
+pub mod large;
+pub mod options;
+pub mod pagemap;
+pub mod quarantine;
 pub mod queue;
+pub mod redzone;
+pub mod remote;
+pub mod report;
 pub mod segment;
 pub mod static_;
+pub mod stats;
@@ -0,0 +1,224 @@
+use std::{
+  sync::atomic::{
+    AtomicBool,
+    AtomicU8,
+    AtomicU64,
+    AtomicUsize,
+    Ordering,
+  },
+  time::Duration,
+};
+
+use tinyalloc_config::config::{
+  ARENA_GROWTH,
+  ARENA_INITIAL_SIZE,
+  ARENA_MAX_SIZE,
+  ARENA_RELEASE_DELAY_MS,
+  QUEUE_THRESHOLD,
+  REMOTE_BATCH_SIZE,
+  REMOTE_CHECK_FREQUENCY,
+  SEGMENT_SIZE,
+};
+
+/// Bytes of freed small blocks each heap holds back, zero when quarantine
+/// is off.
+static QUARANTINE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static FREE_POLICY: AtomicU8 = AtomicU8::new(FreePolicy::Ignore as u8);
+static REDZONE: AtomicBool = AtomicBool::new(false);
+/// One in this many allocations goes to the guarded pool, zero for none.
+static SAMPLE_RATE: AtomicUsize = AtomicUsize::new(0);
+static LARGE_GUARD: AtomicU8 = AtomicU8::new(LargeGuard::Off as u8);
+
+// Runtime stand-ins for the build-time geometry that is safe to change
+// while the allocator runs. Each starts at its `config` value.
+static ARENA_SIZE: AtomicUsize = AtomicUsize::new(ARENA_INITIAL_SIZE);
+static ARENA_GROWTH_FACTOR: AtomicUsize = AtomicUsize::new(ARENA_GROWTH);
+static RETAINED_SEGMENTS: AtomicUsize = AtomicUsize::new(QUEUE_THRESHOLD);
+static REMOTE_BATCH: AtomicUsize = AtomicUsize::new(REMOTE_BATCH_SIZE);
+static REMOTE_CHECK: AtomicUsize = AtomicUsize::new(REMOTE_CHECK_FREQUENCY);
+static RELEASE_DELAY_MS: AtomicU64 = AtomicU64::new(ARENA_RELEASE_DELAY_MS);
+
+/// What to do when a free is found to be invalid or a block to have been
+/// written past its end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FreePolicy {
+  /// Drop the free without a word, as if it had succeeded.
+  Ignore,
+  /// Print a report to stderr and carry on.
+  Log,
+  /// Print a report to stderr and abort the process.
+  Abort,
+}
+
+impl FreePolicy {
+  /// Parses `ignore`, `log` or `abort`.
+  pub fn parse(name: &[u8]) -> Option<Self> {
+    match name {
+      b"ignore" => Some(Self::Ignore),
+      b"log" => Some(Self::Log),
+      b"abort" => Some(Self::Abort),
+      _ => None,
+    }
+  }
+}
+
+/// Which inaccessible pages surround the user region of large blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LargeGuard {
+  /// No guard pages.
+  Off,
+  /// One page right after the user region.
+  After,
+  /// One page before the block and one right after the user region.
+  Both,
+}
+
+impl LargeGuard {
+  /// Parses `off`, `after` or `both`.
+  pub fn parse(name: &[u8]) -> Option<Self> {
+    match name {
+      b"off" | b"0" => Some(Self::Off),
+      b"after" | b"1" => Some(Self::After),
+      b"both" => Some(Self::Both),
+      _ => None,
+    }
+  }
+
+  pub fn before(self) -> bool {
+    self == Self::Both
+  }
+
+  pub fn after(self) -> bool {
+    self != Self::Off
+  }
+}
+
+pub fn quarantine_bytes() -> usize {
+  QUARANTINE_BYTES.load(Ordering::Relaxed)
+}
+
+/// Sets how many bytes of freed small blocks each heap keeps poisoned in
+/// quarantine before reusing them. Zero turns quarantine off; blocks
+/// already held are released as their heaps free or collect.
+pub fn set_quarantine_bytes(bytes: usize) {
+  QUARANTINE_BYTES.store(bytes, Ordering::Relaxed);
+}
+
+pub fn free_policy() -> FreePolicy {
+  match FREE_POLICY.load(Ordering::Relaxed) {
+    0 => FreePolicy::Ignore,
+    1 => FreePolicy::Log,
+    _ => FreePolicy::Abort,
+  }
+}
+
+/// Sets how double frees, frees of pointers tinyalloc never handed out
+/// and frees that disagree with the block's size are handled. Defaults
+/// to [`FreePolicy::Ignore`].
+pub fn set_free_policy(policy: FreePolicy) {
+  FREE_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+pub fn redzone() -> bool {
+  REDZONE.load(Ordering::Relaxed)
+}
+
+/// Pads every block with a redzone that is checked when the block is
+/// freed or resized. Must be set before the first allocation: blocks made
+/// without a redzone would otherwise read as overrun when freed.
+pub fn set_redzone(on: bool) {
+  REDZONE.store(on, Ordering::Relaxed);
+}
+
+pub fn sample_rate() -> usize {
+  SAMPLE_RATE.load(Ordering::Relaxed)
+}
+
+/// Serves about one allocation in every `rate` from pages of their own,
+/// each followed by an inaccessible guard page and made inaccessible once
+/// freed, so overruns and use after free fault on the spot. Zero turns
+/// sampling off.
+pub fn set_sample_rate(rate: usize) {
+  SAMPLE_RATE.store(rate, Ordering::Relaxed);
+}
+
+pub fn large_guard() -> LargeGuard {
+  match LARGE_GUARD.load(Ordering::Relaxed) {
+    0 => LargeGuard::Off,
+    1 => LargeGuard::After,
+    _ => LargeGuard::Both,
+  }
+}
+
+/// Maps large blocks made from now on with guard pages, their user region
+/// pushed against the page after it so overruns fault. Blocks with guard
+/// pages are never resized in place. Defaults to [`LargeGuard::Off`].
+pub fn set_large_guard(guard: LargeGuard) {
+  LARGE_GUARD.store(guard as u8, Ordering::Relaxed);
+}
+
+pub fn arena_initial_size() -> usize {
+  ARENA_SIZE.load(Ordering::Relaxed)
+}
+
+/// Sets the size of the first arenas, kept between two segments and
+/// `ARENA_MAX_SIZE`. Only arenas reserved from now on are affected.
+pub fn set_arena_initial_size(bytes: usize) {
+  ARENA_SIZE.store(
+    bytes.clamp(2 * SEGMENT_SIZE, ARENA_MAX_SIZE),
+    Ordering::Relaxed,
+  );
+}
+
+pub fn arena_growth() -> usize {
+  ARENA_GROWTH_FACTOR.load(Ordering::Relaxed)
+}
+
+/// Sets the factor arenas grow by every `ARENA_STEP` arenas. One keeps
+/// every arena at the initial size.
+pub fn set_arena_growth(factor: usize) {
+  ARENA_GROWTH_FACTOR.store(factor.max(1), Ordering::Relaxed);
+}
+
+pub fn retained_segments() -> usize {
+  RETAINED_SEGMENTS.load(Ordering::Relaxed)
+}
+
+/// Sets how many empty segments each class of each heap keeps before
+/// handing the rest back to their arenas. Defaults to `QUEUE_THRESHOLD`.
+pub fn set_retained_segments(count: usize) {
+  RETAINED_SEGMENTS.store(count, Ordering::Relaxed);
+}
+
+pub fn remote_batch() -> usize {
+  REMOTE_BATCH.load(Ordering::Relaxed)
+}
+
+/// Sets how many frees from other threads a heap lets pile up before it
+/// takes them back, whatever its operation count.
+pub fn set_remote_batch(count: usize) {
+  REMOTE_BATCH.store(count.max(1), Ordering::Relaxed);
+}
+
+pub fn remote_check() -> usize {
+  REMOTE_CHECK.load(Ordering::Relaxed)
+}
+
+/// Sets how many operations a heap makes between checks for frees from
+/// other threads.
+pub fn set_remote_check(operations: usize) {
+  REMOTE_CHECK.store(operations.max(1), Ordering::Relaxed);
+}
+
+pub fn arena_release_delay() -> Duration {
+  Duration::from_millis(RELEASE_DELAY_MS.load(Ordering::Relaxed))
+}
+
+/// Sets how long an arena must go without live segments before it is
+/// unmapped. Zero releases arenas as soon as they empty and `u64::MAX`
+/// keeps them for good.
+pub fn set_arena_release_delay(millis: u64) {
+  RELEASE_DELAY_MS.store(millis, Ordering::Relaxed);
+}
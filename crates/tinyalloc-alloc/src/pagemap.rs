@@ -0,0 +1,157 @@
+use std::{
+  num::NonZeroUsize,
+  ptr::NonNull,
+  sync::atomic::{
+    AtomicPtr,
+    AtomicUsize,
+    Ordering,
+  },
+};
+
+use tinyalloc_config::config::SEGMENT_SIZE;
+use tinyalloc_sys::{
+  GLOBAL_MAPPER,
+  mapper::Protection,
+};
+
+use crate::arena::ArenaError;
+
+/// Bits of user address space covered by the map.
+const ADDRESS_BITS: u32 = 48;
+const SEGMENT_SHIFT: u32 = SEGMENT_SIZE.trailing_zeros();
+/// Bits of a segment slot index, one slot per `SEGMENT_SIZE` of address
+/// space.
+const INDEX_BITS: u32 = ADDRESS_BITS - SEGMENT_SHIFT;
+/// The root holds at most 2^14 leaf pointers, 128 KiB of static memory,
+/// whatever the segment size.
+const ROOT_BITS: u32 = if INDEX_BITS < 14 { INDEX_BITS } else { 14 };
+/// Each leaf tracks 2^`LEAF_BITS` segment slots, one bit each, so smaller
+/// segments get larger leaves. With 512 KiB segments that is 2^15 slots,
+/// 16 GiB of address space, in 4 KiB.
+const LEAF_BITS: u32 = INDEX_BITS - ROOT_BITS;
+const LEAF_WORDS: usize = (1usize << LEAF_BITS).div_ceil(usize::BITS as usize);
+const ROOT_LEN: usize = 1 << ROOT_BITS;
+
+const _: () = assert!(SEGMENT_SIZE.is_power_of_two());
+const _: () = assert!(SEGMENT_SHIFT < ADDRESS_BITS);
+
+struct Leaf {
+  words: [AtomicUsize; LEAF_WORDS],
+}
+
+/// Two-level bitmap with one bit per `SEGMENT_SIZE` slot of the address
+/// space, set while an active segment starts at that slot. Leaves are
+/// mapped on first use and never released, so readers need no lock.
+static ROOT: [AtomicPtr<Leaf>; ROOT_LEN] =
+  [const { AtomicPtr::new(core::ptr::null_mut()) }; ROOT_LEN];
+
+struct Slot {
+  root: usize,
+  word: usize,
+  mask: usize,
+}
+
+fn slot(addr: usize) -> Option<Slot> {
+  let index = addr >> SEGMENT_SHIFT;
+  let root = index >> LEAF_BITS;
+  if root >= ROOT_LEN {
+    return None;
+  }
+
+  let bit = index & ((1 << LEAF_BITS) - 1);
+  Some(Slot {
+    root,
+    word: bit / usize::BITS as usize,
+    mask: 1 << (bit % usize::BITS as usize),
+  })
+}
+
+fn leaf(root: usize) -> Option<&'static Leaf> {
+  let leaf = ROOT[root].load(Ordering::Acquire);
+  unsafe { leaf.as_ref() }
+}
+
+fn leaf_or_map(root: usize) -> Result<&'static Leaf, ArenaError> {
+  if let Some(leaf) = leaf(root) {
+    return Ok(leaf);
+  }
+
+  let size = NonZeroUsize::new(core::mem::size_of::<Leaf>()).unwrap();
+  let mapped = GLOBAL_MAPPER.map(size).map_err(ArenaError::MapError)?;
+  if let Err(e) =
+    GLOBAL_MAPPER.protect(mapped, Protection::Read | Protection::Write)
+  {
+    GLOBAL_MAPPER.unmap(mapped);
+    return Err(ArenaError::MapError(e));
+  }
+
+  // Fresh mappings read as zero, which is an empty leaf.
+  let fresh = mapped.cast::<Leaf>().as_ptr();
+  match ROOT[root].compare_exchange(
+    core::ptr::null_mut(),
+    fresh,
+    Ordering::AcqRel,
+    Ordering::Acquire,
+  ) {
+    Ok(_) => Ok(unsafe { &*fresh }),
+    Err(installed) => {
+      GLOBAL_MAPPER.unmap(mapped);
+      Ok(unsafe { &*installed })
+    }
+  }
+}
+
+/// Records that an active segment now starts at `segment`.
+pub fn insert(segment: NonNull<u8>) -> Result<(), ArenaError> {
+  let slot = slot(segment.as_ptr() as usize).ok_or(ArenaError::Insufficient)?;
+  let leaf = leaf_or_map(slot.root)?;
+  leaf.words[slot.word].fetch_or(slot.mask, Ordering::Release);
+  Ok(())
+}
+
+pub fn remove(segment: NonNull<u8>) {
+  if let Some(slot) = slot(segment.as_ptr() as usize)
+    && let Some(leaf) = leaf(slot.root)
+  {
+    leaf.words[slot.word].fetch_and(!slot.mask, Ordering::Release);
+  }
+}
+
+/// Returns the start of the active segment slot holding `addr`, if any.
+pub fn lookup(addr: usize) -> Option<NonNull<u8>> {
+  let slot = slot(addr)?;
+  let word = leaf(slot.root)?.words[slot.word].load(Ordering::Acquire);
+  if word & slot.mask == 0 {
+    return None;
+  }
+  NonNull::new((addr & !(SEGMENT_SIZE - 1)) as *mut u8)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn pagemap_tracks_segment_slots() {
+    // Far above anything the allocator maps, so no live segment collides.
+    let base = (1usize << 46) + 7 * SEGMENT_SIZE;
+    let segment = NonNull::new(base as *mut u8).unwrap();
+
+    assert!(lookup(base + 123).is_none());
+    insert(segment).unwrap();
+    assert_eq!(lookup(base), Some(segment));
+    assert_eq!(lookup(base + SEGMENT_SIZE - 1), Some(segment));
+    assert!(lookup(base + SEGMENT_SIZE).is_none());
+    assert!(lookup(base - 1).is_none());
+
+    remove(segment);
+    assert!(lookup(base + 123).is_none());
+  }
+
+  #[test]
+  fn pagemap_rejects_addresses_outside_the_map() {
+    assert!(lookup(usize::MAX).is_none());
+    let outside = NonNull::new((1usize << ADDRESS_BITS) as *mut u8).unwrap();
+    assert!(matches!(insert(outside), Err(ArenaError::Insufficient)));
+  }
+}
@@ -0,0 +1,124 @@
+use std::ptr::NonNull;
+
+use tinyalloc_config::config::QUARANTINE_SLOTS;
+
+/// Byte written over every quarantined slot.
+pub const POISON: u8 = 0xDF;
+
+/// Bounded FIFO of freed slots that are held back from reuse. Each slot is
+/// poisoned on the way in and checked on the way out, so writes made
+/// through a dangling pointer are caught when the slot leaves.
+pub struct Quarantine {
+  slots: [Option<(NonNull<u8>, usize)>; QUARANTINE_SLOTS],
+  head: usize,
+  len: usize,
+  bytes: usize,
+}
+
+impl Quarantine {
+  pub const fn new() -> Self {
+    Self {
+      slots: [None; QUARANTINE_SLOTS],
+      head: 0,
+      len: 0,
+      bytes: 0,
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  /// Bytes currently held, as passed to [`Quarantine::push`].
+  pub fn bytes(&self) -> usize {
+    self.bytes
+  }
+
+  /// Poisons the `size` bytes at `ptr` and queues the slot. The slot must
+  /// stay allocated until it is popped.
+  pub fn push(&mut self, ptr: NonNull<u8>, size: usize) {
+    debug_assert!(self.len < QUARANTINE_SLOTS);
+    unsafe { ptr.as_ptr().write_bytes(POISON, size) };
+    let tail = (self.head + self.len) % QUARANTINE_SLOTS;
+    self.slots[tail] = Some((ptr, size));
+    self.len += 1;
+    self.bytes += size;
+  }
+
+  /// Whether another slot of `size` bytes would exceed `limit` bytes or
+  /// the fixed capacity, so the oldest should be popped first.
+  pub fn over(&self, size: usize, limit: usize) -> bool {
+    self.len > 0 && (self.len == QUARANTINE_SLOTS || self.bytes + size > limit)
+  }
+
+  /// Takes the oldest slot along with the size it was pushed with.
+  pub fn pop(&mut self) -> Option<(NonNull<u8>, usize)> {
+    if self.len == 0 {
+      return None;
+    }
+    let (ptr, size) = self.slots[self.head].take()?;
+    self.head = (self.head + 1) % QUARANTINE_SLOTS;
+    self.len -= 1;
+    self.bytes -= size;
+    Some((ptr, size))
+  }
+
+  /// Offset of the first byte in the `size` bytes at `ptr` that no longer
+  /// holds [`POISON`], if any.
+  pub fn check(ptr: NonNull<u8>, size: usize) -> Option<usize> {
+    let slot = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), size) };
+    slot.iter().position(|&byte| byte != POISON)
+  }
+}
+
+impl Default for Quarantine {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn quarantine_is_fifo_and_bounded() {
+    let mut slots = [[0u8; 32]; 4];
+    let ptrs: Vec<_> = slots
+      .iter_mut()
+      .map(|slot| NonNull::from(slot).cast())
+      .collect();
+    let mut quarantine = Quarantine::new();
+
+    for &ptr in &ptrs[..3] {
+      assert!(!quarantine.over(32, 96));
+      quarantine.push(ptr, 32);
+    }
+    assert_eq!(quarantine.bytes(), 96);
+    assert!(quarantine.over(32, 96));
+
+    assert_eq!(quarantine.pop(), Some((ptrs[0], 32)));
+    quarantine.push(ptrs[3], 32);
+    let order: Vec<_> =
+      core::iter::from_fn(|| quarantine.pop().map(|(ptr, _)| ptr)).collect();
+    assert_eq!(order, ptrs[1..]);
+    assert!(quarantine.is_empty());
+    assert_eq!(quarantine.bytes(), 0);
+  }
+
+  #[test]
+  fn quarantine_check_finds_writes_after_free() {
+    let mut slot = [0u8; 64];
+    let ptr = NonNull::from(&mut slot).cast::<u8>();
+    let mut quarantine = Quarantine::new();
+    quarantine.push(ptr, 64);
+    assert_eq!(Quarantine::check(ptr, 64), None);
+
+    unsafe { ptr.as_ptr().add(40).write(0) };
+    assert_eq!(Quarantine::check(ptr, 64), Some(40));
+  }
+}
@@ -1,21 +1,33 @@
-use std::ptr::NonNull;
+use std::{
+  ptr::NonNull,
+  sync::atomic::{
+    AtomicUsize,
+    Ordering,
+  },
+};
 
 use tinyalloc_config::{
   classes::Class,
-  config::QUEUE_THRESHOLD,
+  config::SEGMENT_SIZE,
   metric,
 };
 
 #[cfg(feature = "metrics")]
 use tinyalloc_config::metrics::MetricId;
-use tinyalloc_list::List;
+use tinyalloc_list::{
+  HasLink,
+  List,
+};
 
-use crate::{ 
+use crate::{
+  heap::Heap,
+  options::retained_segments,
   segment::Segment,
   static_::{
+    abandon_segment,
+    adopt_segment,
     allocate_segment,
     deallocate_segment,
-    segment_from_ptr,
   },
 };
 
@@ -32,6 +44,8 @@ pub struct Queue {
   free_list: List<Segment>,
   partial_list: List<Segment>,
   full_list: List<Segment>,
+  /// Length of `free_list`, readable from other threads for statistics.
+  free_segments: AtomicUsize,
 }
 
 impl Queue {
@@ -41,31 +55,21 @@ impl Queue {
       free_list: List::new(),
       partial_list: List::new(),
       full_list: List::new(),
+      free_segments: AtomicUsize::new(0),
     }
   }
 
   pub fn displace(&mut self, mut segment: NonNull<Segment>, mv: Position) {
     metric!(MetricId::QueueSegmentDisplace);
 
+    self.detach(segment);
     let segment_ref = unsafe { segment.as_mut() };
-    let current_position = segment_ref.current().clone();
-
-    match current_position {
-      Position::Free => {
-        let _ = self.free_list.remove(segment);
-      }
-      Position::Partial => {
-        let _ = self.partial_list.remove(segment);
-      }
-      Position::Full => {
-        let _ = self.full_list.remove(segment);
-      }
-    }
 
     match mv {
       Position::Free => {
         metric!(MetricId::SegmentStateTransitionPartialToFree);
         self.free_list.push(segment);
+        self.free_segments.fetch_add(1, Ordering::Relaxed);
         segment_ref.set_current(Position::Free);
       }
       Position::Partial => {
@@ -81,6 +85,37 @@ impl Queue {
     }
   }
 
+  /// Unlinks `segment` from whichever list its position says it is on.
+  fn detach(&mut self, segment: NonNull<Segment>) {
+    match unsafe { segment.as_ref() }.current() {
+      Position::Free => {
+        if self.free_list.remove(segment) {
+          self.free_segments.fetch_sub(1, Ordering::Relaxed);
+        }
+      }
+      Position::Partial => {
+        let _ = self.partial_list.remove(segment);
+      }
+      Position::Full => {
+        let _ = self.full_list.remove(segment);
+      }
+    }
+  }
+
+  /// Number of empty segments kept for reuse.
+  pub fn free_segments(&self) -> usize {
+    self.free_segments.load(Ordering::Relaxed)
+  }
+
+  /// Every segment held by the queue, whatever its state.
+  pub fn segments(&self) -> impl Iterator<Item = &Segment> {
+    self
+      .free_list
+      .iter()
+      .chain(self.partial_list.iter())
+      .chain(self.full_list.iter())
+  }
+
   pub fn has_available(&self) -> bool {
     self.free_list.head().is_some() || self.partial_list.head().is_some()
   }
@@ -89,21 +124,52 @@ impl Queue {
     metric!(MetricId::QueueGetAvailable);
 
     if let Some(segment) = self.free_list.pop() {
+      self.free_segments.fetch_sub(1, Ordering::Relaxed);
       metric!(MetricId::QueueGetAvailableFromFree);
-      Some(segment)
-    } else if let Some(segment) = self.partial_list.pop() {
+      return Some(segment);
+    }
+
+    while let Some(mut segment) = self.partial_list.pop() {
+      // Take in the frees other threads made first; they may have emptied
+      // the segment, which then goes back to its arena like any other.
+      let segment_ref = unsafe { segment.as_mut() };
+      segment_ref.collect();
+      if segment_ref.is_empty() && self.free_list.count() > retained_segments()
+      {
+        metric!(MetricId::QueueTrimFreeSegments);
+        let _ = deallocate_segment(segment);
+        continue;
+      }
       metric!(MetricId::QueueGetAvailableFromPartial);
-      Some(segment)
-    } else {
-      metric!(MetricId::QueueGetAvailableNone);
-      None
+      return Some(segment);
     }
+
+    metric!(MetricId::QueueGetAvailableNone);
+    None
   }
 
-  pub fn allocate(&mut self) -> Option<NonNull<u8>> {
+  pub fn allocate(&mut self, owner: *mut Heap) -> Option<NonNull<u8>> {
+    self.allocate_with(owner, Segment::alloc)
+  }
+
+  /// Allocates a slot whose first `size` bytes are zero, clearing it only
+  /// when the segment has handed it out before.
+  pub fn allocate_zeroed(
+    &mut self,
+    owner: *mut Heap,
+    size: usize,
+  ) -> Option<NonNull<u8>> {
+    self.allocate_with(owner, |segment| segment.alloc_zeroed(size))
+  }
+
+  fn allocate_with(
+    &mut self,
+    owner: *mut Heap,
+    mut alloc: impl FnMut(&mut Segment) -> Option<NonNull<u8>>,
+  ) -> Option<NonNull<u8>> {
     if let Some(mut segment) = self.get_available() {
       metric!(MetricId::SegmentAlloc);
-      if let Some(ptr) = unsafe { segment.as_mut() }.alloc() {
+      if let Some(ptr) = alloc(unsafe { segment.as_mut() }) {
         metric!(MetricId::SegmentAllocSuccess);
         self.update_state(segment);
         return Some(ptr);
@@ -112,12 +178,31 @@ impl Queue {
       }
     }
 
+    if let Some(mut segment) = self.reclaim_full() {
+      metric!(MetricId::SegmentAlloc);
+      if let Some(ptr) = alloc(unsafe { segment.as_mut() }) {
+        metric!(MetricId::SegmentAllocSuccess);
+        self.update_state(segment);
+        return Some(ptr);
+      }
+    }
+
+    while let Some(mut segment) = adopt_segment(self.class, owner) {
+      unsafe { segment.as_mut() }.collect();
+      self.update_state(segment);
+      if let Some(ptr) = alloc(unsafe { segment.as_mut() }) {
+        self.update_state(segment);
+        return Some(ptr);
+      }
+    }
+
     metric!(MetricId::QueueNewSegmentCreated);
     let mut new_segment = allocate_segment(self.class).ok()?;
+    unsafe { new_segment.as_ref() }.set_owner(owner);
     self.add_segment(new_segment);
 
     metric!(MetricId::SegmentAlloc);
-    let ptr = unsafe { new_segment.as_mut() }.alloc()?;
+    let ptr = alloc(unsafe { new_segment.as_mut() })?;
     metric!(MetricId::SegmentAllocSuccess);
     self.update_state(new_segment);
     Some(ptr)
@@ -126,20 +211,15 @@ impl Queue {
   pub fn add_segment(&mut self, segment: NonNull<Segment>) {
     metric!(MetricId::QueueAddSegment);
     self.free_list.push(segment);
+    self.free_segments.fetch_add(1, Ordering::Relaxed);
   }
 
-  pub fn deallocate(&mut self, ptr: NonNull<u8>) -> bool {
-    metric!(MetricId::SegmentPtrLookup);
-    let segment = match self.segment_from_ptr(ptr) {
-      Some(mut segment) => {
-        metric!(MetricId::SegmentPtrLookupSuccess);
-        unsafe { segment.as_mut() }
-      }
-      None => {
-        metric!(MetricId::SegmentPtrLookupFail);
-        return false;
-      }
-    };
+  pub fn deallocate(
+    &mut self,
+    mut segment: NonNull<Segment>,
+    ptr: NonNull<u8>,
+  ) -> bool {
+    let segment = unsafe { segment.as_mut() };
 
     metric!(MetricId::SegmentDealloc);
     if !segment.dealloc(ptr) {
@@ -148,12 +228,12 @@ impl Queue {
     }
     metric!(MetricId::SegmentDeallocSuccess);
 
-    if segment.is_empty() && self.free_list.count() > QUEUE_THRESHOLD {
+    if segment.is_empty() && self.free_list.count() > retained_segments() {
       metric!(MetricId::QueueTrimFreeSegments);
       metric!(MetricId::QueueTrimSegmentsRemoved);
       let segment_ptr = NonNull::from(segment);
-      let _ = self.free_list.remove(segment_ptr);
-      let _ = deallocate_segment(segment_ptr.cast());
+      self.detach(segment_ptr);
+      let _ = deallocate_segment(segment_ptr);
     } else {
       self.update_state(NonNull::from(segment));
     }
@@ -161,21 +241,85 @@ impl Queue {
     true
   }
 
-  fn segment_from_ptr(&self, ptr: NonNull<u8>) -> Option<NonNull<Segment>> {
-    segment_from_ptr(ptr).map(|segment| segment.cast())
+  /// Merges the thread frees of the first full segment that has any, and
+  /// returns it if that left room to allocate.
+  fn reclaim_full(&mut self) -> Option<NonNull<Segment>> {
+    let mut segment = self
+      .full_list
+      .iter()
+      .find(|segment| !segment.thread_free().is_empty())
+      .map(NonNull::from)?;
+
+    unsafe { segment.as_mut() }.collect();
+    self.update_state(segment);
+    (!unsafe { segment.as_ref() }.is_full()).then_some(segment)
   }
 
-  fn update_state(&mut self, segment: NonNull<Segment>) {
-    let segment_ref = unsafe { segment.as_ref() };
+  /// Merges pending thread frees into every segment and returns each one
+  /// left empty to its arena, ignoring [`retained_segments`]. Returns the
+  /// bytes decommitted.
+  pub fn collect(&mut self) -> usize {
+    let mut released = 0;
+    for position in [Position::Full, Position::Partial, Position::Free] {
+      let list = match position {
+        Position::Free => &self.free_list,
+        Position::Partial => &self.partial_list,
+        Position::Full => &self.full_list,
+      };
 
-    let new_state = if segment_ref.is_full() {
+      let mut cursor = *list.head();
+      while let Some(mut segment) = cursor {
+        cursor = *unsafe { segment.as_ref() }.link().next();
+        unsafe { segment.as_mut() }.collect();
+        let segment_ref = unsafe { segment.as_ref() };
+        if segment_ref.is_empty() {
+          self.detach(segment);
+          if deallocate_segment(segment).is_ok() {
+            released += SEGMENT_SIZE;
+          }
+        } else if *segment_ref.current() != Self::position_of(segment_ref) {
+          // Only move segments that change lists; one pushed back onto the
+          // tail of the list being walked would be visited again.
+          self.update_state(segment);
+        }
+      }
+    }
+    released
+  }
+
+  /// Releases empty segments and publishes the rest for adoption by other
+  /// heaps, leaving the queue empty.
+  pub fn abandon(&mut self) {
+    let lists = [
+      &mut self.free_list,
+      &mut self.partial_list,
+      &mut self.full_list,
+    ];
+    for list in lists {
+      for mut segment in list.drain() {
+        unsafe { segment.as_mut() }.collect();
+        if unsafe { segment.as_ref() }.is_empty() {
+          let _ = deallocate_segment(segment);
+        } else {
+          abandon_segment(segment);
+        }
+      }
+    }
+    self.free_segments.store(0, Ordering::Relaxed);
+  }
+
+  fn position_of(segment: &Segment) -> Position {
+    if segment.is_full() {
       Position::Full
-    } else if segment_ref.is_empty() {
+    } else if segment.is_empty() {
       Position::Free
     } else {
       Position::Partial
-    };
+    }
+  }
 
+  fn update_state(&mut self, segment: NonNull<Segment>) {
+    let new_state = Self::position_of(unsafe { segment.as_ref() });
     self.displace(segment, new_state);
   }
 }
@@ -196,7 +340,10 @@ impl Drop for Queue {
 
 #[cfg(test)]
 mod tests {
-  use tinyalloc_config::classes::CLASSES;
+  use tinyalloc_config::{
+    classes::CLASSES,
+    config::LARGE_SC_LIMIT,
+  };
 
 use super::*; 
 
@@ -210,4 +357,109 @@ use super::*;
       "New queue should have no available segments"
     );
   }
+
+  #[test]
+  fn queue_trims_segments_emptied_from_partial() {
+    let class =
+      tinyalloc_config::classes::find_class(LARGE_SC_LIMIT / 4, 8).unwrap();
+    let mut queue = Queue::new(class);
+
+    let blocks: Vec<_> = (0..256)
+      .map(|_| queue.allocate(core::ptr::null_mut()).unwrap())
+      .collect();
+    for &block in &blocks {
+      let segment = crate::static_::segment_from_ptr(block).unwrap();
+      assert!(queue.deallocate(segment, block));
+    }
+    assert!(queue.free_list.count() <= retained_segments() + 1);
+    assert!(queue.partial_list.is_empty());
+    assert!(queue.full_list.is_empty());
+
+    for _ in 0..blocks.len() {
+      let block = queue.allocate(core::ptr::null_mut()).unwrap();
+      let segment = crate::static_::segment_from_ptr(block).unwrap();
+      assert!(queue.deallocate(segment, block));
+    }
+  }
+
+  #[test]
+  fn queue_collect_releases_every_empty_segment() {
+    let class =
+      tinyalloc_config::classes::find_class(LARGE_SC_LIMIT / 4, 8).unwrap();
+    let mut queue = Queue::new(class);
+
+    let blocks: Vec<_> = (0..32)
+      .map(|_| queue.allocate(core::ptr::null_mut()).unwrap())
+      .collect();
+    let mut segments = std::collections::HashSet::new();
+    for (index, &block) in blocks.iter().enumerate() {
+      let segment = crate::static_::segment_from_ptr(block).unwrap();
+      segments.insert(segment);
+      if index % 2 == 0 {
+        assert!(queue.deallocate(segment, block));
+      } else {
+        unsafe { segment.as_ref() }.thread_free().push(block);
+      }
+    }
+
+    assert_eq!(queue.collect(), segments.len() * SEGMENT_SIZE);
+    assert!(queue.free_list.is_empty());
+    assert!(queue.partial_list.is_empty());
+    assert!(queue.full_list.is_empty());
+  }
+
+  #[test]
+  fn queue_collect_keeps_live_segments_in_place() {
+    let class =
+      tinyalloc_config::classes::find_class(LARGE_SC_LIMIT / 4, 8).unwrap();
+    let mut queue = Queue::new(class);
+
+    let blocks: Vec<_> = (0..64)
+      .map(|_| queue.allocate(core::ptr::null_mut()).unwrap())
+      .collect();
+    let full = queue.full_list.count();
+    assert!(full >= 2);
+
+    assert_eq!(queue.collect(), 0);
+    assert_eq!(queue.full_list.count(), full);
+
+    for &block in &blocks {
+      let segment = crate::static_::segment_from_ptr(block).unwrap();
+      assert!(queue.deallocate(segment, block));
+    }
+  }
+
+  #[test]
+  fn partial_segments_take_in_frees_from_other_threads() {
+    let class = tinyalloc_config::classes::find_class(512, 8).unwrap();
+    let mut queue = Queue::new(class);
+
+    let blocks: Vec<_> = (0..2)
+      .map(|_| queue.allocate(core::ptr::null_mut()).unwrap())
+      .collect();
+    let segment = crate::static_::segment_from_ptr(blocks[0]).unwrap();
+    assert_eq!(queue.partial_list.count(), 1);
+
+    let address = segment.as_ptr() as usize;
+    let freed: Vec<usize> =
+      blocks.iter().map(|block| block.as_ptr() as usize).collect();
+    std::thread::spawn(move || {
+      let segment = unsafe { &*(address as *const Segment) };
+      for block in freed {
+        segment
+          .thread_free()
+          .push(NonNull::new(block as *mut u8).unwrap());
+      }
+    })
+    .join()
+    .unwrap();
+
+    // With both frees taken in, the segment empties again once the slot
+    // allocated from it is freed.
+    let block = queue.allocate(core::ptr::null_mut()).unwrap();
+    assert_eq!(crate::static_::segment_from_ptr(block), Some(segment));
+    assert!(queue.deallocate(segment, block));
+    assert!(unsafe { segment.as_ref() }.is_empty());
+    assert!(queue.partial_list.is_empty());
+  }
 }
@@ -0,0 +1,46 @@
+use std::ptr::NonNull;
+
+/// Byte written over the slack past the end of each block while redzones
+/// are on.
+pub const REDZONE: u8 = 0xFD;
+
+/// Fills bytes `start..end` of the block at `ptr` with [`REDZONE`].
+pub fn fill(ptr: NonNull<u8>, start: usize, end: usize) {
+  if start < end {
+    unsafe { ptr.as_ptr().add(start).write_bytes(REDZONE, end - start) };
+  }
+}
+
+/// Offset from `ptr` of the first byte in `start..end` that no longer
+/// holds [`REDZONE`], if any.
+pub fn check(ptr: NonNull<u8>, start: usize, end: usize) -> Option<usize> {
+  if start >= end {
+    return None;
+  }
+  let zone = unsafe {
+    core::slice::from_raw_parts(ptr.as_ptr().add(start), end - start)
+  };
+  zone
+    .iter()
+    .position(|&byte| byte != REDZONE)
+    .map(|index| start + index)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn redzone_check_reports_first_overwritten_byte() {
+    let mut block = [0u8; 48];
+    let ptr = NonNull::from(&mut block).cast::<u8>();
+    fill(ptr, 30, 48);
+    assert_eq!(check(ptr, 30, 48), None);
+    assert_eq!(unsafe { ptr.as_ptr().add(29).read() }, 0);
+
+    unsafe { ptr.as_ptr().add(33).write(0) };
+    unsafe { ptr.as_ptr().add(40).write(0) };
+    assert_eq!(check(ptr, 30, 48), Some(33));
+    assert_eq!(check(ptr, 48, 48), None);
+  }
+}
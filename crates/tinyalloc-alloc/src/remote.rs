@@ -0,0 +1,180 @@
+use std::{
+  ptr::NonNull,
+  sync::atomic::{
+    AtomicPtr,
+    AtomicUsize,
+    Ordering,
+  },
+};
+
+pub struct Block {
+  next: *mut Block,
+}
+
+/// Intrusive stack of blocks freed by other threads. Any thread may push;
+/// only the owning heap takes, and it detaches the whole chain in a single
+/// swap, so pops never race with each other and the stack is immune to ABA.
+pub struct RemoteStack {
+  head: AtomicPtr<Block>,
+  count: AtomicUsize,
+}
+
+impl RemoteStack {
+  pub const fn new() -> Self {
+    Self {
+      head: AtomicPtr::new(core::ptr::null_mut()),
+      count: AtomicUsize::new(0),
+    }
+  }
+
+  /// Number of blocks pushed since the last take. Only a hint: a push that
+  /// races with [`RemoteStack::take`] may be counted on either side.
+  pub fn count(&self) -> usize {
+    self.count.load(Ordering::Relaxed)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.head.load(Ordering::Relaxed).is_null()
+  }
+
+  /// Threads the freed block onto the stack by writing the link into the
+  /// block itself, so `ptr` must be at least word sized and word aligned.
+  ///
+  /// The stack is not touched once the block is published: the owner may
+  /// take it and release the memory holding the stack right away.
+  pub fn push(&self, ptr: NonNull<u8>) {
+    self.count.fetch_add(1, Ordering::Relaxed);
+    let block = ptr.cast::<Block>().as_ptr();
+    let mut head = self.head.load(Ordering::Relaxed);
+    loop {
+      unsafe { block.write(Block { next: head }) };
+      match self.head.compare_exchange_weak(
+        head,
+        block,
+        Ordering::Release,
+        Ordering::Relaxed,
+      ) {
+        Ok(_) => break,
+        Err(current) => head = current,
+      }
+    }
+  }
+
+  /// Detaches every block pushed so far. Must only be called by the owner.
+  pub fn take(&self) -> RemoteBatch {
+    self.count.store(0, Ordering::Relaxed);
+    RemoteBatch {
+      next: self.head.swap(core::ptr::null_mut(), Ordering::Acquire),
+    }
+  }
+}
+
+impl Default for RemoteStack {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Blocks detached by [`RemoteStack::take`], most recently pushed first.
+pub struct RemoteBatch {
+  next: *mut Block,
+}
+
+impl Iterator for RemoteBatch {
+  type Item = NonNull<u8>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let block = NonNull::new(self.next)?;
+    self.next = unsafe { block.as_ref() }.next;
+    Some(block.cast())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    collections::HashSet,
+    sync::{
+      Arc,
+      atomic::AtomicBool,
+    },
+    thread,
+  };
+
+  use super::*;
+
+  #[test]
+  fn remote_stack_is_lifo() {
+    let mut slots = [0usize; 4];
+    let stack = RemoteStack::new();
+    assert!(stack.is_empty());
+
+    for slot in slots.iter_mut() {
+      stack.push(NonNull::from(slot).cast());
+    }
+    assert_eq!(stack.count(), 4);
+
+    let expected = slots.as_mut_ptr();
+    let taken: Vec<_> = stack.take().collect();
+    assert!(stack.is_empty());
+    assert_eq!(stack.count(), 0);
+    for (popped, index) in taken.into_iter().zip((0..4).rev()) {
+      assert_eq!(popped.as_ptr(), unsafe { expected.add(index) } as *mut u8);
+    }
+  }
+
+  #[test]
+  fn remote_stack_survives_many_producers() {
+    const PRODUCERS: usize = 8;
+    const BLOCKS: usize = 20_000;
+
+    let stack = Arc::new(RemoteStack::new());
+    let done = Arc::new(AtomicBool::new(false));
+
+    let producers: Vec<_> = (0..PRODUCERS)
+      .map(|_| {
+        let stack = Arc::clone(&stack);
+        thread::spawn(move || {
+          let blocks: Vec<usize> = (0..BLOCKS)
+            .map(|_| Box::into_raw(Box::new(0usize)) as usize)
+            .collect();
+          for &block in &blocks {
+            stack.push(NonNull::new(block as *mut u8).unwrap());
+          }
+          blocks
+        })
+      })
+      .collect();
+
+    let consumer = {
+      let stack = Arc::clone(&stack);
+      let done = Arc::clone(&done);
+      thread::spawn(move || {
+        let mut seen = HashSet::new();
+        loop {
+          let finished = done.load(Ordering::Acquire);
+          for block in stack.take() {
+            assert!(seen.insert(block.as_ptr() as usize), "block seen twice");
+          }
+          if finished {
+            return seen;
+          }
+          thread::yield_now();
+        }
+      })
+    };
+
+    let pushed: Vec<usize> = producers
+      .into_iter()
+      .flat_map(|producer| producer.join().unwrap())
+      .collect();
+    done.store(true, Ordering::Release);
+    let seen = consumer.join().unwrap();
+
+    assert_eq!(seen.len(), PRODUCERS * BLOCKS);
+    for block in pushed {
+      assert!(seen.contains(&block), "block lost");
+      drop(unsafe { Box::from_raw(block as *mut usize) });
+    }
+  }
+}
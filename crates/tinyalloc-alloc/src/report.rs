@@ -0,0 +1,84 @@
+use std::{
+  fmt::{
+    self,
+    Arguments,
+    Display,
+  },
+  io::Write,
+};
+
+use tinyalloc_config::classes::Class;
+
+use crate::options::{
+  FreePolicy,
+  free_policy,
+};
+
+/// Writes one diagnostic line to stderr. Stderr is unbuffered, so this
+/// never allocates and is safe to call from inside the allocator.
+pub fn report(args: Arguments<'_>) {
+  let _ = writeln!(std::io::stderr().lock(), "tinyalloc: {args}");
+}
+
+/// Why a free was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FreeProblem {
+  /// The block was already free.
+  DoubleFree,
+  /// The pointer does not belong to any block tinyalloc handed out.
+  Foreign,
+  /// The pointer falls inside a block rather than at its start.
+  Interior,
+  /// The layout passed to the free does not fit the block.
+  LayoutMismatch,
+  /// The bookkeeping around the block was overwritten.
+  Corrupted,
+}
+
+impl Display for FreeProblem {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      FreeProblem::DoubleFree => "double free",
+      FreeProblem::Foreign => "free of a foreign pointer",
+      FreeProblem::Interior => "free of an interior pointer",
+      FreeProblem::LayoutMismatch => "free with a mismatched layout",
+      FreeProblem::Corrupted => "free of a corrupted block",
+    })
+  }
+}
+
+/// Handles an invalid free of `ptr` according to the [`FreePolicy`].
+/// `class` is the size class the block was expected to belong to, if any.
+pub fn invalid_free(
+  problem: FreeProblem,
+  ptr: *const u8,
+  class: Option<&Class>,
+) {
+  match class {
+    Some(class) => enforce(format_args!(
+      "{problem}: {ptr:p}, expected class {} ({} B)",
+      class.id, class.size.0
+    )),
+    None => enforce(format_args!("{problem}: {ptr:p}, expected no class")),
+  }
+}
+
+/// Handles a write found `offset` bytes into the block at `ptr`, past its
+/// `size` bytes, according to the [`FreePolicy`].
+pub fn overflow(ptr: *const u8, size: usize, offset: usize) {
+  enforce(format_args!(
+    "buffer overflow: {ptr:p} ({size} B) was written at offset {offset}"
+  ));
+}
+
+fn enforce(args: Arguments<'_>) {
+  let policy = free_policy();
+  if policy == FreePolicy::Ignore {
+    return;
+  }
+
+  report(args);
+  if policy == FreePolicy::Abort {
+    std::process::abort();
+  }
+}
@@ -1,6 +1,14 @@
-use std::ptr::NonNull;
+use std::{
+  ptr::NonNull,
+  sync::atomic::{
+    AtomicPtr,
+    AtomicUsize,
+    Ordering,
+  },
+};
 
 use getset::{
+  CopyGetters,
   Getters,
   Setters,
 };
@@ -11,11 +19,19 @@ use tinyalloc_bitmap::{
 };
 use tinyalloc_config::{
   classes::{
+    CLASSES,
     Class,
     Segmentation,
   },
+  config::{
+    MIN_SEGMENT_OBJECTS,
+    MIN_SIZE,
+    SEGMENT_SIZE,
+    SIZES,
+  },
   helper::align_slice,
   metric,
+  simulate::objects_per_segment,
 };
 
 #[cfg(feature = "metrics")]
@@ -25,22 +41,67 @@ use tinyalloc_list::{
   Link,
 };
 
-use crate::queue::Position;
+use crate::{
+  arena::Arena,
+  heap::Heap,
+  queue::Position,
+  remote::RemoteStack,
+  report::{
+    FreeProblem,
+    invalid_free,
+  },
+};
 
 pub const SEGMENT_CACHE_SIZE: usize = 12;
 pub const SEGMENT_CACHE_PREFETCH: usize = 8;
 
-#[derive(Getters, Setters)]
+#[derive(Getters, Setters, CopyGetters)]
 pub struct Segment {
+  #[getset(get_copy = "pub")]
   class: &'static Class,
+  /// The arena this segment was carved from, null for segments built over
+  /// a caller-provided buffer.
+  #[getset(get_copy = "pub", set = "pub")]
+  arena: *const Arena,
+  /// The heap allowed to allocate from and free into this segment, or null
+  /// while the segment sits abandoned.
+  owner: AtomicPtr<Heap>,
+  /// Slots freed by threads other than the owner, merged back into the
+  /// bitmap by [`Segment::collect`].
+  #[getset(get = "pub")]
+  thread_free: RemoteStack,
   link: Link<Segment>,
   #[getset(set = "pub", get = "pub")]
   current: Position,
   bitmap: Bitmap<'static, usize>,
+  /// Slots held in some heap's quarantine. Any thread may free into the
+  /// segment that way, so the bits are set and cleared atomically.
+  quarantined: &'static [AtomicUsize],
   cache: Array<usize, SEGMENT_CACHE_SIZE>,
+  /// Slots at or past this index have never been handed out and still
+  /// hold the zeroes of the freshly activated segment. Slot counts per
+  /// segment fit comfortably in 32 bits, which keeps the header compact.
+  fresh: u32,
   user: &'static mut [u8],
 }
 
+// The geometry is picked at build time, so check here that every class
+// still fits `MIN_SEGMENT_OBJECTS` slots behind the header and bitmap.
+const _: () = {
+  assert!(SEGMENT_SIZE / MIN_SIZE <= u32::MAX as usize);
+  let mut index = 0;
+  while index < SIZES {
+    let class = &CLASSES[index];
+    let objects =
+      objects_per_segment(class, SEGMENT_SIZE, core::mem::size_of::<Segment>());
+    assert!(
+      objects >= MIN_SEGMENT_OBJECTS,
+      "a size class fits fewer than MIN_SEGMENT_OBJECTS slots in a segment"
+    );
+    index += 1;
+  }
+};
+
 #[derive(Debug)]
 pub enum SegmentError {
   InsufficientCapacity { class_id: usize },
@@ -55,7 +116,8 @@ impl From<BitmapError> for SegmentError {
 
 impl Segment {
   fn prefetch_cache(&mut self, start_bit: usize) {
-    let prefetch_count = SEGMENT_CACHE_PREFETCH.min(SEGMENT_CACHE_SIZE - self.cache.len());
+    let prefetch_count =
+      SEGMENT_CACHE_PREFETCH.min(SEGMENT_CACHE_SIZE - self.cache.len());
     let mut current_bit = start_bit + 1;
     let mut prefetched = 0;
 
@@ -83,7 +145,18 @@ impl Segment {
       bitmap: bitmap_slice,
       rest: bitmap_rest,
     } = class.segment::<usize>(rest);
-    let user_aligned = align_slice(bitmap_rest, class.align.0);
+    let Segmentation {
+      bitmap: quarantined_slice,
+      rest: quarantined_rest,
+    } = class.segment::<usize>(bitmap_rest);
+    quarantined_slice.fill(0);
+    let quarantined = unsafe {
+      core::slice::from_raw_parts(
+        quarantined_slice.as_ptr() as *const AtomicUsize,
+        quarantined_slice.len(),
+      )
+    };
+    let user_aligned = align_slice(quarantined_rest, class.align.0);
     let object_capacity = user_aligned.len() / class.size.0;
     if object_capacity == 0 {
       metric!(MetricId::SegmentNewFail);
@@ -97,10 +170,15 @@ impl Segment {
         segment_ptr,
         Self {
           class,
+          arena: core::ptr::null(),
+          owner: AtomicPtr::new(core::ptr::null_mut()),
+          thread_free: RemoteStack::new(),
           link: Link::new(),
           bitmap,
+          quarantined,
           current: Position::default(),
           cache: Array::new(),
+          fresh: 0,
           user: user_aligned,
         },
       );
@@ -109,6 +187,14 @@ impl Segment {
     }
   }
 
+  pub fn owner(&self) -> *mut Heap {
+    self.owner.load(Ordering::Acquire)
+  }
+
+  pub fn set_owner(&self, owner: *mut Heap) {
+    self.owner.store(owner, Ordering::Release);
+  }
+
   pub fn is_full(&self) -> bool {
     !self.bitmap.one_clear()
   }
@@ -129,10 +215,7 @@ impl Segment {
       return None;
     }
 
-    let end = match offset.checked_add(self.class.size.0) {
-      Some(end) => end,
-      None => return None,
-    };
+    let end = offset.checked_add(self.class.size.0)?;
 
     if end > self.user.len() {
       return None;
@@ -141,8 +224,8 @@ impl Segment {
     NonNull::new(ptr as *mut u8)
   }
 
-  fn index_from_ptr(&mut self, ptr: NonNull<u8>) -> Option<usize> {
-    let user_start = self.user.as_mut_ptr() as *mut u8;
+  fn index_from_ptr(&self, ptr: NonNull<u8>) -> Option<usize> {
+    let user_start = self.user.as_ptr() as *mut u8;
     let user_end = unsafe { user_start.add(self.user.len()) };
     let ptr_addr = ptr.as_ptr();
 
@@ -159,7 +242,48 @@ impl Segment {
     Some(offset / object_size)
   }
 
+  /// Marks `ptr`'s slot as held in a quarantine. Whichever heaps two frees
+  /// of one slot go through, the second finds the mark and is refused with
+  /// the problem to report.
+  pub fn quarantine(&self, ptr: NonNull<u8>) -> Result<(), FreeProblem> {
+    let index = self.index_from_ptr(ptr).ok_or(FreeProblem::Interior)?;
+    let (word, bit) = Self::quarantine_bit(index);
+    if self.quarantined[word].fetch_or(bit, Ordering::AcqRel) & bit != 0 {
+      return Err(FreeProblem::DoubleFree);
+    }
+    Ok(())
+  }
+
+  /// Clears the mark [`Segment::quarantine`] set, as the slot leaves
+  /// quarantine to be freed for real.
+  pub fn unquarantine(&self, ptr: NonNull<u8>) {
+    if let Some(index) = self.index_from_ptr(ptr) {
+      let (word, bit) = Self::quarantine_bit(index);
+      self.quarantined[word].fetch_and(!bit, Ordering::AcqRel);
+    }
+  }
+
+  fn quarantine_bit(index: usize) -> (usize, usize) {
+    let bits = usize::BITS as usize;
+    (index / bits, 1 << (index % bits))
+  }
+
   pub fn alloc(&mut self) -> Option<NonNull<u8>> {
+    self.take().map(|(ptr, _)| ptr)
+  }
+
+  /// Like [`Segment::alloc`], but guarantees the first `size` bytes of the
+  /// slot are zero. Slots that were never handed out are already zero and
+  /// skip the clear.
+  pub fn alloc_zeroed(&mut self, size: usize) -> Option<NonNull<u8>> {
+    let (ptr, fresh) = self.take()?;
+    if !fresh {
+      unsafe { ptr.as_ptr().write_bytes(0, size.min(self.class.size.0)) };
+    }
+    Some(ptr)
+  }
+
+  fn take(&mut self) -> Option<(NonNull<u8>, bool)> {
     let bit_index = if let Some(cached_index) = self.cache.pop() {
       metric!(MetricId::SegmentCacheHit);
       cached_index
@@ -175,14 +299,54 @@ impl Segment {
 
     metric!(MetricId::SegmentBitmapSet);
     self.bitmap.set(bit_index).ok()?;
-    self.ptr_from_index(bit_index)
+    let ptr = self.ptr_from_index(bit_index)?;
+
+    let fresh = bit_index >= self.fresh as usize;
+    if fresh {
+      self.fresh = bit_index as u32 + 1;
+    }
+    Some((ptr, fresh))
   }
 
+  /// Calls `f` with every slot currently handed out. Slots waiting on the
+  /// thread-free stack still count as in use.
+  pub fn for_each_used(&self, mut f: impl FnMut(NonNull<u8>)) {
+    let user_start = self.user.as_ptr() as *mut u8;
+    for (word_index, &word) in self.bitmap.store().iter().enumerate() {
+      let mut bits = word;
+      while bits != 0 {
+        let index =
+          word_index * usize::BITS as usize + bits.trailing_zeros() as usize;
+        bits &= bits - 1;
+        let slot = unsafe { user_start.add(index * self.class.size.0) };
+        f(unsafe { NonNull::new_unchecked(slot) });
+      }
+    }
+  }
+
+  /// Merges slots freed by other threads back into the bitmap, returning
+  /// how many were released.
+  pub fn collect(&mut self) -> usize {
+    let mut collected = 0;
+    for ptr in self.thread_free.take() {
+      if self.dealloc(ptr) {
+        collected += 1;
+      }
+    }
+    collected
+  }
+
+  /// Returns `ptr`'s slot to the segment. Pointers inside a slot and slots
+  /// that are already free are reported as invalid frees and left alone.
   pub fn dealloc(&mut self, ptr: NonNull<u8>) -> bool {
-    let bit_index = match self.index_from_ptr(ptr) {
-      Some(index) => index,
-      None => return false,
+    let Some(bit_index) = self.index_from_ptr(ptr) else {
+      invalid_free(FreeProblem::Interior, ptr.as_ptr(), Some(self.class));
+      return false;
     };
+    if !matches!(self.bitmap.get(bit_index), Ok(true)) {
+      invalid_free(FreeProblem::DoubleFree, ptr.as_ptr(), Some(self.class));
+      return false;
+    }
 
     let _ = self.cache.push(bit_index);
     metric!(MetricId::SegmentBitmapClear);
@@ -210,14 +374,6 @@ impl Drop for Segment {
 
 #[cfg(test)]
 mod tests {
-  use tinyalloc_config::{
-    classes::CLASSES,
-    config::{
-      SEGMENT_SIZE,
-      SIZES,
-    },
-  };
-
   use super::*;
 
   #[test]
@@ -225,7 +381,7 @@ mod tests {
     let mut buffer = vec![0u8; SEGMENT_SIZE];
     let smallest_class = &CLASSES[0];
     let segment_ptr = Segment::new(smallest_class, unsafe {
-      core::mem::transmute(&mut buffer[..])
+      core::mem::transmute::<&mut [u8], &mut [u8]>(&mut buffer[..])
     })
     .expect("segment must initialize for smallest class");
     let segment = unsafe { segment_ptr.as_ref() };
@@ -242,7 +398,12 @@ mod tests {
 
     assert_eq!(object_size, 8, "First class should be 8 bytes");
     assert_eq!(remainder, 0, "Should have perfect fit for 8-byte objects");
-    assert!(max_objects > 16000, "Should fit many small objects");
+    // The in-use and quarantine bitmaps each take a bit per slot, 1/64 of
+    // the space of 8-byte slots.
+    assert!(
+      max_objects >= SEGMENT_SIZE / object_size * 15 / 16,
+      "Should fit many small objects"
+    );
   }
 
   #[test]
@@ -260,9 +421,10 @@ mod tests {
 
     for (i, class) in CLASSES.iter().enumerate() {
       let mut buffer = vec![0u8; SEGMENT_SIZE];
-      let segment_ptr =
-        Segment::new(class, unsafe { core::mem::transmute(&mut buffer[..]) })
-          .expect("segment must initialize for class");
+      let segment_ptr = Segment::new(class, unsafe {
+        core::mem::transmute::<&mut [u8], &mut [u8]>(&mut buffer[..])
+      })
+      .expect("segment must initialize for class");
       let segment = unsafe { segment_ptr.as_ref() };
 
       let user_space = segment.user.len();
@@ -318,9 +480,10 @@ mod tests {
   fn segment_alloc_dealloc_basic() {
     let mut buffer = vec![0u8; SEGMENT_SIZE];
     let class = &CLASSES[0];
-    let mut segment_ptr =
-      Segment::new(class, unsafe { core::mem::transmute(&mut buffer[..]) })
-        .expect("segment must initialize");
+    let mut segment_ptr = Segment::new(class, unsafe {
+      core::mem::transmute::<&mut [u8], &mut [u8]>(&mut buffer[..])
+    })
+    .expect("segment must initialize");
     let segment = unsafe { segment_ptr.as_mut() };
 
     let ptr1 = segment.alloc().expect("Should allocate first object");
@@ -358,14 +521,14 @@ mod tests {
   fn segment_bitmap_sizing_correctness() {
     for class in CLASSES.iter() {
       let mut buffer = vec![0u8; SEGMENT_SIZE];
-      let segment_ptr =
-        Segment::new(class, unsafe { core::mem::transmute(&mut buffer[..]) })
-          .expect("segment must initialize for bitmap sizing");
+      let segment_ptr = Segment::new(class, unsafe {
+        core::mem::transmute::<&mut [u8], &mut [u8]>(&mut buffer[..])
+      })
+      .expect("segment must initialize for bitmap sizing");
       let segment = unsafe { segment_ptr.as_ref() };
 
       let max_objects = segment.user.len() / class.size.0;
-      let bitmap_words_needed =
-        (max_objects + usize::BITS as usize - 1) / usize::BITS as usize;
+      let bitmap_words_needed = max_objects.div_ceil(usize::BITS as usize);
       let actual_bitmap_words = segment.bitmap.store().len();
 
       assert!(
@@ -376,8 +539,11 @@ mod tests {
         class.size.0
       );
 
+      // The bitmap is sized before its own words and the quarantine
+      // bitmap's come out of the segment.
+      let slack = SEGMENT_SIZE / (16 * 1024);
       assert!(
-        actual_bitmap_words <= bitmap_words_needed + 16,
+        actual_bitmap_words <= bitmap_words_needed + slack,
         "Bitmap oversized: need {} words, have {} for class size {}",
         bitmap_words_needed,
         actual_bitmap_words,
@@ -385,4 +551,99 @@ mod tests {
       );
     }
   }
+
+  #[test]
+  fn simulated_capacity_matches_segment_layout() {
+    // Aligned the way arenas place segments.
+    let layout =
+      std::alloc::Layout::from_size_align(SEGMENT_SIZE, SEGMENT_SIZE).unwrap();
+    for class in CLASSES.iter() {
+      let buffer = unsafe { std::alloc::alloc(layout) };
+      let slice =
+        unsafe { core::slice::from_raw_parts_mut(buffer, SEGMENT_SIZE) };
+      let segment_ptr = Segment::new(class, slice).unwrap();
+      let segment = unsafe { segment_ptr.as_ref() };
+
+      assert_eq!(
+        objects_per_segment(
+          class,
+          SEGMENT_SIZE,
+          core::mem::size_of::<Segment>()
+        ),
+        segment.user.len() / class.size.0,
+        "class {}",
+        class.id
+      );
+      unsafe { std::alloc::dealloc(buffer, layout) };
+    }
+  }
+
+  #[test]
+  fn segment_alloc_zeroed_clears_only_recycled_slots() {
+    let mut buffer = vec![0u8; SEGMENT_SIZE];
+    let class = &CLASSES[3];
+    let mut segment_ptr = Segment::new(class, unsafe {
+      core::mem::transmute::<&mut [u8], &mut [u8]>(&mut buffer[..])
+    })
+    .expect("segment must initialize");
+    let segment = unsafe { segment_ptr.as_mut() };
+
+    let ptr = segment.alloc().expect("first slot");
+    unsafe { ptr.as_ptr().write_bytes(0xAA, class.size.0) };
+    assert!(segment.dealloc(ptr));
+
+    let zeroed = segment.alloc_zeroed(class.size.0).expect("recycled slot");
+    assert_eq!(zeroed, ptr, "cache should hand the dirty slot back");
+    let bytes =
+      unsafe { core::slice::from_raw_parts(zeroed.as_ptr(), class.size.0) };
+    assert!(
+      bytes.iter().all(|&b| b == 0),
+      "recycled slot must be cleared"
+    );
+
+    let (_, fresh) = segment.take().expect("next slot");
+    assert!(fresh, "slots past the high-water mark are fresh");
+  }
+
+  #[test]
+  fn segment_collect_merges_thread_frees() {
+    let mut buffer = vec![0u8; SEGMENT_SIZE];
+    let mut segment_ptr = Segment::new(&CLASSES[2], unsafe {
+      core::mem::transmute::<&mut [u8], &mut [u8]>(&mut buffer[..])
+    })
+    .expect("segment must initialize");
+    let segment = unsafe { segment_ptr.as_mut() };
+
+    let blocks: Vec<_> = (0..16).map(|_| segment.alloc().unwrap()).collect();
+    for &block in &blocks {
+      segment.thread_free().push(block);
+    }
+    assert!(!segment.is_empty(), "pending frees still hold their slots");
+
+    assert_eq!(segment.collect(), blocks.len());
+    assert!(segment.is_empty());
+    assert_eq!(segment.collect(), 0);
+  }
+
+  #[test]
+  fn segment_dealloc_rejects_double_and_interior_frees() {
+    let mut buffer = vec![0u8; SEGMENT_SIZE];
+    let mut segment_ptr = Segment::new(&CLASSES[4], unsafe {
+      core::mem::transmute::<&mut [u8], &mut [u8]>(&mut buffer[..])
+    })
+    .expect("segment must initialize");
+    let segment = unsafe { segment_ptr.as_mut() };
+
+    let kept = segment.alloc().unwrap();
+    let freed = segment.alloc().unwrap();
+    assert!(segment.dealloc(freed));
+    assert!(!segment.dealloc(freed), "second free must be rejected");
+
+    let interior = unsafe { kept.add(1) };
+    assert!(!segment.dealloc(interior));
+    assert!(!segment.is_empty(), "rejected frees keep the slot in use");
+
+    assert_eq!(segment.alloc(), Some(freed));
+    assert_ne!(segment.alloc(), Some(freed), "slot handed out only once");
+  }
 }
@@ -1,16 +1,30 @@
-use std::sync::atomic::{
-  AtomicPtr,
-  AtomicUsize,
-  Ordering,
+use std::{
+  num::NonZeroUsize,
+  time::Duration,
 };
 
-use spin::RwLock;
-use tinyalloc_array::Array;
+use spin::{
+  Mutex,
+  RwLock,
+};
 use tinyalloc_config::{
   classes::Class,
-  config::{ARENA_GROWTH, ARENA_INITIAL_SIZE, ARENA_LIMIT, ARENA_STEP, SEGMENT_SIZE},
+  config::{
+    ARENA_MAX_SIZE,
+    ARENA_STEP,
+    SIZES,
+  },
   metric,
 };
+use tinyalloc_list::{
+  HasLink,
+  List,
+};
+use tinyalloc_sys::{
+  GLOBAL_MAPPER,
+  mapper::Protection,
+  thread,
+};
 
 #[cfg(feature = "metrics")]
 use tinyalloc_config::metrics::MetricId;
@@ -26,131 +40,352 @@ use crate::{
 use std::ptr::NonNull;
 
 use crate::{ 
+  heap::Heap,
+  options::{
+    arena_growth,
+    arena_initial_size,
+    arena_release_delay,
+  },
+  pagemap,
   segment::Segment,
+  stats::Stats,
 };
 
-static ARENAS: RwLock<Array<AtomicPtr<Arena>, ARENA_LIMIT>> =
-  RwLock::new(Array::new());
-static NEXT_ARENA_SIZE: AtomicUsize = AtomicUsize::new(ARENA_INITIAL_SIZE);
+/// A list shared between threads. Every access goes through the lock.
+struct Shared<T: HasLink<T>>(Mutex<List<T>>);
 
-fn create_arena() -> Result<NonNull<Arena>, ArenaError> {
-  metric!(MetricId::StaticCreateArena);
-  let size = NEXT_ARENA_SIZE.load(Ordering::Relaxed);
-  match Arena::new(size) {
-    Ok(arena) => {
-      metric!(MetricId::StaticCreateArenaSuccess);
-      Ok(arena)
-    }
-    Err(e) => {
-      metric!(MetricId::StaticCreateArenaFail);
-      Err(e)
-    }
+unsafe impl<T: HasLink<T>> Sync for Shared<T> {}
+
+impl<T: HasLink<T>> Shared<T> {
+  const fn new() -> Self {
+    Self(Mutex::new(List::new()))
   }
 }
 
-fn add_arena(arena: NonNull<Arena>) -> Result<(), ArenaError> {
-  metric!(MetricId::StaticAddArena);
-  let mut arenas = ARENAS.write();
-  let arena_count = arenas.len();
+/// Registered arenas, oldest first so new segments pack into long-lived
+/// arenas and younger ones get a chance to drain. Readers allocate under
+/// the read lock; unlinking an arena takes the write lock.
+struct Arenas(RwLock<List<Arena>>);
 
-  if arena_count > 0 && arena_count % ARENA_STEP == 0 {
-    metric!(MetricId::StaticArenaGrowth);
-    let current_size = NEXT_ARENA_SIZE.load(Ordering::Relaxed);
-    let next_size = current_size
-      .checked_mul(ARENA_GROWTH)
-      .unwrap_or(current_size);
-    NEXT_ARENA_SIZE.store(next_size, Ordering::Relaxed);
-  }
-
-  let atomic_ptr = AtomicPtr::new(arena.as_ptr());
-  match arenas.push(atomic_ptr) {
-    Ok(()) => {
-      metric!(MetricId::StaticAddArenaSuccess);
-      Ok(())
-    }
-    Err(_) => {
-      metric!(MetricId::StaticAddArenaFail);
-      Err(ArenaError::Insufficient)
+unsafe impl Sync for Arenas {}
+
+static ARENAS: Arenas = Arenas(RwLock::new(List::new()));
+
+/// Every heap ever created. Heaps are never unmapped, so pointers held by
+/// segments, allocation headers and remote frees stay valid after the
+/// thread that used a heap exits.
+static HEAPS: Shared<Heap> = Shared::new();
+
+/// Segments with live objects whose heap was abandoned, one list per
+/// class. A null segment owner marks membership and is only changed under
+/// the class lock.
+static ABANDONED: [Shared<Segment>; SIZES] = [const { Shared::new() }; SIZES];
+
+/// Size of the next arena given how many are registered: it grows by
+/// [`arena_growth`] every `ARENA_STEP` arenas, up to `ARENA_MAX_SIZE`, and
+/// shrinks back as arenas are released.
+fn arena_size(arena_count: usize) -> usize {
+  let growth = arena_growth();
+  let mut size = arena_initial_size();
+  for _ in 0..arena_count / ARENA_STEP {
+    if size >= ARENA_MAX_SIZE || growth == 1 {
+      break;
     }
+    size = size.saturating_mul(growth);
   }
+  size.min(ARENA_MAX_SIZE)
+}
+
+fn create_arena(size: usize) -> Result<NonNull<Arena>, ArenaError> {
+  metric!(MetricId::StaticCreateArena);
+  Arena::new(size)
+    .inspect(|_| {
+      metric!(MetricId::StaticCreateArenaSuccess);
+    })
+    .inspect_err(|_| {
+      metric!(MetricId::StaticCreateArenaFail);
+    })
 }
 
 pub fn allocate_segment(
   class: &'static Class,
 ) -> Result<NonNull<Segment>, ArenaError> {
-  let arenas = ARENAS.read();
-
-  for i in 0..arenas.len() {
-    let arena_ptr = unsafe { arenas.get_unchecked(i) }.load(Ordering::Acquire);
-    if !arena_ptr.is_null() {
-      let arena = unsafe { &mut *arena_ptr };
-      if arena.has_space() {
-        if let Ok(segment) = arena.allocate(class) {
-          return Ok(segment);
-        }
-      }
+  let arenas = ARENAS.0.read();
+  for arena in arenas.iter() {
+    if arena.has_space()
+      && let Ok(segment) = arena.allocate(class)
+    {
+      return Ok(segment);
     }
   }
-
   drop(arenas);
 
-  let new_arena = create_arena()?;
-  add_arena(new_arena)?;
+  let mut arenas = ARENAS.0.write();
+  // Another thread may have added an arena or released a segment while
+  // the lock was dropped.
+  for arena in arenas.iter() {
+    if arena.has_space()
+      && let Ok(segment) = arena.allocate(class)
+    {
+      return Ok(segment);
+    }
+  }
 
-  let arena = unsafe { &mut *new_arena.as_ptr() };
-  arena.allocate(class)
+  metric!(MetricId::StaticAddArena);
+  let arena_count = arenas.count();
+  if arena_count > 0 && arena_count.is_multiple_of(ARENA_STEP) {
+    metric!(MetricId::StaticArenaGrowth);
+  }
+  let arena = create_arena(arena_size(arena_count))?;
+  arenas.push(arena);
+  metric!(MetricId::StaticAddArenaSuccess);
+
+  unsafe { arena.as_ref() }.allocate(class)
 }
 
 pub fn deallocate_segment(segment: NonNull<Segment>) -> Result<(), ArenaError> {
-  let arenas = ARENAS.read();
-  let segment_ptr = segment.as_ptr() as *const u8;
-
-  for i in 0..arenas.len() {
-    let arena_ptr = unsafe { arenas.get_unchecked(i) }.load(Ordering::Acquire);
-    if !arena_ptr.is_null() {
-      let arena = unsafe { &mut *arena_ptr };
-      let arena_start = arena.user_start();
-      let arena_end = unsafe { arena_start.add(arena.user_len()) };
-
-      if segment_ptr >= arena_start && segment_ptr < arena_end {
-        return arena.deallocate(segment);
-      }
-    }
-  }
+  let arena = unsafe { segment.as_ref() }.arena();
+  let result = match unsafe { arena.as_ref() } {
+    Some(arena) => arena.deallocate(segment),
+    None => Err(ArenaError::Insufficient),
+  };
 
-  Err(ArenaError::Insufficient)
+  release_idle_arenas(arena_release_delay(), false);
+  result
 }
 
-pub fn segment_from_ptr(ptr: NonNull<u8>) -> Option<NonNull<Segment>> {
-  metric!(MetricId::StaticSegmentLookup);
-  let arenas = ARENAS.read();
-  let addr = ptr.as_ptr() as usize;
-
-  for i in 0..arenas.len() {
-    let arena_ptr = unsafe { arenas.get_unchecked(i) }.load(Ordering::Acquire);
-    if arena_ptr.is_null() {
-      continue;
+/// Unmaps every arena that has had no active segment for at least `grace`,
+/// returning the bytes released. Without `wait` the sweep is skipped when
+/// the registry is busy.
+pub fn release_idle_arenas(grace: Duration, wait: bool) -> usize {
+  let mut arenas = if wait {
+    ARENAS.0.write()
+  } else {
+    match ARENAS.0.try_write() {
+      Some(arenas) => arenas,
+      None => return 0,
     }
+  };
 
-    let arena = unsafe { &*arena_ptr };
-    let start = arena.user_start() as usize;
-    let end = start.checked_add(arena.user_len())?;
-    if addr < start || addr >= end {
-      continue;
+  let mut released = 0;
+  let mut cursor = *arenas.head();
+  while let Some(arena) = cursor {
+    let arena_ref = unsafe { arena.as_ref() };
+    cursor = *arena_ref.link().next();
+    if arena_ref
+      .idle_for()
+      .is_some_and(|idle_for| idle_for >= grace)
+    {
+      arenas.remove_unchecked(arena);
+      released += arena_ref.size();
+      // The arena lives inside its own mapping: move it out so dropping it
+      // unmaps the region without touching freed memory.
+      drop(unsafe { arena.as_ptr().read() });
     }
+  }
+  released
+}
 
-    let offset = addr - start;
-    let segment_index = offset / SEGMENT_SIZE;
-    let segment_base = start + (segment_index * SEGMENT_SIZE);
-    let segment_ptr = segment_base as *mut Segment;
-    if let Some(segment_nn) = NonNull::new(segment_ptr) {
-      if unsafe { segment_nn.as_ref() }.contains_ptr(ptr) {
-        metric!(MetricId::StaticSegmentLookupSuccess);
-        return Some(segment_nn);
-      }
+pub fn segment_from_ptr(ptr: NonNull<u8>) -> Option<NonNull<Segment>> {
+  metric!(MetricId::StaticSegmentLookup);
+  if let Some(base) = pagemap::lookup(ptr.as_ptr() as usize) {
+    let segment = base.cast::<Segment>();
+    if unsafe { segment.as_ref() }.contains_ptr(ptr) {
+      metric!(MetricId::StaticSegmentLookupSuccess);
+      return Some(segment);
     }
   }
 
   metric!(MetricId::StaticSegmentLookupFail);
   None
 }
+
+/// Claims an idle heap from the registry, or maps and registers a new one,
+/// on behalf of the calling thread.
+pub fn acquire_heap() -> Option<NonNull<Heap>> {
+  let mut heaps = HEAPS.0.lock();
+  if let Some(heap) = heaps.iter().find(|heap| heap.try_claim()) {
+    heap.set_thread(Some(thread::current_id()));
+    return Some(NonNull::from(heap));
+  }
+
+  let size = NonZeroUsize::new(core::mem::size_of::<Heap>())?;
+  let mapped = GLOBAL_MAPPER.map(size).ok()?;
+  if GLOBAL_MAPPER
+    .protect(mapped, Protection::Read | Protection::Write)
+    .is_err()
+  {
+    GLOBAL_MAPPER.unmap(mapped);
+    return None;
+  }
+
+  let heap = mapped.cast::<Heap>();
+  unsafe { heap.as_ptr().write(Heap::new()) };
+  unsafe { heap.as_ref() }.try_claim();
+  unsafe { heap.as_ref() }.set_thread(Some(thread::current_id()));
+  heaps.push(heap);
+  Some(heap)
+}
+
+/// Abandons `heap` and returns it to the registry for reuse.
+pub fn release_heap(heap: NonNull<Heap>) {
+  let guard = unsafe { heap.as_ref() }.lock();
+  let heap_mut = unsafe { &mut *heap.as_ptr() };
+  heap_mut.abandon();
+  heap_mut.set_thread(None);
+  drop(guard);
+  heap_mut.unclaim();
+  drain_unclaimed(heap);
+}
+
+/// Frees the large blocks other threads pushed to the remote stack of
+/// `heap` while it was claimed, as an unclaimed heap never drains it
+/// itself. Called by whoever unclaims a heap. Stops once the stack is
+/// empty or another thread claims the heap, which then drains it.
+pub fn drain_unclaimed(heap: NonNull<Heap>) {
+  let heap_ref = unsafe { heap.as_ref() };
+  while !heap_ref.remote().is_empty() && heap_ref.try_claim() {
+    let guard = heap_ref.lock();
+    unsafe { &mut *heap.as_ptr() }.drain_remote();
+    drop(guard);
+    heap_ref.unclaim();
+  }
+}
+
+/// Calls `visit` with each registered heap in turn, holding that heap's
+/// lock meanwhile. `visit` must not allocate or free through the heaps.
+pub fn visit_heaps(mut visit: impl FnMut(&Heap)) {
+  for heap in HEAPS.0.lock().iter() {
+    let _guard = heap.lock();
+    visit(heap);
+  }
+}
+
+/// Collects every registered heap under its lock, applying the frees
+/// other threads left pending. Returns the bytes handed back to the OS.
+pub fn collect_heaps() -> usize {
+  let heaps = HEAPS.0.lock();
+  let mut released = 0;
+  let mut cursor = *heaps.head();
+  while let Some(mut heap) = cursor {
+    cursor = *unsafe { heap.as_ref() }.link().next();
+    let _guard = unsafe { heap.as_ref() }.lock();
+    released += unsafe { heap.as_mut() }.collect();
+  }
+  released
+}
+
+/// Calls `visit` with every abandoned segment, holding its class lock.
+pub fn visit_abandoned(mut visit: impl FnMut(&Segment)) {
+  for abandoned in ABANDONED.iter() {
+    for segment in abandoned.0.lock().iter() {
+      visit(segment);
+    }
+  }
+}
+
+/// Sums the counters of every registered heap and the usage of every
+/// arena.
+pub fn stats() -> Stats {
+  let mut stats = Stats::default();
+  for heap in HEAPS.0.lock().iter() {
+    stats.add_heap(heap);
+  }
+  for arena in ARENAS.0.read().iter() {
+    stats.add_arena(arena);
+  }
+  stats
+}
+
+pub fn abandon_segment(segment: NonNull<Segment>) {
+  let segment_ref = unsafe { segment.as_ref() };
+  let mut abandoned = ABANDONED[segment_ref.class().id].0.lock();
+  segment_ref.set_owner(core::ptr::null_mut());
+  abandoned.push(segment);
+}
+
+/// Hands an abandoned segment of `class` over to `owner`.
+pub fn adopt_segment(
+  class: &'static Class,
+  owner: *mut Heap,
+) -> Option<NonNull<Segment>> {
+  let segment = ABANDONED[class.id].0.lock().pop()?;
+  unsafe { segment.as_ref() }.set_owner(owner);
+  Some(segment)
+}
+
+/// Frees `ptr` into `segment` while it is abandoned, releasing the segment
+/// once it empties. Returns `false` if the segment was adopted meanwhile,
+/// in which case the block belongs to the new owner.
+pub fn free_abandoned(mut segment: NonNull<Segment>, ptr: NonNull<u8>) -> bool {
+  let class = unsafe { segment.as_ref() }.class();
+  let mut abandoned = ABANDONED[class.id].0.lock();
+
+  let segment_ref = unsafe { segment.as_mut() };
+  if !segment_ref.owner().is_null() {
+    return false;
+  }
+
+  segment_ref.dealloc(ptr);
+  segment_ref.collect();
+  if segment_ref.is_empty() {
+    abandoned.remove(segment);
+    drop(abandoned);
+    let _ = deallocate_segment(segment);
+  }
+  true
+}
+
+#[cfg(test)]
+mod tests {
+  use std::alloc::Layout;
+
+  use tinyalloc_config::config::{
+    ARENA_GROWTH,
+    ARENA_INITIAL_SIZE,
+    LARGE_SC_LIMIT,
+  };
+
+  use super::*;
+
+  #[test]
+  fn arena_size_follows_arena_count() {
+    assert_eq!(arena_size(0), ARENA_INITIAL_SIZE);
+    assert_eq!(arena_size(ARENA_STEP - 1), ARENA_INITIAL_SIZE);
+    assert_eq!(arena_size(ARENA_STEP), ARENA_INITIAL_SIZE * ARENA_GROWTH);
+    assert_eq!(arena_size(usize::MAX), ARENA_MAX_SIZE);
+  }
+
+  #[test]
+  fn idle_arenas_are_released() {
+    let arena = Arena::new(ARENA_INITIAL_SIZE).unwrap();
+    let size = unsafe { arena.as_ref() }.size();
+    ARENAS.0.write().push(arena);
+
+    assert!(release_idle_arenas(Duration::ZERO, true) >= size);
+    let arenas = ARENAS.0.read();
+    assert!(
+      !arenas
+        .iter()
+        .any(|other| core::ptr::eq(other, arena.as_ptr()))
+    );
+  }
+
+  #[test]
+  fn remote_frees_left_on_a_released_heap_are_drained() {
+    let heap = acquire_heap().unwrap();
+    let layout = Layout::from_size_align(LARGE_SC_LIMIT + 1, 8).unwrap();
+    let block = unsafe { &mut *heap.as_ptr() }
+      .allocate(layout)
+      .unwrap()
+      .cast::<u8>();
+    release_heap(heap);
+
+    // As pushed by a thread that still saw the heap claimed.
+    let heap_ref = unsafe { heap.as_ref() };
+    heap_ref.remote().push(block);
+    drain_unclaimed(heap);
+    assert!(heap_ref.remote().is_empty());
+    assert!(!heap_ref.larges().any(|large| large.contains_ptr(block)));
+  }
+}
@@ -0,0 +1,149 @@
+use std::sync::atomic::{
+  AtomicUsize,
+  Ordering,
+};
+
+use tinyalloc_config::config::SEGMENT_SIZE;
+
+use crate::{
+  arena::Arena,
+  heap::Heap,
+};
+
+/// Byte counters kept by every heap. Whichever thread is using the heap
+/// updates them with relaxed atomics, so they are cheap enough to stay on
+/// in every build. A free may be counted on a different heap than its
+/// allocation, so only sums across all heaps are meaningful.
+pub struct Counters {
+  allocated: AtomicUsize,
+  freed: AtomicUsize,
+  large: AtomicUsize,
+}
+
+impl Counters {
+  pub const fn new() -> Self {
+    Self {
+      allocated: AtomicUsize::new(0),
+      freed: AtomicUsize::new(0),
+      large: AtomicUsize::new(0),
+    }
+  }
+
+  pub fn alloc(&self, bytes: usize) {
+    self.allocated.fetch_add(bytes, Ordering::Relaxed);
+  }
+
+  pub fn free(&self, bytes: usize) {
+    self.freed.fetch_add(bytes, Ordering::Relaxed);
+  }
+
+  pub fn map_large(&self, bytes: usize) {
+    self.large.fetch_add(bytes, Ordering::Relaxed);
+  }
+
+  pub fn unmap_large(&self, bytes: usize) {
+    self.large.fetch_sub(bytes, Ordering::Relaxed);
+  }
+
+  /// Records a large block resized from `old` to `new` bytes of capacity,
+  /// counted as a free followed by a fresh allocation.
+  pub fn resize_large(&self, old: usize, new: usize) {
+    self.free(old);
+    self.alloc(new);
+    self.unmap_large(old);
+    self.map_large(new);
+  }
+}
+
+impl Default for Counters {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Point-in-time view of the allocator. Small blocks count at their class
+/// size and large blocks at their mapped capacity. Figures are gathered
+/// without stopping other threads, so they may be slightly inconsistent
+/// with each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+  /// Bytes handed out since start, counting a resize as a new allocation.
+  pub allocated: usize,
+  /// Bytes handed out and not yet freed.
+  pub live: usize,
+  /// Bytes in empty segments that heaps keep cached.
+  pub free_segments: usize,
+  /// Bytes of arena segments that are committed.
+  pub committed: usize,
+  /// Bytes of address space reserved by arenas.
+  pub reserved: usize,
+  /// Bytes mapped for large blocks.
+  pub large: usize,
+  /// Blocks freed by other threads that their owners have not taken back.
+  pub pending_remote: usize,
+  /// Arenas currently mapped.
+  pub arenas: usize,
+}
+
+impl Stats {
+  pub fn add_heap(&mut self, heap: &Heap) {
+    let counters = heap.counters();
+    let allocated = counters.allocated.load(Ordering::Relaxed);
+    let freed = counters.freed.load(Ordering::Relaxed);
+
+    // Each heap's share may wrap on its own; the totals do not.
+    self.allocated = self.allocated.wrapping_add(allocated);
+    self.live = self.live.wrapping_add(allocated.wrapping_sub(freed));
+    self.large = self
+      .large
+      .wrapping_add(counters.large.load(Ordering::Relaxed));
+    self.free_segments += heap.free_segments() * SEGMENT_SIZE;
+    self.pending_remote += heap.remote().count();
+  }
+
+  pub fn add_arena(&mut self, arena: &Arena) {
+    self.arenas += 1;
+    self.reserved += arena.size();
+    self.committed += arena.active_segments() * SEGMENT_SIZE;
+    self.pending_remote += arena.pending_frees();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::alloc::Layout;
+
+  use tinyalloc_config::classes::find_class;
+
+  use super::*;
+  use crate::large::Large;
+
+  #[test]
+  fn heap_counters_track_live_bytes() {
+    let mut heap = Heap::new();
+    let small = Layout::from_size_align(100, 8).unwrap();
+    let large = Layout::from_size_align(1 << 20, 8).unwrap();
+
+    let small_ptr = heap.allocate(small).unwrap().cast::<u8>();
+    let large_ptr = heap.allocate_large(large).unwrap().cast::<u8>();
+    let class_size = find_class(100, 8).unwrap().size.0;
+    let capacity =
+      unsafe { Large::from_user_ptr(large_ptr).unwrap().as_ref() }.capacity();
+
+    let mut stats = Stats::default();
+    stats.add_heap(&heap);
+    assert_eq!(stats.allocated, class_size + capacity);
+    assert_eq!(stats.live, stats.allocated);
+    assert_eq!(stats.large, capacity);
+
+    heap.deallocate(small_ptr, small).unwrap();
+    heap.deallocate_large(large_ptr).unwrap();
+
+    let mut stats = Stats::default();
+    stats.add_heap(&heap);
+    assert_eq!(stats.allocated, class_size + capacity);
+    assert_eq!(stats.live, 0);
+    assert_eq!(stats.large, 0);
+    assert_eq!(stats.free_segments, heap.free_segments() * SEGMENT_SIZE);
+  }
+}
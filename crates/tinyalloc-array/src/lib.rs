@@ -89,10 +89,16 @@ impl<T, const SIZE: usize> Array<T, SIZE> {
     }
   }
 
+  /// # Safety
+  ///
+  /// `index` must be less than `self.len()`.
   pub unsafe fn get_unchecked(&self, index: usize) -> &T {
     unsafe { self.data.get_unchecked(index).assume_init_ref() }
   }
 
+  /// # Safety
+  ///
+  /// `index` must be less than `self.len()`.
   pub unsafe fn get_unchecked_mut(&mut self, index: usize) -> &mut T {
     unsafe { self.data.get_unchecked_mut(index).assume_init_mut() }
   }
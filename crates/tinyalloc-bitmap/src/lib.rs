@@ -29,7 +29,7 @@ where
 {
   #[inline(always)]
   pub const fn words(fields: usize) -> usize {
-    (fields + T::BITS - 1) / T::BITS
+    fields.div_ceil(T::BITS)
   }
 
   #[inline(always)]
@@ -29,7 +29,7 @@ where
   fn get(self, bit: usize) -> bool;
 
   fn words(bits: usize) -> usize {
-    (bits + Self::BITS - 1) / Self::BITS
+    bits.div_ceil(Self::BITS)
   }
 
   fn bytes(bits: usize) -> usize {
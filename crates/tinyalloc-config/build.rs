@@ -0,0 +1,224 @@
+//! Picks the allocator geometry: the values of a named profile, chosen by
+//! cargo feature, with any of them overridden by a `TINYALLOC_<NAME>`
+//! variable in the build environment, e.g.
+//!
+//! ```sh
+//! TINYALLOC_SEGMENT_SHIFT=17 cargo build --features low-footprint
+//! ```
+//!
+//! The result is written to `$OUT_DIR/geometry.rs` and included by
+//! `config.rs`, which derives the rest and checks the combination.
+
+use std::{
+  env,
+  fmt::Write as _,
+  fs,
+  path::Path,
+};
+
+#[derive(Clone, Copy)]
+enum Profile {
+  Standard,
+  LowFootprint,
+  Throughput,
+}
+
+#[derive(Clone, Copy)]
+enum Value {
+  Number(usize),
+  Spacing([usize; 3]),
+}
+
+struct Knob {
+  name: &'static str,
+  doc: &'static str,
+  value: fn(Profile, usize) -> Value,
+}
+
+use Profile::*;
+use Value::*;
+
+/// Every knob, with its value in each profile given the log2 of the
+/// target's word size.
+const KNOBS: &[Knob] = &[
+  Knob {
+    name: "SEGMENT_SHIFT",
+    doc: "Log2 of the segment size, the unit small objects are carved from.",
+    value: |profile, shift| {
+      Number(match profile {
+        Standard => 16 + shift,
+        LowFootprint => 14 + shift,
+        Throughput => 18 + shift,
+      })
+    },
+  },
+  Knob {
+    name: "ARENA_SHIFT",
+    doc: "Log2 of the size of the first arenas reserved for segments.",
+    value: |profile, shift| {
+      Number(match profile {
+        Standard => 23 + shift,
+        LowFootprint => 20 + shift,
+        Throughput => 24 + shift,
+      })
+    },
+  },
+  Knob {
+    name: "ARENA_MAX_SHIFT",
+    doc: "Log2 of the size arenas stop growing at.",
+    value: |profile, shift| {
+      Number(match profile {
+        Standard => 29 + shift,
+        LowFootprint => 24 + shift,
+        Throughput => 30 + shift,
+      })
+    },
+  },
+  Knob {
+    name: "SMALL_SC_LIMIT",
+    doc: "Largest size served by the finely spaced small classes.",
+    value: |_, shift| Number(1 << (shift + 5)),
+  },
+  Knob {
+    name: "MEDIUM_SC_LIMIT",
+    doc: "Largest size served by the medium classes.",
+    value: |profile, shift| {
+      Number(match profile {
+        LowFootprint => 1 << (shift + 9),
+        Standard | Throughput => 1 << (shift + 10),
+      })
+    },
+  },
+  Knob {
+    name: "LARGE_SC_LIMIT",
+    doc: "Largest size served from segments; anything bigger is mapped.",
+    value: |profile, shift| {
+      Number(match profile {
+        Standard => 1 << (shift + 15),
+        LowFootprint => 1 << (shift + 12),
+        Throughput => 1 << (shift + 16),
+      })
+    },
+  },
+  Knob {
+    name: "CLASS_SPACING",
+    doc: "How many alignment steps apart classes are below the small, \
+          medium and large limits.",
+    value: |profile, _| {
+      Spacing(match profile {
+        LowFootprint => [1, 4, 8],
+        Standard | Throughput => [1, 2, 4],
+      })
+    },
+  },
+  Knob {
+    name: "MIN_SEGMENT_OBJECTS",
+    doc: "Objects a segment must hold of every class, checked at compile \
+          time.",
+    value: |profile, _| {
+      Number(match profile {
+        Standard => 1,
+        LowFootprint | Throughput => 2,
+      })
+    },
+  },
+  Knob {
+    name: "REMOTE_BATCH_SIZE",
+    doc: "Remote frees a heap lets queue up before taking them back.",
+    value: |profile, _| {
+      Number(match profile {
+        Standard => 32,
+        LowFootprint => 8,
+        Throughput => 128,
+      })
+    },
+  },
+  Knob {
+    name: "REMOTE_CHECK_FREQUENCY",
+    doc: "Operations between a heap's checks for remote frees.",
+    value: |profile, _| {
+      Number(match profile {
+        Standard => 16,
+        LowFootprint => 8,
+        Throughput => 64,
+      })
+    },
+  },
+  Knob {
+    name: "QUEUE_THRESHOLD",
+    doc: "Empty segments a class keeps before returning them to the arena.",
+    value: |profile, _| {
+      Number(match profile {
+        Standard => 12,
+        LowFootprint => 1,
+        Throughput => 32,
+      })
+    },
+  },
+];
+
+fn profile() -> Profile {
+  let low_footprint = env::var_os("CARGO_FEATURE_LOW_FOOTPRINT").is_some();
+  let throughput = env::var_os("CARGO_FEATURE_THROUGHPUT").is_some();
+  match (low_footprint, throughput) {
+    (true, true) => {
+      println!(
+        "cargo:warning=both the low-footprint and throughput profiles are \
+         enabled; using low-footprint"
+      );
+      LowFootprint
+    }
+    (true, false) => LowFootprint,
+    (false, true) => Throughput,
+    (false, false) => Standard,
+  }
+}
+
+fn parse(name: &str, text: &str, default: Value) -> Value {
+  let number = |text: &str| {
+    text
+      .trim()
+      .parse::<usize>()
+      .unwrap_or_else(|_| panic!("TINYALLOC_{name}={text} is not a number"))
+  };
+  match default {
+    Number(_) => Number(number(text)),
+    Spacing(_) => {
+      let steps: Vec<_> = text.split(':').map(number).collect();
+      Spacing(steps.try_into().unwrap_or_else(|_| {
+        panic!("TINYALLOC_{name}={text} needs three steps, as A:B:C")
+      }))
+    }
+  }
+}
+
+fn main() {
+  let shift = match env::var("CARGO_CFG_TARGET_POINTER_WIDTH").as_deref() {
+    Ok("16") => 1,
+    Ok("32") => 2,
+    _ => 3,
+  };
+  let profile = profile();
+
+  let mut out = String::new();
+  for knob in KNOBS {
+    let variable = format!("TINYALLOC_{}", knob.name);
+    println!("cargo:rerun-if-env-changed={variable}");
+
+    let default = (knob.value)(profile, shift);
+    let value = match env::var(&variable) {
+      Ok(text) => parse(knob.name, &text, default),
+      Err(_) => default,
+    };
+    let (ty, value) = match value {
+      Number(value) => ("usize", value.to_string()),
+      Spacing(steps) => ("[usize; 3]", format!("{steps:?}")),
+    };
+    writeln!(out, "/// {}", knob.doc).unwrap();
+    writeln!(out, "pub const {}: {ty} = {value};", knob.name).unwrap();
+  }
+
+  let path = Path::new(&env::var("OUT_DIR").unwrap()).join("geometry.rs");
+  fs::write(path, out).expect("Unable to write geometry.rs");
+  println!("cargo:rerun-if-changed=build.rs");
+}
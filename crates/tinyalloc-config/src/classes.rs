@@ -4,6 +4,7 @@ use tinyalloc_bitmap::numeric::Bits;
 
 use crate::{
   config::{
+    CLASS_SPACING,
     LARGE_ALIGN_RATIO,
     LARGE_SC_LIMIT,
     MEDIUM_ALIGN_LIMIT,
@@ -88,30 +89,40 @@ const fn size_to_align(size: usize) -> usize {
   }
 }
 
+/// The class size after `size`, spaced by `CLASS_SPACING` within each tier.
+const fn next_size(size: usize) -> usize {
+  let align = size_to_align(size);
+  if size < SMALL_SC_LIMIT {
+    size + align * CLASS_SPACING[0]
+  } else if size < MEDIUM_SC_LIMIT {
+    size + align * CLASS_SPACING[1]
+  } else {
+    size + align * CLASS_SPACING[2]
+  }
+}
+
+/// How many classes `classes()` builds: one per step below
+/// `LARGE_SC_LIMIT`, and one for the limit itself.
+pub(crate) const fn class_count() -> usize {
+  let mut count = 1;
+  let mut size = MIN_SIZE;
+  while size < LARGE_SC_LIMIT {
+    count += 1;
+    size = next_size(size);
+  }
+  count
+}
+
 const fn classes() -> [Class; SIZES] {
   let mut classes = [Class::new(0, 0, 0); SIZES];
   let mut i = 0;
   let mut size = MIN_SIZE;
 
-  while i < SIZES {
+  while size < LARGE_SC_LIMIT {
     let align = size_to_align(size);
     let aligned_size = align_up(size, align);
     classes[i] = Class::new(aligned_size, align, i);
-
-    if i == SIZES - 1 {
-      break;
-    }
-
-    if size < SMALL_SC_LIMIT {
-      size += align;
-    } else if size < MEDIUM_SC_LIMIT {
-      size += align * 2;
-    } else if size < LARGE_SC_LIMIT {
-      size += align * 4;
-    } else {
-      size *= 2;
-    }
-
+    size = next_size(size);
     i += 1;
   }
 
@@ -135,7 +146,7 @@ const fn find_small_align_class(
   }
 
   if align <= MIN_ALIGN {
-    let rounded = (size + MIN_ALIGN - 1) / MIN_ALIGN;
+    let rounded = size.div_ceil(MIN_ALIGN);
     let index = rounded - 1;
     return Some(&CLASSES[index]);
   }
@@ -156,7 +167,7 @@ const fn find_small_ratio_class(
     return None;
   }
 
-  let mut multiple = (size + SMALL_ALIGN_LIMIT - 1) / SMALL_ALIGN_LIMIT;
+  let mut multiple = size.div_ceil(SMALL_ALIGN_LIMIT);
   if multiple < 2 {
     multiple = 2;
   }
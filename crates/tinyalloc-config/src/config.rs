@@ -1,4 +1,3 @@
-pub const SIZES: usize = 84;
 pub const ONE: usize = 1;
 pub const WORD: usize = core::mem::size_of::<usize>();
 
@@ -7,19 +6,20 @@ pub const SHIFT: usize = WORD.trailing_zeros() as usize;
 pub const MIN_ALIGN: usize = WORD;
 pub const MIN_SIZE: usize = MIN_ALIGN;
 
-pub const ARENA_SHIFT: usize = 23 + SHIFT;
+// The geometry picked at build time, from a profile feature and any
+// `TINYALLOC_*` overrides. See `build.rs`.
+include!(concat!(env!("OUT_DIR"), "/geometry.rs"));
+
+pub const SIZES: usize = crate::classes::class_count();
+
 pub const ARENA_INITIAL_SIZE: usize = 1 << ARENA_SHIFT;
 pub const ARENA_GROWTH: usize = 2;
 pub const ARENA_STEP: usize = 4;
-pub const ARENA_LIMIT: usize = 80;
+pub const ARENA_MAX_SIZE: usize = 1 << ARENA_MAX_SHIFT;
+pub const ARENA_RELEASE_DELAY_MS: u64 = 1000;
 
-pub const SEGMENT_SHIFT: usize = 16 + SHIFT;
 pub const SEGMENT_SIZE: usize = 1 << SEGMENT_SHIFT;
 
-pub const SMALL_SC_LIMIT: usize = 1 << (SHIFT + 5);
-pub const MEDIUM_SC_LIMIT: usize = 1 << (SHIFT + 10);
-pub const LARGE_SC_LIMIT: usize = 1 << (SHIFT + 15);
-
 pub const SMALL_ALIGN_LIMIT: usize = SMALL_SC_LIMIT / 4;
 pub const MEDIUM_ALIGN_LIMIT: usize = MEDIUM_SC_LIMIT / 8;
 pub const LARGE_ALIGN_RATIO: usize = 8;
@@ -27,9 +27,34 @@ pub const LARGE_ALIGN_RATIO: usize = 8;
 pub const SMALL_ALIGN_CLASSES: usize = SMALL_ALIGN_LIMIT / MIN_ALIGN;
 pub const SMALL_RATIO: usize = SMALL_SC_LIMIT / SMALL_ALIGN_LIMIT;
 
-pub const REMOTE_BATCH_SIZE: usize = 32;
-pub const REMOTE_CHECK_FREQUENCY: usize = 16;
-pub const REMOTE_MAX_BATCH: usize = 64;
-
-pub const QUEUE_THRESHOLD: usize = 12;
-
+pub const QUARANTINE_SLOTS: usize = 256;
+pub const REDZONE_SIZE: usize = 16;
+pub const GUARDED_SLOTS: usize = 64;
+
+const _: () = {
+  assert!(SEGMENT_SHIFT >= 12, "segments must span at least a page");
+  assert!(SEGMENT_SHIFT < ARENA_SHIFT, "arenas must hold segments");
+  assert!(ARENA_SHIFT <= ARENA_MAX_SHIFT);
+  assert!(ARENA_MAX_SHIFT < usize::BITS as usize);
+
+  assert!(
+    SMALL_SC_LIMIT.is_power_of_two()
+      && MEDIUM_SC_LIMIT.is_power_of_two()
+      && LARGE_SC_LIMIT.is_power_of_two(),
+    "size class limits must be powers of two"
+  );
+  assert!(SMALL_ALIGN_LIMIT >= MIN_ALIGN);
+  assert!(
+    MEDIUM_ALIGN_LIMIT >= SMALL_ALIGN_LIMIT && MEDIUM_SC_LIMIT > SMALL_SC_LIMIT
+  );
+  assert!(LARGE_SC_LIMIT > MEDIUM_SC_LIMIT);
+  assert!(
+    CLASS_SPACING[0] == 1,
+    "the small class lookup indexes classes one alignment step apart"
+  );
+  assert!(CLASS_SPACING[1] > 0 && CLASS_SPACING[2] > 0);
+  assert!(MIN_SEGMENT_OBJECTS > 0);
+  assert!(LARGE_SC_LIMIT * MIN_SEGMENT_OBJECTS < SEGMENT_SIZE);
+
+  assert!(REMOTE_BATCH_SIZE > 0 && REMOTE_CHECK_FREQUENCY > 0);
+};
@@ -1,4 +1,5 @@
 pub mod config;
 pub mod classes;
 pub mod helper;
-pub mod metrics;
\ No newline at end of file
+pub mod metrics;
+pub mod simulate;
\ No newline at end of file
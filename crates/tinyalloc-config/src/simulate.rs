@@ -0,0 +1,489 @@
+use tinyalloc_sys::size::page_align;
+
+use crate::{
+  classes::Class,
+  config::{
+    CLASS_SPACING,
+    LARGE_ALIGN_RATIO,
+    LARGE_SC_LIMIT,
+    MEDIUM_SC_LIMIT,
+    MIN_ALIGN,
+    MIN_SIZE,
+    SMALL_SC_LIMIT,
+    WORD,
+  },
+  helper::align_up,
+};
+
+/// The knobs `classes()` builds the class table from, so candidate tables
+/// can be built and compared against the current one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableParams {
+  pub small_limit: usize,
+  pub medium_limit: usize,
+  pub large_limit: usize,
+  /// How many alignment steps apart classes are below the small, medium
+  /// and large limits.
+  pub spacing: [usize; 3],
+}
+
+impl TableParams {
+  pub const CURRENT: Self = Self {
+    small_limit: SMALL_SC_LIMIT,
+    medium_limit: MEDIUM_SC_LIMIT,
+    large_limit: LARGE_SC_LIMIT,
+    spacing: CLASS_SPACING,
+  };
+
+  fn size_to_align(&self, size: usize) -> usize {
+    if size <= self.small_limit / 4 {
+      MIN_ALIGN
+    } else if size <= self.medium_limit / 8 {
+      self.small_limit / 4
+    } else if size <= self.large_limit {
+      self.medium_limit / 8
+    } else {
+      size / LARGE_ALIGN_RATIO
+    }
+  }
+
+  /// The class table these parameters give, the way `classes()` builds
+  /// `CLASSES`, or `None` if the limits are not increasing powers of two
+  /// or a spacing is zero.
+  pub fn classes(&self) -> Option<Vec<Class>> {
+    let limits = [self.small_limit, self.medium_limit, self.large_limit];
+    if limits.iter().any(|limit| !limit.is_power_of_two())
+      || self.small_limit < 4 * MIN_ALIGN
+      || self.medium_limit < 8 * MIN_ALIGN
+      || self.small_limit >= self.medium_limit
+      || self.medium_limit >= self.large_limit
+      || self.spacing.contains(&0)
+    {
+      return None;
+    }
+
+    let mut classes = Vec::new();
+    let mut size = MIN_SIZE;
+    while size < self.large_limit {
+      let align = self.size_to_align(size);
+      classes.push(Class::new(align_up(size, align), align, classes.len()));
+      let tier = if size < self.small_limit {
+        0
+      } else if size < self.medium_limit {
+        1
+      } else {
+        2
+      };
+      size += align * self.spacing[tier];
+    }
+    let align = self.size_to_align(self.large_limit);
+    classes.push(Class::new(
+      align_up(self.large_limit, align),
+      align,
+      classes.len(),
+    ));
+    Some(classes)
+  }
+}
+
+/// Slots a segment of `segment_size` bytes holds for `class`, laid out
+/// the way `Segment::new` does it: the `header` bytes of the segment
+/// itself, the in-use bitmap and the quarantine bitmap, each sized for
+/// every slot that could fit after the words before it, then the slots.
+pub const fn objects_per_segment(
+  class: &Class,
+  segment_size: usize,
+  header: usize,
+) -> usize {
+  let bitmap_start = align_up(header, core::mem::align_of::<usize>());
+  let quarantine_start = bitmap_end(class, segment_size, bitmap_start);
+  let bitmaps_end = bitmap_end(class, segment_size, quarantine_start);
+  let user_start = align_up(bitmaps_end, class.align.0);
+  segment_size.saturating_sub(user_start) / class.size.0
+}
+
+/// End of a bitmap starting at `start`, with a bit for every slot that
+/// fits in the rest of the segment.
+const fn bitmap_end(class: &Class, segment_size: usize, start: usize) -> usize {
+  let bits = segment_size.saturating_sub(start) / class.size.0;
+  start + bits.div_ceil(usize::BITS as usize) * WORD
+}
+
+/// The smallest class in `classes` that fits `size` at `align`, which is
+/// what `find_class` picks from `CLASSES`.
+pub fn find_in(classes: &[Class], size: usize, align: usize) -> Option<usize> {
+  if size == 0 {
+    return None;
+  }
+  let first = classes.partition_point(|class| class.size.0 < size);
+  (first..classes.len()).find(|&index| align <= classes[index].align.0)
+}
+
+/// What one class saw over a simulation.
+#[derive(Debug, Clone, Default)]
+pub struct ClassReport {
+  /// Allocations and in-place reallocs served from the class.
+  pub allocations: usize,
+  /// Bytes asked for, against the class size times `allocations`.
+  pub requested: usize,
+  pub objects_per_segment: usize,
+  pub peak_segments: usize,
+  /// Most objects live while the class held `peak_segments`.
+  pub live_at_peak: usize,
+}
+
+impl ClassReport {
+  /// Share of the bytes handed out that went unused inside the slots.
+  pub fn internal_fragmentation(&self, class: &Class) -> f64 {
+    let handed_out = self.allocations * class.size.0;
+    if handed_out == 0 {
+      return 0.0;
+    }
+    1.0 - self.requested as f64 / handed_out as f64
+  }
+
+  /// Share of a segment's bytes its slots take up, even when all full.
+  pub fn segment_utilization(&self, class: &Class, segment_size: usize) -> f64 {
+    (self.objects_per_segment * class.size.0) as f64 / segment_size as f64
+  }
+
+  /// Share of the slots in use, at most, while the class held the most
+  /// segments.
+  pub fn occupancy_at_peak(&self) -> f64 {
+    let slots = self.peak_segments * self.objects_per_segment;
+    if slots == 0 {
+      return 0.0;
+    }
+    self.live_at_peak as f64 / slots as f64
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+  pub classes: Vec<ClassReport>,
+  /// Requests too big or too aligned for any class.
+  pub large_allocations: usize,
+  /// Most bytes in segments and large blocks at once.
+  pub peak_memory: usize,
+  /// Most bytes asked for and live at once.
+  pub peak_requested: usize,
+}
+
+/// Segments of one class. Slots go to the current segment until it
+/// fills, then to any segment with room, then to a new one; a segment
+/// whose last slot is freed is given back.
+#[derive(Default)]
+struct Pool {
+  used: Vec<usize>,
+  partial: Vec<usize>,
+  released: Vec<usize>,
+  current: Option<usize>,
+  segments: usize,
+  live: usize,
+}
+
+impl Pool {
+  fn take(&mut self, per_segment: usize) -> usize {
+    let has_room = |pool: &Self, index: usize| pool.used[index] < per_segment;
+    let index = match self.current.filter(|&index| has_room(self, index)) {
+      Some(index) => index,
+      None => {
+        let mut found = None;
+        while let Some(index) = self.partial.pop() {
+          if has_room(self, index) && self.used[index] > 0 {
+            found = Some(index);
+            break;
+          }
+        }
+        let index = found.unwrap_or_else(|| {
+          self.segments += 1;
+          self.released.pop().unwrap_or_else(|| {
+            self.used.push(0);
+            self.used.len() - 1
+          })
+        });
+        self.current = Some(index);
+        index
+      }
+    };
+    self.used[index] += 1;
+    self.live += 1;
+    index
+  }
+
+  fn put(&mut self, index: usize, per_segment: usize) {
+    self.used[index] -= 1;
+    self.live -= 1;
+    if self.used[index] == 0 {
+      if self.current == Some(index) {
+        self.current = None;
+      }
+      self.segments -= 1;
+      self.released.push(index);
+    } else if self.used[index] + 1 == per_segment {
+      self.partial.push(index);
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+enum Placement {
+  Slot { class: usize, segment: usize },
+  Large { bytes: usize },
+}
+
+#[derive(Clone, Copy)]
+struct Block {
+  placement: Placement,
+  size: usize,
+  align: usize,
+}
+
+/// Plays allocations and frees against a class table as if they all came
+/// from one heap, tracking the segments each class needs.
+pub struct Simulator {
+  classes: Vec<Class>,
+  segment_size: usize,
+  pools: Vec<Pool>,
+  blocks: Vec<Option<Block>>,
+  memory: usize,
+  requested: usize,
+  report: Report,
+}
+
+impl Simulator {
+  /// `header` is the bytes each segment spends on itself ahead of its
+  /// bitmap, `size_of::<Segment>()` for tinyalloc's own segments.
+  pub fn new(classes: Vec<Class>, segment_size: usize, header: usize) -> Self {
+    let report = Report {
+      classes: classes
+        .iter()
+        .map(|class| ClassReport {
+          objects_per_segment: objects_per_segment(class, segment_size, header),
+          ..ClassReport::default()
+        })
+        .collect(),
+      ..Report::default()
+    };
+    Self {
+      pools: classes.iter().map(|_| Pool::default()).collect(),
+      classes,
+      segment_size,
+      blocks: Vec::new(),
+      memory: 0,
+      requested: 0,
+      report,
+    }
+  }
+
+  pub fn classes(&self) -> &[Class] {
+    &self.classes
+  }
+
+  /// Allocates the block named `key`, which must not be live.
+  pub fn alloc(&mut self, key: usize, size: usize, align: usize) {
+    let class = find_in(&self.classes, size, align)
+      .filter(|&class| self.report.classes[class].objects_per_segment > 0);
+    let placement = match class {
+      Some(class) => {
+        let per_segment = self.report.classes[class].objects_per_segment;
+        let pool = &mut self.pools[class];
+        let segments = pool.segments;
+        let segment = pool.take(per_segment);
+        if pool.segments > segments {
+          self.memory += self.segment_size;
+        }
+
+        let report = &mut self.report.classes[class];
+        report.allocations += 1;
+        report.requested += size;
+        if pool.segments > report.peak_segments {
+          report.peak_segments = pool.segments;
+          report.live_at_peak = pool.live;
+        } else if pool.segments == report.peak_segments {
+          report.live_at_peak = report.live_at_peak.max(pool.live);
+        }
+        Placement::Slot { class, segment }
+      }
+      None => {
+        let bytes = page_align(size.max(1));
+        self.memory += bytes;
+        self.report.large_allocations += 1;
+        Placement::Large { bytes }
+      }
+    };
+
+    if key >= self.blocks.len() {
+      self.blocks.resize(key + 1, None);
+    }
+    self.blocks[key] = Some(Block {
+      placement,
+      size,
+      align,
+    });
+    self.requested += size;
+    self.report.peak_memory = self.report.peak_memory.max(self.memory);
+    self.report.peak_requested = self.report.peak_requested.max(self.requested);
+  }
+
+  /// Frees the block named `key`, if it is live.
+  pub fn free(&mut self, key: usize) {
+    let Some(block) = self.blocks.get_mut(key).and_then(Option::take) else {
+      return;
+    };
+    self.requested -= block.size;
+    match block.placement {
+      Placement::Slot { class, segment } => {
+        let per_segment = self.report.classes[class].objects_per_segment;
+        let pool = &mut self.pools[class];
+        let segments = pool.segments;
+        pool.put(segment, per_segment);
+        if pool.segments < segments {
+          self.memory -= self.segment_size;
+        }
+      }
+      Placement::Large { bytes } => self.memory -= bytes,
+    }
+  }
+
+  /// Resizes the block named `key`. Like the allocator, it stays in its
+  /// slot while the new size fits and its class is at least half the
+  /// slot's size, and moves otherwise.
+  pub fn realloc(&mut self, key: usize, new_size: usize) {
+    let Some(block) = self.blocks.get(key).copied().flatten() else {
+      return;
+    };
+    if let Placement::Slot { class, .. } = block.placement
+      && new_size <= self.classes[class].size.0
+      && let Some(new_class) = find_in(&self.classes, new_size, block.align)
+      && self.classes[new_class].size.0 * 2 >= self.classes[class].size.0
+    {
+      self.requested = self.requested - block.size + new_size;
+      self.report.peak_requested =
+        self.report.peak_requested.max(self.requested);
+      self.report.classes[class].requested += new_size;
+      self.report.classes[class].allocations += 1;
+      if let Some(block) = &mut self.blocks[key] {
+        block.size = new_size;
+      }
+      return;
+    }
+    // The new block is made before the old one goes, as the copy needs
+    // both.
+    let moved = self.blocks.len();
+    self.alloc(moved, new_size, block.align);
+    self.free(key);
+    self.blocks[key] = self.blocks.pop().flatten();
+  }
+
+  pub fn report(&self) -> &Report {
+    &self.report
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    classes::{
+      CLASSES,
+      find_class,
+    },
+    config::SEGMENT_SIZE,
+  };
+
+  #[test]
+  fn current_params_rebuild_the_class_table() {
+    let classes = TableParams::CURRENT.classes().unwrap();
+    assert_eq!(classes, CLASSES.to_vec());
+
+    for size in (1..LARGE_SC_LIMIT).step_by(7) {
+      for align in [1, MIN_ALIGN, 32, 256, 4096] {
+        assert_eq!(
+          find_in(&classes, size, align),
+          find_class(size, align).map(|class| class.id),
+          "size {size} align {align}"
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn params_reject_tables_that_cannot_be_built() {
+    let params = TableParams {
+      medium_limit: SMALL_SC_LIMIT,
+      ..TableParams::CURRENT
+    };
+    assert!(params.classes().is_none());
+    let params = TableParams {
+      spacing: [1, 0, 4],
+      ..TableParams::CURRENT
+    };
+    assert!(params.classes().is_none());
+
+    let coarser = TableParams {
+      spacing: [2, 4, 8],
+      ..TableParams::CURRENT
+    };
+    assert!(coarser.classes().unwrap().len() < CLASSES.len());
+  }
+
+  #[test]
+  fn simulator_tracks_segments_and_peak_memory() {
+    let classes = TableParams::CURRENT.classes().unwrap();
+    let mut simulator = Simulator::new(classes, SEGMENT_SIZE, 256);
+    let class = find_class(100, 8).unwrap();
+    let per_segment = simulator.report().classes[class.id].objects_per_segment;
+
+    for key in 0..per_segment + 1 {
+      simulator.alloc(key, 100, 8);
+    }
+    simulator.alloc(per_segment + 1, 10 * LARGE_SC_LIMIT, 8);
+    let report = simulator.report();
+    assert_eq!(report.classes[class.id].peak_segments, 2);
+    assert_eq!(report.large_allocations, 1);
+    assert_eq!(report.peak_memory, 2 * SEGMENT_SIZE + 10 * LARGE_SC_LIMIT);
+    assert_eq!(
+      report.peak_requested,
+      (per_segment + 1) * 100 + 10 * LARGE_SC_LIMIT
+    );
+
+    let fragmentation = report.classes[class.id].internal_fragmentation(class);
+    assert!((fragmentation - (1.0 - 100.0 / class.size.0 as f64)).abs() < 1e-9);
+
+    // Growing within the class stays put; emptying a segment gives it back.
+    simulator.realloc(0, class.size.0);
+    simulator.free(per_segment);
+    for key in 0..per_segment + 2 {
+      simulator.free(key);
+    }
+    assert_eq!(simulator.memory, 0);
+    assert_eq!(simulator.requested, 0);
+  }
+
+  #[test]
+  fn shrinking_realloc_keeps_the_slot_down_to_half_its_class() {
+    let classes = TableParams::CURRENT.classes().unwrap();
+    let mut simulator = Simulator::new(classes, SEGMENT_SIZE, 256);
+    let class = find_class(1000, 8).unwrap();
+    let class_of = |simulator: &Simulator| match simulator.blocks[0] {
+      Some(Block {
+        placement: Placement::Slot { class, .. },
+        ..
+      }) => class,
+      _ => panic!("block left its slot"),
+    };
+
+    simulator.alloc(0, class.size.0, 8);
+    simulator.realloc(0, class.size.0 / 2);
+    assert_eq!(class_of(&simulator), class.id);
+    assert_eq!(simulator.requested, class.size.0 / 2);
+
+    simulator.realloc(0, class.size.0 / 4);
+    let smaller = find_class(class.size.0 / 4, 8).unwrap();
+    assert_eq!(class_of(&simulator), smaller.id);
+
+    simulator.free(0);
+    assert_eq!(simulator.memory, 0);
+  }
+}
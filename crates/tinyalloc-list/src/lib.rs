@@ -54,6 +54,15 @@ where
   }
 }
 
+impl<T> Default for Link<T>
+where
+  T: HasLink<T>,
+{
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 pub trait HasLink<T>
 where
   T: HasLink<T>,
@@ -274,6 +283,15 @@ where
   }
 }
 
+impl<T> Default for List<T>
+where
+  T: HasLink<T>,
+{
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 pub struct Iter<'list, T>
 where
   T: HasLink<T>,
@@ -0,0 +1,101 @@
+use std::sync::atomic::{
+  AtomicUsize,
+  Ordering,
+};
+
+/// Callback run on a memory fault with the faulting address. Returns true
+/// when it recognised and reported the fault.
+pub type FaultHandler = fn(usize) -> bool;
+
+static HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(unix)]
+mod unix {
+  use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+  };
+
+  pub const SIGNALS: [libc::c_int; 2] = [libc::SIGSEGV, libc::SIGBUS];
+
+  /// Actions that were installed before ours, one per entry of `SIGNALS`.
+  /// Only written while ours is not installed, so never as it runs.
+  pub struct Previous(pub UnsafeCell<[MaybeUninit<libc::sigaction>; 2]>);
+
+  unsafe impl Sync for Previous {}
+
+  pub static PREVIOUS: Previous =
+    Previous(UnsafeCell::new([MaybeUninit::zeroed(); 2]));
+}
+
+/// Routes segmentation faults to `handler`. Faults it does not recognise
+/// go to whichever handler was installed before, and recognised ones to
+/// the default action once reported, so the process still dies with the
+/// original signal. Calling it again reinstalls the handler if other code,
+/// such as the Rust runtime starting up, has since replaced it. Returns
+/// false if the handler could not be installed.
+#[cfg(unix)]
+pub fn install(handler: FaultHandler) -> bool {
+  HANDLER.store(handler as usize, Ordering::Release);
+
+  let previous = unix::PREVIOUS.0.get() as *mut libc::sigaction;
+  unix::SIGNALS
+    .iter()
+    .enumerate()
+    .all(|(index, &signal)| unsafe {
+      let mut current: libc::sigaction = core::mem::zeroed();
+      if libc::sigaction(signal, core::ptr::null(), &mut current) != 0 {
+        return false;
+      }
+      if current.sa_sigaction == on_fault as *const () as usize {
+        return true;
+      }
+
+      previous.add(index).write(current);
+      let mut action: libc::sigaction = core::mem::zeroed();
+      action.sa_sigaction = on_fault as *const () as usize;
+      action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+      libc::sigemptyset(&mut action.sa_mask);
+      libc::sigaction(signal, &action, core::ptr::null_mut()) == 0
+    })
+}
+
+#[cfg(not(unix))]
+pub fn install(handler: FaultHandler) -> bool {
+  _ = handler;
+  false
+}
+
+#[cfg(unix)]
+extern "C" fn on_fault(
+  signal: libc::c_int,
+  info: *mut libc::siginfo_t,
+  _context: *mut libc::c_void,
+) {
+  #[cfg(any(target_os = "linux", target_os = "android"))]
+  let address = unsafe { (*info).si_addr() } as usize;
+  #[cfg(not(any(target_os = "linux", target_os = "android")))]
+  let address = unsafe { (*info).si_addr } as usize;
+
+  let handler = HANDLER.load(Ordering::Acquire);
+  let handled = handler != 0 && {
+    let handler: FaultHandler = unsafe { core::mem::transmute(handler) };
+    handler(address)
+  };
+
+  // Returning retries the faulting access, which now reaches the restored
+  // action.
+  let Some(index) = unix::SIGNALS.iter().position(|&s| s == signal) else {
+    return;
+  };
+  unsafe {
+    if handled {
+      let mut action: libc::sigaction = core::mem::zeroed();
+      action.sa_sigaction = libc::SIG_DFL;
+      libc::sigaction(signal, &action, core::ptr::null_mut());
+    } else {
+      let previous = unix::PREVIOUS.0.get() as *const libc::sigaction;
+      libc::sigaction(signal, previous.add(index), core::ptr::null_mut());
+    }
+  }
+}
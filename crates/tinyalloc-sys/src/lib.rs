@@ -4,10 +4,13 @@ use crate::posix::PosixMapper;
 #[cfg(windows)]
 use crate::windows::WindowsMapper;
 
+pub mod fault;
 pub mod mapper;
 pub mod posix;
+pub mod process;
 pub mod region;
 pub mod size;
+pub mod thread;
 pub mod windows;
 
 #[cfg(unix)]
@@ -25,4 +28,5 @@ pub enum MapError {
   CommitFailed,
   DecommitFailed,
   ProtectFailed,
+  RemapFailed,
 }
@@ -36,6 +36,15 @@ where
   fn unmap(&self, ptr: NonNull<[u8]>) {
     _ = ptr;
   }
+  fn remap(
+    &self,
+    ptr: NonNull<[u8]>,
+    size: NonZeroUsize,
+    may_move: bool,
+  ) -> Result<NonNull<[u8]>, MapError> {
+    _ = (ptr, size, may_move);
+    Err(MapError::RemapFailed)
+  }
   fn decommit(&self, ptr: NonNull<[u8]>) -> Result<(), MapError> {
     _ = ptr;
     Ok(())
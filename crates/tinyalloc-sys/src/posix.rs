@@ -83,6 +83,33 @@ impl Mapper for PosixMapper {
     unsafe { libc::munmap(ptr.as_ptr() as *mut libc::c_void, size) };
   }
 
+  #[cfg(any(target_os = "linux", target_os = "android"))]
+  fn remap(
+    &self,
+    ptr: NonNull<[u8]>,
+    size: NonZeroUsize,
+    may_move: bool,
+  ) -> Result<NonNull<[u8]>, MapError> {
+    let aligned_size = page_align(size.get());
+    let flags = if may_move { libc::MREMAP_MAYMOVE } else { 0 };
+    let res = unsafe {
+      libc::mremap(
+        self.cptr(ptr.as_ptr() as *mut u8),
+        ptr.len(),
+        aligned_size,
+        flags,
+      )
+    };
+
+    if res == libc::MAP_FAILED {
+      return Err(MapError::RemapFailed);
+    }
+
+    let slice =
+      unsafe { std::slice::from_raw_parts_mut(res as *mut u8, aligned_size) };
+    Ok(NonNull::new(slice).unwrap())
+  }
+
   fn decommit(&self, ptr: NonNull<[u8]>) -> Result<(), MapError> {
     let aligned_slice = page_align_slice(ptr);
     let cptr = self.cptr(aligned_slice.as_ptr() as *mut u8);
@@ -189,6 +216,29 @@ mod tests {
     GLOBAL_MAPPER.unmap(ptr);
   }
 
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn test_remap_in_place_and_moving() {
+    let page = crate::size::page_size();
+    let ptr = GLOBAL_MAPPER.map(NonZero::new(page).unwrap()).unwrap();
+    GLOBAL_MAPPER.protect(ptr, EnumSet::all()).unwrap();
+    unsafe { (ptr.as_ptr() as *mut u8).write(0x5A) };
+
+    let grown = GLOBAL_MAPPER
+      .remap(ptr, NonZero::new(64 * page).unwrap(), true)
+      .unwrap();
+    assert_eq!(grown.len(), 64 * page);
+    assert_eq!(unsafe { (grown.as_ptr() as *mut u8).read() }, 0x5A);
+
+    let shrunk = GLOBAL_MAPPER
+      .remap(grown, NonZero::new(page).unwrap(), false)
+      .unwrap();
+    assert_eq!(shrunk.as_ptr() as *mut u8, grown.as_ptr() as *mut u8);
+    assert_eq!(shrunk.len(), page);
+
+    GLOBAL_MAPPER.unmap(shrunk);
+  }
+
   #[test]
   fn test_large_allocation() {
     let size = 1024 * 1024;
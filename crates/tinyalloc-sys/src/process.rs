@@ -0,0 +1,40 @@
+use std::ffi::{
+  CStr,
+  c_int,
+};
+
+/// Runs `f` when the process exits normally, after `main` returns or on
+/// `exit`. Returns false if the handler could not be registered.
+pub fn at_exit(f: extern "C" fn()) -> bool {
+  unsafe { libc::atexit(f) == 0 }
+}
+
+/// Ends the process with `code` right away, skipping any remaining exit
+/// handlers. Safe to call from within an exit handler.
+pub fn exit_now(code: c_int) -> ! {
+  unsafe { libc::_exit(code) }
+}
+
+/// Reads an environment variable without allocating, so it can be used
+/// while the allocator itself is starting up.
+pub fn env(name: &CStr) -> Option<&'static CStr> {
+  let value = unsafe { libc::getenv(name.as_ptr()) };
+  if value.is_null() {
+    return None;
+  }
+  Some(unsafe { CStr::from_ptr(value) })
+}
+
+/// Writes `bytes` straight to the standard error descriptor. Unlike
+/// `std::io::stderr` this takes no lock, so it is safe in signal handlers.
+pub fn write_stderr(bytes: &[u8]) {
+  #[cfg(unix)]
+  unsafe {
+    libc::write(2, bytes.as_ptr().cast(), bytes.len());
+  }
+  #[cfg(not(unix))]
+  {
+    use std::io::Write;
+    let _ = std::io::stderr().write_all(bytes);
+  }
+}
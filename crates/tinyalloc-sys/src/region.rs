@@ -66,6 +66,24 @@ impl Region {
     Ok(())
   }
 
+  /// Resizes the mapping and returns its new extent without touching `self`,
+  /// which may live inside the mapping being moved. Callers record the result
+  /// through [`Region::rebind`] at the region's new location.
+  pub fn remap(
+    &self,
+    size: NonZeroUsize,
+    may_move: bool,
+  ) -> Result<NonNull<[u8]>, MapError> {
+    self.mapper.remap(self.data, size, may_move)
+  }
+
+  /// # Safety
+  ///
+  /// `data` must be the extent returned by [`Region::remap`] for this region.
+  pub unsafe fn rebind(&mut self, data: NonNull<[u8]>) {
+    self.data = data;
+  }
+
   pub fn as_mut(&mut self) -> Option<&mut [u8]> {
     if self.activate {
       Some(unsafe { self.data.as_mut() })
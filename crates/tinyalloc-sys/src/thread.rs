@@ -0,0 +1,15 @@
+#[cfg(windows)]
+use windows_sys::Win32::System::Threading::GetCurrentThreadId;
+
+/// The OS identifier of the calling thread. Unlike `std::thread::current`
+/// this never allocates, so it is safe to call from inside the allocator.
+pub fn current_id() -> u64 {
+  #[cfg(target_os = "linux")]
+  let id = unsafe { libc::gettid() } as u64;
+  #[cfg(all(unix, not(target_os = "linux")))]
+  let id = unsafe { libc::pthread_self() } as u64;
+  #[cfg(windows)]
+  let id = unsafe { GetCurrentThreadId() } as u64;
+
+  id
+}
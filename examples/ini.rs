@@ -180,10 +180,10 @@ fn main() -> io::Result<()> {
         println!("Global property 'global_key': {}", value);
       }
 
-      if let Some(section) = parser.get_section("section1") {
-        if let Some(value) = section.get_property("key1") {
-          println!("Section 'section1', property 'key1': {}", value);
-        }
+      if let Some(section) = parser.get_section("section1")
+        && let Some(value) = section.get_property("key1")
+      {
+        println!("Section 'section1', property 'key1': {}", value);
       }
     }
     Err(error) => {
@@ -16,7 +16,7 @@ fn main() {
     // Do many allocations to see if we reuse segments
     let mut ptrs = Vec::new();
     for i in 1..=1000 {
-        let ptr = queue.allocate();
+        let ptr = queue.allocate(std::ptr::null_mut());
         if let Some(p) = ptr {
             ptrs.push(p);
         }
@@ -13,8 +13,7 @@ fn main() {
 
     // Show first 10 size classes
     println!("\nFirst 10 size classes:");
-    for i in 0..10 {
-        let c = &CLASSES[i];
+    for (i, c) in CLASSES.iter().take(10).enumerate() {
         println!("  Class {}: size={}, align={}", i, c.size.0, c.align.0);
     }
 }
\ No newline at end of file
@@ -0,0 +1,420 @@
+use std::{
+  collections::HashMap,
+  fmt,
+  io::{
+    self,
+    BufRead,
+  },
+};
+
+use tinyalloc::{
+  EventKind,
+  TRACE_MAGIC,
+  TraceReader,
+};
+
+/// Alignment of blocks in a text trace that name none, as `malloc` gives.
+const DEFAULT_ALIGN: usize = 16;
+
+/// One allocator call to replay. Blocks are named by slot, an index that
+/// stays with a block across reallocs, and carry the layout the allocator
+/// must be given back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+  Alloc {
+    slot: usize,
+    size: usize,
+    align: usize,
+    zeroed: bool,
+  },
+  Free {
+    slot: usize,
+    size: usize,
+    align: usize,
+  },
+  Realloc {
+    slot: usize,
+    size: usize,
+    align: usize,
+    new_size: usize,
+  },
+}
+
+impl Op {
+  pub fn slot(&self) -> usize {
+    match *self {
+      Op::Alloc { slot, .. }
+      | Op::Free { slot, .. }
+      | Op::Realloc { slot, .. } => slot,
+    }
+  }
+}
+
+/// An op and its turn on its slot: the ops on a slot run in trace order
+/// whichever thread makes them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Step {
+  pub op: Op,
+  pub turn: u32,
+}
+
+/// A trace split into the ops each thread makes, in order.
+#[derive(Debug, Default)]
+pub struct Plan {
+  pub threads: Vec<Vec<Step>>,
+  pub slots: usize,
+  /// Most bytes live at once, as requested.
+  pub peak_live: usize,
+  /// Events that named a block that was not live, or a failed call.
+  pub skipped: usize,
+  /// Blocks the trace never frees, freed once a replay is done.
+  pub leftover: Vec<Block>,
+}
+
+impl Plan {
+  pub fn ops(&self) -> usize {
+    self.threads.iter().map(Vec::len).sum()
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Block {
+  pub slot: usize,
+  pub size: usize,
+  pub align: usize,
+}
+
+/// Builds a [`Plan`] from calls given in trace order, with threads and
+/// blocks named by whatever keys the trace uses.
+struct Builder {
+  serial: bool,
+  threads: HashMap<u64, usize>,
+  live: HashMap<u64, Block>,
+  turns: Vec<u32>,
+  live_bytes: usize,
+  plan: Plan,
+}
+
+impl Builder {
+  fn new(serial: bool) -> Self {
+    Self {
+      serial,
+      threads: HashMap::new(),
+      live: HashMap::new(),
+      turns: Vec::new(),
+      live_bytes: 0,
+      plan: Plan::default(),
+    }
+  }
+
+  fn push(&mut self, thread: u64, op: Op) {
+    let thread = if self.serial { 0 } else { thread };
+    let next = self.threads.len();
+    let index = *self.threads.entry(thread).or_insert(next);
+    if index == self.plan.threads.len() {
+      self.plan.threads.push(Vec::new());
+    }
+
+    let turn = &mut self.turns[op.slot()];
+    self.plan.threads[index].push(Step { op, turn: *turn });
+    *turn += 1;
+  }
+
+  fn alloc(
+    &mut self,
+    thread: u64,
+    key: u64,
+    size: usize,
+    align: usize,
+    zeroed: bool,
+  ) {
+    // A block whose free was lost, or that a racing thread's batch of
+    // events put after its reuse. The trace never frees it.
+    if let Some(orphan) = self.live.remove(&key) {
+      self.plan.leftover.push(orphan);
+      self.plan.skipped += 1;
+    }
+    let slot = self.turns.len();
+    self.turns.push(0);
+    self.live.insert(key, Block { slot, size, align });
+    self.grow(size, 0);
+    self.push(
+      thread,
+      Op::Alloc {
+        slot,
+        size,
+        align,
+        zeroed,
+      },
+    );
+  }
+
+  fn free(&mut self, thread: u64, key: u64) {
+    let Some(block) = self.live.remove(&key) else {
+      self.plan.skipped += 1;
+      return;
+    };
+    self.live_bytes -= block.size;
+    self.push(
+      thread,
+      Op::Free {
+        slot: block.slot,
+        size: block.size,
+        align: block.align,
+      },
+    );
+  }
+
+  fn realloc(&mut self, thread: u64, key: u64, new_key: u64, new_size: usize) {
+    let Some(block) = self.live.remove(&key) else {
+      self.plan.skipped += 1;
+      return;
+    };
+    self.live.insert(
+      new_key,
+      Block {
+        size: new_size,
+        ..block
+      },
+    );
+    self.grow(new_size, block.size);
+    self.push(
+      thread,
+      Op::Realloc {
+        slot: block.slot,
+        size: block.size,
+        align: block.align,
+        new_size,
+      },
+    );
+  }
+
+  fn grow(&mut self, size: usize, old: usize) {
+    self.live_bytes = self.live_bytes - old + size;
+    self.plan.peak_live = self.plan.peak_live.max(self.live_bytes);
+  }
+
+  fn finish(mut self) -> Plan {
+    self.plan.slots = self.turns.len();
+    self.plan.leftover.extend(self.live.into_values());
+    self.plan
+  }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+  Io(io::Error),
+  Parse { line: usize, message: &'static str },
+}
+
+impl From<io::Error> for LoadError {
+  fn from(error: io::Error) -> Self {
+    LoadError::Io(error)
+  }
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LoadError::Io(error) => write!(f, "{error}"),
+      LoadError::Parse { line, message } => write!(f, "line {line}: {message}"),
+    }
+  }
+}
+
+/// Reads a trace in either format, telling them apart by the binary
+/// trace's magic. With `serial` every op is put on one thread, in trace
+/// order.
+pub fn load(mut input: impl BufRead, serial: bool) -> Result<Plan, LoadError> {
+  if input.fill_buf()?.starts_with(&TRACE_MAGIC) {
+    load_binary(input, serial)
+  } else {
+    load_text(input, serial)
+  }
+}
+
+/// Reads tinyalloc's own binary trace. Threads write their events out in
+/// batches, so the events are put back in time order first.
+fn load_binary(input: impl BufRead, serial: bool) -> Result<Plan, LoadError> {
+  let mut events = TraceReader::new(input)?.collect::<io::Result<Vec<_>>>()?;
+  events.sort_by_key(|event| event.time);
+
+  let mut builder = Builder::new(serial);
+  for event in events {
+    let size = event.size as usize;
+    let align = event.align as usize;
+    if event.ptr == 0 {
+      builder.plan.skipped += 1;
+      continue;
+    }
+    match event.kind {
+      EventKind::Alloc | EventKind::AllocZeroed => builder.alloc(
+        event.thread,
+        event.ptr,
+        size,
+        align,
+        event.kind == EventKind::AllocZeroed,
+      ),
+      EventKind::Dealloc => builder.free(event.thread, event.ptr),
+      EventKind::Realloc => {
+        builder.realloc(event.thread, event.old, event.ptr, size)
+      }
+    }
+  }
+  Ok(builder.finish())
+}
+
+fn number(token: Option<&str>) -> Option<u64> {
+  let token = token?;
+  match token.strip_prefix("0x") {
+    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+    None => token.parse().ok(),
+  }
+}
+
+/// Reads the text format, one call per line. See the usage text in
+/// `main.rs` for its grammar.
+fn load_text(input: impl BufRead, serial: bool) -> Result<Plan, LoadError> {
+  let mut builder = Builder::new(serial);
+  for (index, line) in input.lines().enumerate() {
+    let line = line?;
+    let error = |message| LoadError::Parse {
+      line: index + 1,
+      message,
+    };
+    let line = line.split('#').next().unwrap_or_default();
+    let mut tokens = line.split_whitespace();
+    let Some(first) = tokens.next() else {
+      continue;
+    };
+
+    let thread = number(Some(first)).ok_or(error("bad thread"))?;
+    let call = tokens.next().ok_or(error("missing call"))?;
+    let id = number(tokens.next()).ok_or(error("bad block id"))?;
+    match call {
+      "alloc" | "calloc" => {
+        let size = number(tokens.next()).ok_or(error("bad size"))? as usize;
+        let align = match tokens.next() {
+          Some(token) => number(Some(token))
+            .map(|align| align as usize)
+            .filter(|align| align.is_power_of_two())
+            .ok_or(error("bad alignment"))?,
+          None => DEFAULT_ALIGN,
+        };
+        builder.alloc(thread, id, size, align, call == "calloc");
+      }
+      "free" => builder.free(thread, id),
+      "realloc" => {
+        let size = number(tokens.next()).ok_or(error("bad size"))? as usize;
+        builder.realloc(thread, id, id, size);
+      }
+      _ => return Err(error("unknown call")),
+    }
+    if tokens.next().is_some() {
+      return Err(error("trailing input"));
+    }
+  }
+  Ok(builder.finish())
+}
+
+#[cfg(test)]
+mod tests {
+  use tinyalloc::{
+    Event,
+    EventPath,
+  };
+
+  use super::*;
+
+  #[test]
+  fn text_traces_keep_per_thread_order_and_cross_thread_frees() {
+    let trace = "\
+      # thread call id size [align]
+      1 alloc 10 100
+      2 calloc 0x20 64 64
+      1 realloc 10 300
+      2 free 10   # freed by another thread
+      1 free 99
+    ";
+    let plan = load(trace.as_bytes(), false).unwrap();
+
+    assert_eq!(plan.threads.len(), 2);
+    assert_eq!(plan.slots, 2);
+    assert_eq!(plan.peak_live, 364);
+    assert_eq!(plan.skipped, 1);
+    assert_eq!(
+      plan.threads[1],
+      vec![
+        Step {
+          op: Op::Alloc {
+            slot: 1,
+            size: 64,
+            align: 64,
+            zeroed: true,
+          },
+          turn: 0,
+        },
+        Step {
+          op: Op::Free {
+            slot: 0,
+            size: 300,
+            align: DEFAULT_ALIGN,
+          },
+          turn: 2,
+        },
+      ]
+    );
+
+    let serial = load(trace.as_bytes(), true).unwrap();
+    assert_eq!(serial.threads.len(), 1);
+    assert_eq!(serial.ops(), 4);
+  }
+
+  #[test]
+  fn text_traces_report_the_bad_line() {
+    match load("1 alloc 1 8\n1 alloc 2 8 3\n".as_bytes(), false) {
+      Err(LoadError::Parse { line, .. }) => assert_eq!(line, 2),
+      other => panic!("expected a parse error, got {other:?}"),
+    }
+  }
+
+  #[test]
+  fn binary_traces_are_replayed_in_time_order() {
+    let event = |time, thread, kind, ptr, old, size| Event {
+      time,
+      thread,
+      kind,
+      path: EventPath::Unknown,
+      ptr,
+      old,
+      size,
+      align: 8,
+    };
+    // Thread 2's batch was written out before thread 1's.
+    let events = [
+      event(3, 2, EventKind::Dealloc, 0x2000, 0, 48),
+      event(1, 1, EventKind::Alloc, 0x1000, 0, 32),
+      event(2, 1, EventKind::Realloc, 0x2000, 0x1000, 48),
+    ];
+    let mut bytes = TRACE_MAGIC.to_vec();
+    for event in &events {
+      bytes.extend_from_slice(&event.encode());
+    }
+
+    let plan = load(bytes.as_slice(), false).unwrap();
+    assert_eq!(plan.skipped, 0);
+    assert_eq!(plan.threads.len(), 2);
+    assert_eq!(plan.threads[0].len(), 2);
+    assert_eq!(
+      plan.threads[1],
+      vec![Step {
+        op: Op::Free {
+          slot: 0,
+          size: 48,
+          align: 8,
+        },
+        turn: 2,
+      }]
+    );
+  }
+}
@@ -0,0 +1,256 @@
+use std::{
+  alloc::{
+    GlobalAlloc,
+    System,
+  },
+  fs::File,
+  io::BufReader,
+  process::ExitCode,
+};
+
+use tinyalloc::TinyAlloc;
+use tinyalloc_config::simulate::TableParams;
+
+use crate::{
+  load::{
+    Plan,
+    load,
+  },
+  replay::{
+    Outcome,
+    replay,
+  },
+  simulate::parse_table,
+};
+
+mod load;
+mod replay;
+mod simulate;
+
+/// Allocators a trace can be replayed against. "system" is whatever
+/// `malloc` the binary links, so build it without the `ffi` feature.
+static ALLOCATORS: &[(&str, &(dyn GlobalAlloc + Sync))] =
+  &[("tinyalloc", &TinyAlloc), ("system", &System)];
+
+const USAGE: &str = "\
+usage: tinyalloc-replay [--allocator NAME]... [--serial] TRACE
+       tinyalloc-replay --simulate [--table SPEC]... TRACE
+
+Replays an allocation trace and reports wall time, peak resident memory,
+fragmentation and per-operation latency percentiles.
+
+With --simulate nothing is allocated. The trace is played, in order,
+against the current size-class table and any candidate tables, as if
+from one heap. Each table gets its peak memory against the peak live
+bytes. Each class gets its internal fragmentation, objects per segment,
+how much of a segment its slots use, and its peak segment count.
+
+TRACE is either a binary trace written with TINYALLOC_TRACE or a text
+trace with one call per line:
+
+  <thread> alloc <id> <size> [<align>]
+  <thread> calloc <id> <size> [<align>]
+  <thread> realloc <id> <size>
+  <thread> free <id>
+
+Threads and block ids are integers, decimal or 0x-prefixed hex, and
+alignment defaults to 16. A block keeps its id across reallocs and may
+be freed by any thread. Text after # is ignored.
+
+Each trace thread is replayed on a thread of its own, in order. An op on
+a block waits for the ops before it on that block, so cross-thread frees
+happen after the allocation they free.
+
+options:
+  --allocator NAME  replay against NAME, repeatable (default: all)
+  --serial          replay every op on one thread, in trace order
+  --simulate        simulate size-class tables instead of replaying
+  --table SPEC      also simulate a candidate table, repeatable, given
+                    as small=N,medium=N,large=N,spacing=A:B:C with any
+                    key left out keeping its current value
+";
+
+struct Args {
+  allocators: Vec<(&'static str, &'static (dyn GlobalAlloc + Sync))>,
+  serial: bool,
+  simulate: bool,
+  tables: Vec<(String, TableParams)>,
+  trace: String,
+}
+
+fn parse_args() -> Result<Args, String> {
+  let mut allocators = Vec::new();
+  let mut serial = false;
+  let mut simulate = false;
+  let mut tables = Vec::new();
+  let mut trace = None;
+  let mut args = std::env::args().skip(1);
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--allocator" => {
+        let name = args.next().ok_or("--allocator needs a name")?;
+        let allocator = ALLOCATORS
+          .iter()
+          .find(|(known, _)| *known == name)
+          .ok_or_else(|| format!("unknown allocator {name}"))?;
+        allocators.push(*allocator);
+      }
+      "--serial" => serial = true,
+      "--simulate" => simulate = true,
+      "--table" => {
+        let spec = args.next().ok_or("--table needs a spec")?;
+        tables.push((spec.clone(), parse_table(&spec)?));
+      }
+      "-h" | "--help" => return Err(String::new()),
+      _ if trace.is_none() && !arg.starts_with('-') => trace = Some(arg),
+      _ => return Err(format!("unexpected argument {arg}")),
+    }
+  }
+  if allocators.is_empty() {
+    allocators.extend_from_slice(ALLOCATORS);
+  }
+  if !tables.is_empty() && !simulate {
+    return Err("--table needs --simulate".to_string());
+  }
+  Ok(Args {
+    allocators,
+    serial,
+    simulate,
+    tables,
+    trace: trace.ok_or("missing trace file")?,
+  })
+}
+
+pub fn mib(bytes: usize) -> f64 {
+  bytes as f64 / (1024.0 * 1024.0)
+}
+
+/// The sample below which `fraction` of `sorted` falls.
+fn percentile(sorted: &[u32], fraction: f64) -> u32 {
+  let index = ((sorted.len() as f64 * fraction).ceil() as usize).max(1) - 1;
+  sorted[index.min(sorted.len() - 1)]
+}
+
+fn report(name: &str, plan: &Plan, outcome: &mut Outcome) {
+  println!(
+    "{name}: {} threads, {} ops, {:.3} ms",
+    plan.threads.len(),
+    plan.ops(),
+    outcome.wall.as_secs_f64() * 1000.0
+  );
+  match outcome.peak_rss {
+    Some(rss) => println!(
+      "  peak live {:.2} MiB, peak rss +{:.2} MiB, fragmentation {:.2}x",
+      mib(plan.peak_live),
+      mib(rss),
+      rss as f64 / plan.peak_live.max(1) as f64
+    ),
+    None => println!("  peak live {:.2} MiB", mib(plan.peak_live)),
+  }
+  if outcome.failed > 0 {
+    println!("  {} calls failed", outcome.failed);
+  }
+
+  println!(
+    "  {:<8} {:>10} {:>8} {:>8} {:>8} {:>8} {:>10}",
+    "op", "count", "p50", "p90", "p99", "p99.9", "max ns"
+  );
+  let latencies = &mut outcome.latencies;
+  for (op, samples) in [
+    ("alloc", &mut latencies.alloc),
+    ("free", &mut latencies.free),
+    ("realloc", &mut latencies.realloc),
+  ] {
+    if samples.is_empty() {
+      continue;
+    }
+    samples.sort_unstable();
+    println!(
+      "  {:<8} {:>10} {:>8} {:>8} {:>8} {:>8} {:>10}",
+      op,
+      samples.len(),
+      percentile(samples, 0.5),
+      percentile(samples, 0.9),
+      percentile(samples, 0.99),
+      percentile(samples, 0.999),
+      samples[samples.len() - 1]
+    );
+  }
+}
+
+fn main() -> ExitCode {
+  let args = match parse_args() {
+    Ok(args) => args,
+    Err(message) => {
+      if !message.is_empty() {
+        eprintln!("tinyalloc-replay: {message}");
+      }
+      eprint!("{USAGE}");
+      return ExitCode::FAILURE;
+    }
+  };
+
+  let plan = match File::open(&args.trace)
+    .map_err(Into::into)
+    .and_then(|file| load(BufReader::new(file), args.serial || args.simulate))
+  {
+    Ok(plan) => plan,
+    Err(error) => {
+      eprintln!("tinyalloc-replay: {}: {error}", args.trace);
+      return ExitCode::FAILURE;
+    }
+  };
+  if plan.skipped > 0 {
+    println!(
+      "{} events named a block that was not live and were skipped",
+      plan.skipped
+    );
+  }
+
+  if args.simulate {
+    simulate::report(&plan, &args.tables);
+    return ExitCode::SUCCESS;
+  }
+  for (name, allocator) in args.allocators {
+    let mut outcome = replay(&plan, allocator);
+    report(name, &plan, &mut outcome);
+  }
+  ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn replays_cross_thread_traces_against_every_allocator() {
+    let mut trace = String::new();
+    for id in 0..2000 {
+      let size = 1 + id * 37 % 5000;
+      trace += &format!("{} alloc {id} {size}\n", id % 4);
+      if id % 3 == 0 {
+        trace += &format!("{} realloc {id} {}\n", id % 4, size * 2);
+      }
+      trace += &format!("{} free {id}\n", (id + 1) % 4);
+    }
+    trace += "0 calloc 5000 100000 4096\n";
+    let plan = load(trace.as_bytes(), false).unwrap();
+
+    for (_, allocator) in ALLOCATORS {
+      let outcome = replay(&plan, *allocator);
+      assert_eq!(outcome.failed, 0);
+      assert_eq!(outcome.latencies.alloc.len(), 2001);
+      assert_eq!(outcome.latencies.realloc.len(), 667);
+      assert_eq!(outcome.latencies.free.len(), 2000);
+    }
+  }
+
+  #[test]
+  fn percentiles_pick_the_sample_at_or_above_the_fraction() {
+    let sorted: Vec<u32> = (1..=1000).collect();
+    assert_eq!(percentile(&sorted, 0.5), 500);
+    assert_eq!(percentile(&sorted, 0.999), 999);
+    assert_eq!(percentile(&sorted, 1.0), 1000);
+    assert_eq!(percentile(&[7], 0.99), 7);
+  }
+}
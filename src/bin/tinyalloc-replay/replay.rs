@@ -0,0 +1,267 @@
+use std::{
+  alloc::{
+    GlobalAlloc,
+    Layout,
+  },
+  fs,
+  sync::{
+    Barrier,
+    atomic::{
+      AtomicBool,
+      AtomicU32,
+      AtomicUsize,
+      Ordering,
+    },
+  },
+  thread,
+  time::{
+    Duration,
+    Instant,
+  },
+};
+
+use tinyalloc_sys::size::page_size;
+
+use crate::load::{
+  Op,
+  Plan,
+  Step,
+};
+
+/// How often resident memory is sampled during a replay.
+const RSS_PERIOD: Duration = Duration::from_millis(1);
+
+/// A block being replayed. `turn` counts the ops made on it so far.
+struct Slot {
+  ptr: AtomicUsize,
+  turn: AtomicU32,
+}
+
+/// Nanoseconds each op took, by kind.
+#[derive(Default)]
+pub struct Latencies {
+  pub alloc: Vec<u32>,
+  pub free: Vec<u32>,
+  pub realloc: Vec<u32>,
+}
+
+impl Latencies {
+  fn with_capacity(steps: &[Step]) -> Self {
+    let count = |kind: fn(&Op) -> bool| {
+      steps.iter().filter(|step| kind(&step.op)).count()
+    };
+    Self {
+      alloc: Vec::with_capacity(count(|op| matches!(op, Op::Alloc { .. }))),
+      free: Vec::with_capacity(count(|op| matches!(op, Op::Free { .. }))),
+      realloc: Vec::with_capacity(count(|op| matches!(op, Op::Realloc { .. }))),
+    }
+  }
+
+  /// Touches every page the samples will be written to, so they count
+  /// towards the baseline rather than the replay's resident memory.
+  fn prefault(&mut self) {
+    for samples in [&mut self.alloc, &mut self.free, &mut self.realloc] {
+      samples.resize(samples.capacity(), u32::MAX);
+      samples.clear();
+    }
+  }
+
+  fn merge(&mut self, other: Latencies) {
+    self.alloc.extend(other.alloc);
+    self.free.extend(other.free);
+    self.realloc.extend(other.realloc);
+  }
+}
+
+pub struct Outcome {
+  pub wall: Duration,
+  /// Growth of resident memory at its peak, where it can be measured.
+  pub peak_rss: Option<usize>,
+  pub latencies: Latencies,
+  /// Calls the allocator failed, and ops on the blocks they left behind.
+  pub failed: usize,
+}
+
+/// Resident bytes of this process, on systems that report them.
+fn resident() -> Option<usize> {
+  let statm = fs::read_to_string("/proc/self/statm").ok()?;
+  let pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+  Some(pages * page_size())
+}
+
+/// Writes a byte to every page of `ptr[from..to]`, as a program would
+/// before using fresh memory, so that it becomes resident.
+fn touch(ptr: *mut u8, from: usize, to: usize) {
+  let page = page_size();
+  let mut offset = from.next_multiple_of(page);
+  if from < to && offset != from {
+    unsafe { ptr.add(from).write_volatile(1) };
+  }
+  while offset < to {
+    unsafe { ptr.add(offset).write_volatile(1) };
+    offset += page;
+  }
+}
+
+fn layout(size: usize, align: usize) -> Layout {
+  // `GlobalAlloc` takes no zero-sized requests.
+  Layout::from_size_align(size.max(1), align).unwrap_or(Layout::new::<u8>())
+}
+
+fn elapsed(start: Instant) -> u32 {
+  start.elapsed().as_nanos().try_into().unwrap_or(u32::MAX)
+}
+
+/// Makes one thread's ops, each once every earlier op on its block has
+/// been made, and returns their latencies and how many failed.
+fn run(
+  allocator: &dyn GlobalAlloc,
+  steps: &[Step],
+  slots: &[Slot],
+  mut latencies: Latencies,
+) -> (Latencies, usize) {
+  let mut failed = 0;
+  for step in steps {
+    let slot = &slots[step.op.slot()];
+    while slot.turn.load(Ordering::Acquire) != step.turn {
+      thread::yield_now();
+    }
+    let ptr = slot.ptr.load(Ordering::Relaxed) as *mut u8;
+
+    let new_ptr = match step.op {
+      Op::Alloc {
+        size,
+        align,
+        zeroed,
+        ..
+      } => {
+        let layout = layout(size, align);
+        let start = Instant::now();
+        let new_ptr = unsafe {
+          if zeroed {
+            allocator.alloc_zeroed(layout)
+          } else {
+            allocator.alloc(layout)
+          }
+        };
+        latencies.alloc.push(elapsed(start));
+        if !new_ptr.is_null() {
+          touch(new_ptr, 0, size);
+        }
+        new_ptr
+      }
+      Op::Free { size, align, .. } => {
+        if !ptr.is_null() {
+          let start = Instant::now();
+          unsafe { allocator.dealloc(ptr, layout(size, align)) };
+          latencies.free.push(elapsed(start));
+        }
+        std::ptr::null_mut()
+      }
+      Op::Realloc {
+        size,
+        align,
+        new_size,
+        ..
+      } => {
+        if ptr.is_null() {
+          std::ptr::null_mut()
+        } else {
+          let start = Instant::now();
+          let new_ptr = unsafe {
+            allocator.realloc(ptr, layout(size, align), new_size.max(1))
+          };
+          latencies.realloc.push(elapsed(start));
+          if new_ptr.is_null() {
+            // The old block is still live; free it so nothing leaks.
+            unsafe { allocator.dealloc(ptr, layout(size, align)) };
+          } else {
+            touch(new_ptr, size, new_size);
+          }
+          new_ptr
+        }
+      }
+    };
+    if new_ptr.is_null() && !matches!(step.op, Op::Free { .. }) {
+      failed += 1;
+    }
+
+    slot.ptr.store(new_ptr as usize, Ordering::Relaxed);
+    slot.turn.store(step.turn + 1, Ordering::Release);
+  }
+  (latencies, failed)
+}
+
+/// Replays `plan` against `allocator`, one thread per trace thread, and
+/// frees whatever the trace left live once it is done.
+pub fn replay(plan: &Plan, allocator: &(dyn GlobalAlloc + Sync)) -> Outcome {
+  let slots: Vec<Slot> = (0..plan.slots)
+    .map(|_| Slot {
+      ptr: AtomicUsize::new(0),
+      turn: AtomicU32::new(0),
+    })
+    .collect();
+  let ready = Barrier::new(plan.threads.len() + 1);
+  let go = Barrier::new(plan.threads.len() + 1);
+  let done = AtomicBool::new(false);
+
+  let (wall, peak_rss, latencies, failed) = thread::scope(|scope| {
+    let workers: Vec<_> = plan
+      .threads
+      .iter()
+      .map(|steps| {
+        let (slots, ready, go) = (&slots, &ready, &go);
+        scope.spawn(move || {
+          let mut latencies = Latencies::with_capacity(steps);
+          latencies.prefault();
+          ready.wait();
+          go.wait();
+          run(allocator, steps, slots, latencies)
+        })
+      })
+      .collect();
+
+    ready.wait();
+    let baseline = resident();
+    let monitor = scope.spawn(|| {
+      let mut peak = resident();
+      while !done.load(Ordering::Relaxed) {
+        thread::sleep(RSS_PERIOD);
+        peak = peak.max(resident());
+      }
+      peak.max(resident())
+    });
+    let start = Instant::now();
+    go.wait();
+
+    let mut latencies = Latencies::default();
+    let mut failed = 0;
+    for worker in workers {
+      let (thread_latencies, thread_failed) =
+        worker.join().expect("replay thread panicked");
+      latencies.merge(thread_latencies);
+      failed += thread_failed;
+    }
+    let wall = start.elapsed();
+    done.store(true, Ordering::Relaxed);
+    let peak = monitor.join().expect("monitor thread panicked");
+    let peak_rss = baseline
+      .zip(peak)
+      .map(|(baseline, peak)| peak.saturating_sub(baseline));
+    (wall, peak_rss, latencies, failed)
+  });
+
+  for block in &plan.leftover {
+    let ptr = slots[block.slot].ptr.load(Ordering::Relaxed) as *mut u8;
+    if !ptr.is_null() {
+      unsafe { allocator.dealloc(ptr, layout(block.size, block.align)) };
+    }
+  }
+
+  Outcome {
+    wall,
+    peak_rss,
+    latencies,
+    failed,
+  }
+}
@@ -0,0 +1,180 @@
+use std::mem;
+
+use tinyalloc_alloc::segment::Segment;
+use tinyalloc_config::{
+  config::SEGMENT_SIZE,
+  simulate::{
+    Simulator,
+    TableParams,
+  },
+};
+
+use crate::{
+  load::{
+    Op,
+    Plan,
+  },
+  mib,
+};
+
+/// Reads a candidate table as `key=value` pairs separated by commas, with
+/// keys `small`, `medium`, `large` and `spacing`, the last as three
+/// numbers joined by `:`. Keys left out keep their current value.
+pub fn parse_table(spec: &str) -> Result<TableParams, String> {
+  let mut params = TableParams::CURRENT;
+  for pair in spec.split(',') {
+    let (key, value) = pair
+      .split_once('=')
+      .ok_or_else(|| format!("expected key=value, got {pair}"))?;
+    let number = |value: &str| {
+      value
+        .parse::<usize>()
+        .map_err(|_| format!("bad number {value} for {key}"))
+    };
+    match key {
+      "small" => params.small_limit = number(value)?,
+      "medium" => params.medium_limit = number(value)?,
+      "large" => params.large_limit = number(value)?,
+      "spacing" => {
+        let steps = value
+          .split(':')
+          .map(number)
+          .collect::<Result<Vec<_>, _>>()?;
+        params.spacing = steps
+          .try_into()
+          .map_err(|_| format!("spacing needs three steps, got {value}"))?;
+      }
+      _ => return Err(format!("unknown table key {key}")),
+    }
+  }
+  params
+    .classes()
+    .map(|_| params)
+    .ok_or_else(|| format!("table {spec} cannot be built"))
+}
+
+/// Plays `plan`, which must be on one thread in trace order, against the
+/// class table `params` gives.
+fn simulate(plan: &Plan, params: &TableParams) -> Option<Simulator> {
+  let mut simulator =
+    Simulator::new(params.classes()?, SEGMENT_SIZE, mem::size_of::<Segment>());
+  for step in plan.threads.iter().flatten() {
+    match step.op {
+      Op::Alloc {
+        slot, size, align, ..
+      } => simulator.alloc(slot, size, align),
+      Op::Free { slot, .. } => simulator.free(slot),
+      Op::Realloc { slot, new_size, .. } => simulator.realloc(slot, new_size),
+    }
+  }
+  Some(simulator)
+}
+
+fn percent(fraction: f64) -> f64 {
+  fraction * 100.0
+}
+
+/// Simulates `plan` against the current table and each candidate, and
+/// prints what each class saw.
+pub fn report(plan: &Plan, candidates: &[(String, TableParams)]) {
+  let current = ("current".to_string(), TableParams::CURRENT);
+  for (name, params) in std::iter::once(&current).chain(candidates) {
+    let Some(simulator) = simulate(plan, params) else {
+      continue;
+    };
+    let classes = simulator.classes();
+    let report = simulator.report();
+    let (handed_out, requested) = classes.iter().zip(&report.classes).fold(
+      (0, 0),
+      |(handed_out, requested), (class, seen)| {
+        (
+          handed_out + seen.allocations * class.size.0,
+          requested + seen.requested,
+        )
+      },
+    );
+
+    println!(
+      "table {name}: {} classes, small {} medium {} large {} spacing {:?}",
+      classes.len(),
+      params.small_limit,
+      params.medium_limit,
+      params.large_limit,
+      params.spacing
+    );
+    println!(
+      "  peak {:.2} MiB for {:.2} MiB live ({:.2}x), {:.1}% internal \
+       fragmentation, {} large allocations",
+      mib(report.peak_memory),
+      mib(report.peak_requested),
+      report.peak_memory as f64 / report.peak_requested.max(1) as f64,
+      percent(1.0 - requested as f64 / handed_out.max(1) as f64),
+      report.large_allocations
+    );
+    println!(
+      "  {:>5} {:>8} {:>10} {:>7} {:>8} {:>8} {:>9} {:>9}",
+      "class",
+      "size",
+      "allocs",
+      "frag",
+      "per seg",
+      "seg use",
+      "peak segs",
+      "occupied"
+    );
+    for (class, seen) in classes.iter().zip(&report.classes) {
+      if seen.allocations == 0 {
+        continue;
+      }
+      println!(
+        "  {:>5} {:>8} {:>10} {:>6.1}% {:>8} {:>7.1}% {:>9} {:>8.1}%",
+        class.id,
+        class.size.0,
+        seen.allocations,
+        percent(seen.internal_fragmentation(class)),
+        seen.objects_per_segment,
+        percent(seen.segment_utilization(class, SEGMENT_SIZE)),
+        seen.peak_segments,
+        percent(seen.occupancy_at_peak())
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::load::load;
+
+  #[test]
+  fn tables_parse_from_key_value_pairs() {
+    let params = parse_table("medium=16384,spacing=2:2:4").unwrap();
+    assert_eq!(params.medium_limit, 16384);
+    assert_eq!(params.spacing, [2, 2, 4]);
+    assert_eq!(params.small_limit, TableParams::CURRENT.small_limit);
+
+    assert!(parse_table("spacing=1:2").is_err());
+    assert!(parse_table("small=100").is_err());
+    assert!(parse_table("tiny=8").is_err());
+  }
+
+  #[test]
+  fn simulation_follows_the_trace() {
+    let trace = "\
+      1 alloc 1 24
+      2 alloc 2 24
+      1 realloc 1 20000
+      2 free 2
+    ";
+    let plan = load(trace.as_bytes(), true).unwrap();
+    let simulator = simulate(&plan, &TableParams::CURRENT).unwrap();
+    let report = simulator.report();
+
+    // The realloc moves, and both copies are live while it does.
+    assert_eq!(report.peak_requested, 24 + 24 + 20000);
+    assert_eq!(report.peak_memory, 2 * SEGMENT_SIZE);
+    let allocations: usize =
+      report.classes.iter().map(|class| class.allocations).sum();
+    assert_eq!(allocations, 3);
+  }
+}
@@ -1,19 +1,29 @@
-use tinyalloc_alloc::config::{
-  MAX_ALIGN,
-  MIN_ALIGN,
-  align_up,
+use tinyalloc_alloc::report::{
+  FreeProblem,
+  invalid_free,
+};
+use tinyalloc_config::{
+  config::MIN_ALIGN,
+  helper::{
+    MAX_ALIGN,
+    align_up,
+  },
 };
 
-use crate::TinyAlloc;
+use crate::{
+  TinyAlloc,
+  trace::{
+    self,
+    EventKind,
+  },
+  trim,
+};
 use core::ffi::{
   c_int,
   c_void,
 };
 use std::{
-  alloc::{
-    GlobalAlloc,
-    Layout,
-  },
+  alloc::Layout,
   convert::TryFrom,
   mem,
   ptr,
@@ -67,7 +77,7 @@ impl Metadata {
     }
 
     let marker_addr = user_addr - mem::size_of::<usize>();
-    if marker_addr % mem::align_of::<usize>() != 0 {
+    if !marker_addr.is_multiple_of(mem::align_of::<usize>()) {
       return None;
     }
 
@@ -77,10 +87,7 @@ impl Metadata {
       return None;
     }
 
-    let header_addr = match user_addr.checked_sub(offset) {
-      Some(addr) => addr,
-      None => return None,
-    };
+    let header_addr = user_addr.checked_sub(offset)?;
 
     if header_addr % mem::align_of::<Self>() != 0 {
       return None;
@@ -151,14 +158,10 @@ impl Allocator {
     Some((layout, user_start))
   }
 
+  // Calls are recorded at the C entry points, with the pointers and sizes
+  // the program sees, rather than here.
   unsafe fn allocate_raw(layout: Layout, zero_init: bool) -> *mut u8 {
-    unsafe {
-      if zero_init {
-        GLOBAL_ALLOCATOR.alloc_zeroed(layout)
-      } else {
-        GLOBAL_ALLOCATOR.alloc(layout)
-      }
-    }
+    GLOBAL_ALLOCATOR.allocate(layout, zero_init)
   }
 
   unsafe fn write_metadata(
@@ -248,29 +251,69 @@ impl Allocator {
   }
 
   unsafe fn deallocate_raw(ptr: *mut u8, layout: Layout) {
-    unsafe { GLOBAL_ALLOCATOR.dealloc(ptr, layout) };
+    unsafe { GLOBAL_ALLOCATOR.deallocate(ptr, layout) };
   }
 
-  unsafe fn deallocate(user_ptr: *mut u8) -> bool {
+  unsafe fn deallocate(user_ptr: *mut u8) -> Result<(), FreeProblem> {
     if user_ptr == ZERO_SIZE_PTR {
-      return true;
+      return Ok(());
     }
 
     let metadata =
       match unsafe { Self::validate_and_extract_metadata(user_ptr) } {
         Some(meta) => meta,
-        None => return false,
+        None => return Err(FreeProblem::Foreign),
       };
 
     let user_size = Self::calculate_user_size(metadata);
     let trailer = unsafe { Self::read_trailer(user_ptr, user_size) };
 
     if !trailer.is_valid(metadata.uoffset) {
-      return false;
+      return Err(FreeProblem::Corrupted);
     }
 
     unsafe { Self::deallocate_raw(metadata.ptr, metadata.layout) };
-    true
+    Ok(())
+  }
+}
+
+/// Hands a block given to the program to the profiler and the tracer.
+fn record_alloc(
+  kind: EventKind,
+  user_ptr: *mut u8,
+  old: *mut u8,
+  size: usize,
+  align: usize,
+) {
+  #[cfg(feature = "profile")]
+  crate::profile::record_alloc(user_ptr, size);
+  if trace::enabled() {
+    let block = unsafe { Allocator::validate_and_extract_metadata(user_ptr) }
+      .map_or(ptr::null_mut(), |metadata| metadata.ptr);
+    trace::record(kind, user_ptr, old, size, align, block);
+  }
+}
+
+/// Tells the profiler and the tracer a block is about to be freed.
+fn record_free(user_ptr: *mut u8) {
+  #[cfg(feature = "profile")]
+  crate::profile::record_dealloc(user_ptr);
+  if trace::enabled() {
+    let metadata =
+      unsafe { Allocator::validate_and_extract_metadata(user_ptr) };
+    let (size, align, block) =
+      metadata.map_or((0, MIN_ALIGN, ptr::null_mut()), |metadata| {
+        let size = Allocator::calculate_user_size(metadata);
+        (size, metadata.ualign as usize, metadata.ptr)
+      });
+    trace::record(
+      EventKind::Dealloc,
+      user_ptr,
+      ptr::null_mut(),
+      size,
+      align,
+      block,
+    );
   }
 }
 
@@ -278,6 +321,7 @@ impl Allocator {
 pub unsafe extern "C" fn malloc(size: usize) -> *mut c_void {
   let align = if size >= 1024 { MAX_ALIGN } else { MIN_ALIGN };
   let ptr = unsafe { Allocator::allocate(size, align, false) };
+  record_alloc(EventKind::Alloc, ptr, ptr::null_mut(), size, align);
   ptr as *mut c_void
 }
 
@@ -294,6 +338,13 @@ pub unsafe extern "C" fn calloc(nmemb: usize, size: usize) -> *mut c_void {
     MIN_ALIGN
   };
   let ptr = unsafe { Allocator::allocate(total_size, align, true) };
+  record_alloc(
+    EventKind::AllocZeroed,
+    ptr,
+    ptr::null_mut(),
+    total_size,
+    align,
+  );
   ptr as *mut c_void
 }
 
@@ -305,7 +356,10 @@ pub unsafe extern "C" fn free(ptr: *mut c_void) {
     return;
   }
 
-  let _ = unsafe { Allocator::deallocate(user_ptr) };
+  record_free(user_ptr);
+  if let Err(problem) = unsafe { Allocator::deallocate(user_ptr) } {
+    invalid_free(problem, user_ptr, None);
+  }
 }
 
 #[unsafe(no_mangle)]
@@ -317,11 +371,12 @@ pub unsafe extern "C" fn aligned_alloc(
     return ptr::null_mut();
   }
 
-  if size % alignment != 0 {
+  if !size.is_multiple_of(alignment) {
     return ptr::null_mut();
   }
 
   let ptr = unsafe { Allocator::allocate(size, alignment, false) };
+  record_alloc(EventKind::Alloc, ptr, ptr::null_mut(), size, alignment);
   ptr as *mut c_void
 }
 
@@ -350,7 +405,7 @@ pub unsafe extern "C" fn posix_memalign(
     return libc::EINVAL;
   }
 
-  if alignment % mem::size_of::<*mut c_void>() != 0 {
+  if !alignment.is_multiple_of(mem::size_of::<*mut c_void>()) {
     unsafe {
       *memptr = ptr::null_mut();
     }
@@ -364,6 +419,7 @@ pub unsafe extern "C" fn posix_memalign(
     }
     return libc::ENOMEM;
   }
+  record_alloc(EventKind::Alloc, ptr, ptr::null_mut(), size, alignment);
 
   unsafe {
     *memptr = ptr as *mut c_void;
@@ -391,13 +447,19 @@ pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
   let metadata =
     match unsafe { Allocator::validate_and_extract_metadata(user_ptr) } {
       Some(meta) => meta,
-      None => return ptr::null_mut(),
+      None => {
+        invalid_free(FreeProblem::Foreign, user_ptr, None);
+        return ptr::null_mut();
+      }
     };
 
   let old_size = Allocator::calculate_user_size(metadata);
   let old_align = metadata.ualign as usize;
   let copy_size = old_size.min(size);
 
+  // Forgotten up front, like a realloc through `GlobalAlloc`.
+  #[cfg(feature = "profile")]
+  crate::profile::record_dealloc(user_ptr);
   let new_ptr = unsafe { Allocator::allocate(size, old_align, false) };
   if new_ptr.is_null() {
     return ptr::null_mut();
@@ -405,8 +467,11 @@ pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
 
   unsafe { ptr::copy_nonoverlapping(user_ptr, new_ptr, copy_size) };
 
-  let _ = unsafe { Allocator::deallocate(user_ptr) };
+  if let Err(problem) = unsafe { Allocator::deallocate(user_ptr) } {
+    invalid_free(problem, user_ptr, None);
+  }
 
+  record_alloc(EventKind::Realloc, new_ptr, user_ptr, size, old_align);
   new_ptr as *mut c_void
 }
 
@@ -426,3 +491,182 @@ pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> usize {
 
   Allocator::calculate_user_size(metadata)
 }
+
+/// Releases cached memory back to the OS. `pad` is accepted for glibc
+/// compatibility and ignored. Returns 1 if any memory was released.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malloc_trim(pad: usize) -> c_int {
+  let _ = pad;
+  c_int::from(trim() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn exported_functions_round_trip() {
+    unsafe {
+      let block = malloc(100) as *mut u8;
+      assert!(!block.is_null());
+      assert!(malloc_usable_size(block as *mut c_void) >= 100);
+      block.write_bytes(0x5A, 100);
+
+      let grown = realloc(block as *mut c_void, 5000) as *mut u8;
+      assert!(!grown.is_null());
+      assert!((0..100).all(|index| *grown.add(index) == 0x5A));
+      free(grown as *mut c_void);
+
+      let zeroed = calloc(64, 64) as *mut u8;
+      assert!((0..64 * 64).all(|index| *zeroed.add(index) == 0));
+      free(zeroed as *mut c_void);
+
+      let mut aligned = ptr::null_mut();
+      assert_eq!(posix_memalign(&mut aligned, 4096, 300), 0);
+      assert!((aligned as usize).is_multiple_of(4096));
+      free(aligned);
+    }
+  }
+
+  #[test]
+  fn first_allocation_on_a_new_thread() {
+    // A thread's first allocation registers its destructors, which calls
+    // back into `calloc`.
+    let value = std::thread::spawn(|| unsafe {
+      let block = malloc(32) as *mut u64;
+      block.write(7);
+      let value = block.read();
+      free(block as *mut c_void);
+      value
+    })
+    .join()
+    .unwrap();
+    assert_eq!(value, 7);
+  }
+
+  #[test]
+  fn invalid_frees_are_classified() {
+    let mut stack = [0u64; 8];
+    let foreign = stack[4..].as_mut_ptr() as *mut u8;
+    assert_eq!(
+      unsafe { Allocator::deallocate(foreign) },
+      Err(FreeProblem::Foreign)
+    );
+    // The default policy drops it without touching the memory.
+    unsafe { free(foreign as *mut c_void) };
+    assert_eq!(stack, [0; 8]);
+
+    unsafe {
+      let block = malloc(40) as *mut u8;
+      let trailer = Allocator::calculate_trailer_start(block, 40);
+      let saved = trailer.read();
+      trailer.write(!saved);
+      assert_eq!(Allocator::deallocate(block), Err(FreeProblem::Corrupted));
+      free(block as *mut c_void);
+
+      trailer.write(saved);
+      assert_eq!(Allocator::deallocate(block), Ok(()));
+    }
+  }
+
+  #[test]
+  fn exported_functions_are_traced() {
+    use std::fs::File;
+
+    use crate::trace::{
+      Event,
+      EventPath,
+      TraceReader,
+      disable_trace,
+      enable_trace,
+    };
+
+    let path = std::env::temp_dir()
+      .join(format!("tinyalloc-ffi-{}.trace", std::process::id()));
+    enable_trace(File::create(&path).unwrap()).unwrap();
+    let (block, grown) = unsafe {
+      let block = malloc(100);
+      let grown = realloc(block, 3000);
+      free(grown);
+      (block as u64, grown as u64)
+    };
+    disable_trace();
+
+    let thread = tinyalloc_sys::thread::current_id();
+    let events: Vec<Event> = TraceReader::new(File::open(&path).unwrap())
+      .unwrap()
+      .map(Result::unwrap)
+      .filter(|event| event.thread == thread)
+      .collect();
+    let _ = std::fs::remove_file(&path);
+
+    let calls: Vec<_> = events
+      .iter()
+      .map(|event| (event.kind, event.ptr, event.old, event.size))
+      .collect();
+    assert_eq!(
+      calls,
+      [
+        (EventKind::Alloc, block, 0, 100),
+        (EventKind::Realloc, grown, block, 3000),
+        (EventKind::Dealloc, grown, 0, 3000),
+      ]
+    );
+    assert!(
+      events
+        .iter()
+        .all(|event| matches!(event.path, EventPath::Small(_)))
+    );
+  }
+
+  #[cfg(feature = "profile")]
+  #[inline(never)]
+  fn malloc_profiled() -> Vec<usize> {
+    (0..16 * 1024)
+      .map(|_| unsafe { malloc(4096) } as usize)
+      .collect()
+  }
+
+  /// Live bytes the profile puts on stacks whose leaf is `function`.
+  #[cfg(feature = "profile")]
+  fn live_bytes_in(function: &str) -> u64 {
+    use crate::profile::{
+      ProfileFormat,
+      ProfileKind,
+      write_profile,
+    };
+
+    let mut collapsed = Vec::new();
+    write_profile(ProfileKind::Live, ProfileFormat::Collapsed, &mut collapsed)
+      .unwrap();
+    String::from_utf8(collapsed)
+      .unwrap()
+      .lines()
+      .filter_map(|line| line.rsplit_once(' '))
+      .filter(|(stack, _)| stack.rsplit(';').next().unwrap().contains(function))
+      .map(|(_, bytes)| bytes.parse::<u64>().unwrap())
+      .sum()
+  }
+
+  #[cfg(feature = "profile")]
+  #[test]
+  fn profile_attributes_malloc_to_its_caller() {
+    use crate::profile::{
+      PROFILE_INTERVAL,
+      SAMPLING,
+      set_profile_interval,
+    };
+
+    let _sampling = SAMPLING.lock();
+    set_profile_interval(PROFILE_INTERVAL);
+    let blocks = malloc_profiled();
+    set_profile_interval(0);
+
+    let live = live_bytes_in("malloc_profiled");
+    assert!(live > 16 << 20, "{live} bytes");
+    for block in blocks {
+      unsafe { free(block as *mut c_void) };
+    }
+    assert_eq!(live_bytes_in("malloc_profiled"), 0);
+  }
+}
@@ -1,8 +1,40 @@
-use std::sync::atomic::{
-  AtomicBool,
-  Ordering,
+use std::{
+  cell::Cell,
+  ffi::CStr,
+  hint::spin_loop,
+  sync::atomic::{
+    AtomicBool,
+    AtomicU8,
+    AtomicU64,
+    Ordering,
+  },
 };
 
+use tinyalloc_alloc::options::{
+  FreePolicy,
+  LargeGuard,
+  set_free_policy,
+  set_large_guard,
+  set_quarantine_bytes,
+  set_redzone,
+  set_sample_rate,
+};
+use tinyalloc_sys::{
+  process::env,
+  thread::current_id,
+};
+
+/// Bytes of freed small blocks each heap holds in quarantine.
+const QUARANTINE_ENV: &CStr = c"TINYALLOC_QUARANTINE";
+/// How invalid frees are handled: `ignore`, `log` or `abort`.
+const FREE_POLICY_ENV: &CStr = c"TINYALLOC_FREE_POLICY";
+/// Set to `1` to pad every block with a redzone checked on free.
+const REDZONE_ENV: &CStr = c"TINYALLOC_REDZONE";
+/// Serve one allocation in this many from the guarded pool.
+const SAMPLE_RATE_ENV: &CStr = c"TINYALLOC_SAMPLE_RATE";
+/// Guard pages around large blocks: `off`, `after` or `both`.
+const LARGE_GUARD_ENV: &CStr = c"TINYALLOC_LARGE_GUARD";
+
 struct LifetimeGuard;
 
 impl Drop for LifetimeGuard {
@@ -14,9 +46,27 @@ impl Drop for LifetimeGuard {
   }
 }
 
+/// Registers the destructors of the calling thread's locals on its first
+/// allocation. Returns false while that is under way: registering can
+/// itself allocate through the exported `calloc`, and an allocation made
+/// then must not touch a thread local with a destructor.
 #[inline]
-pub fn td_register() {
+pub fn td_register() -> bool {
+  match REGISTERED.try_with(Cell::get) {
+    Ok(Registration::Done) => true,
+    Ok(Registration::Pending) => register(),
+    _ => false,
+  }
+}
+
+#[cold]
+fn register() -> bool {
+  REGISTERED.set(Registration::Running);
   _GUARD.try_with(|_| {}).ok();
+  crate::LOCAL_HEAP.try_with(|_| {}).ok();
+  crate::trace::register();
+  REGISTERED.set(Registration::Done);
+  true
 }
 
 #[inline]
@@ -26,7 +76,86 @@ pub fn is_td() -> bool {
     .unwrap_or(true)
 }
 
+/// Where the environment setup stands: [`UNINIT`], [`RUNNING`] or
+/// [`DONE`].
+static PROCESS_INIT: AtomicU8 = AtomicU8::new(UNINIT);
+/// The thread running the setup, whose own allocations pass through it.
+static INIT_THREAD: AtomicU64 = AtomicU64::new(0);
+
+const UNINIT: u8 = 0;
+const RUNNING: u8 = 1;
+const DONE: u8 = 2;
+
+/// One-time setup driven by the environment, run ahead of the first
+/// allocation so every block is made under the same options.
+#[inline]
+pub fn process_init() {
+  if PROCESS_INIT.load(Ordering::Acquire) != DONE {
+    init_from_env();
+  }
+}
+
+/// A state flag rather than a `Once`, so an allocation the setup makes
+/// itself does not deadlock on it. Other threads wait until it is done.
+#[cold]
+fn init_from_env() {
+  let thread = current_id();
+  if PROCESS_INIT
+    .compare_exchange(UNINIT, RUNNING, Ordering::Acquire, Ordering::Acquire)
+    .is_err()
+  {
+    while PROCESS_INIT.load(Ordering::Acquire) != DONE {
+      if INIT_THREAD.load(Ordering::Relaxed) == thread {
+        return;
+      }
+      spin_loop();
+    }
+    return;
+  }
+  INIT_THREAD.store(thread, Ordering::Relaxed);
+
+  crate::options::init_from_env();
+  crate::leak::init_from_env();
+  #[cfg(feature = "profile")]
+  crate::profile::init_from_env();
+  crate::trace::init_from_env();
+  if let Some(bytes) =
+    env(QUARANTINE_ENV).and_then(|value| value.to_str().ok()?.parse().ok())
+  {
+    set_quarantine_bytes(bytes);
+  }
+  if let Some(policy) =
+    env(FREE_POLICY_ENV).and_then(|value| FreePolicy::parse(value.to_bytes()))
+  {
+    set_free_policy(policy);
+  }
+  if env(REDZONE_ENV).is_some_and(|value| value.to_bytes() == b"1") {
+    set_redzone(true);
+  }
+  if let Some(rate) =
+    env(SAMPLE_RATE_ENV).and_then(|value| value.to_str().ok()?.parse().ok())
+  {
+    set_sample_rate(rate);
+  }
+  if let Some(guard) =
+    env(LARGE_GUARD_ENV).and_then(|value| LargeGuard::parse(value.to_bytes()))
+  {
+    set_large_guard(guard);
+  }
+  PROCESS_INIT.store(DONE, Ordering::Release);
+}
+
+#[derive(Clone, Copy)]
+enum Registration {
+  Pending,
+  Running,
+  Done,
+}
+
 thread_local! {
-  static _GUARD: LifetimeGuard = LifetimeGuard {};
-  pub static TEARING_DOWN: AtomicBool = AtomicBool::new(false);
+  static _GUARD: LifetimeGuard = const { LifetimeGuard {} };
+  pub static TEARING_DOWN: AtomicBool = const { AtomicBool::new(false) };
+  /// Has no destructor, so reading it never allocates.
+  static REGISTERED: Cell<Registration> =
+    const { Cell::new(Registration::Pending) };
 }
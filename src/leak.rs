@@ -0,0 +1,178 @@
+use std::{
+  ffi::CStr,
+  io::Write,
+  sync::atomic::{
+    AtomicBool,
+    Ordering,
+  },
+};
+
+use tinyalloc_alloc::static_::collect_heaps;
+use tinyalloc_config::{
+  classes::CLASSES,
+  config::SIZES,
+};
+use tinyalloc_sys::process::{
+  at_exit,
+  env,
+  exit_now,
+};
+
+use crate::{
+  BOOTSTRAP_HEAP,
+  visit_allocations,
+};
+
+/// Set to `1` to print a leak report at exit, or to `fail` to also exit
+/// with [`LEAK_EXIT_CODE`] when anything leaked.
+const LEAK_REPORT_ENV: &CStr = c"TINYALLOC_LEAK_REPORT";
+pub const LEAK_EXIT_CODE: i32 = 23;
+
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+static FAIL_ON_LEAK: AtomicBool = AtomicBool::new(false);
+
+/// Blocks and bytes still live for one group of allocations.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Leaked {
+  pub blocks: usize,
+  pub bytes: usize,
+}
+
+impl Leaked {
+  fn add(&mut self, bytes: usize) {
+    self.blocks += 1;
+    self.bytes += bytes;
+  }
+}
+
+/// Every allocation still live, grouped by size class. Small blocks count
+/// at their class size and blocks with a header at their usable size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LeakSummary {
+  pub total: Leaked,
+  pub classes: [Leaked; SIZES],
+  pub large: Leaked,
+}
+
+impl LeakSummary {
+  fn write_to(&self, out: &mut impl Write) -> std::io::Result<()> {
+    writeln!(
+      out,
+      "tinyalloc: {} allocations still live, {} bytes",
+      self.total.blocks, self.total.bytes
+    )?;
+    for (class, leaked) in CLASSES.iter().zip(self.classes.iter()) {
+      if leaked.blocks > 0 {
+        writeln!(
+          out,
+          "tinyalloc:   class {:>2} ({:>6} B): {:>8} blocks {:>12} bytes",
+          class.id, class.size.0, leaked.blocks, leaked.bytes
+        )?;
+      }
+    }
+    if self.large.blocks > 0 {
+      writeln!(
+        out,
+        "tinyalloc:   large:               {:>8} blocks {:>12} bytes",
+        self.large.blocks, self.large.bytes
+      )?;
+    }
+    Ok(())
+  }
+}
+
+/// Applies every pending cross-thread free, then tallies what is left.
+/// Runs with heap locks held, so it allocates nothing.
+pub fn leak_summary() -> LeakSummary {
+  collect_heaps();
+  if let Some(bootstrap) = BOOTSTRAP_HEAP.get() {
+    bootstrap.with(|heap| heap.collect());
+  }
+
+  let mut summary = LeakSummary {
+    total: Leaked::default(),
+    classes: [Leaked::default(); SIZES],
+    large: Leaked::default(),
+  };
+  visit_allocations(|info| {
+    summary.total.add(info.size);
+    match info.class {
+      Some(class) => summary.classes[class].add(info.size),
+      None => summary.large.add(info.size),
+    }
+  });
+  summary
+}
+
+extern "C" fn report_at_exit() {
+  let summary = leak_summary();
+  if summary.total.blocks == 0 {
+    return;
+  }
+
+  let _ = summary.write_to(&mut std::io::stderr().lock());
+  if FAIL_ON_LEAK.load(Ordering::Relaxed) {
+    exit_now(LEAK_EXIT_CODE);
+  }
+}
+
+/// Prints a [`LeakSummary`] to stderr when the process exits. With
+/// `fail_on_leak` the process then exits with [`LEAK_EXIT_CODE`] if any
+/// allocation is still live, skipping later exit handlers.
+pub fn enable_leak_report(fail_on_leak: bool) {
+  FAIL_ON_LEAK.fetch_or(fail_on_leak, Ordering::Relaxed);
+  if !REGISTERED.swap(true, Ordering::AcqRel) {
+    at_exit(report_at_exit);
+  }
+}
+
+/// Turns the report on when the environment asks for it.
+pub fn init_from_env() {
+  let Some(value) = env(LEAK_REPORT_ENV) else {
+    return;
+  };
+  match value.to_bytes() {
+    b"" | b"0" => {}
+    b"fail" => enable_leak_report(true),
+    _ => enable_leak_report(false),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::alloc::{
+    GlobalAlloc,
+    Layout,
+  };
+
+  use tinyalloc_config::{
+    classes::find_class,
+    config::LARGE_SC_LIMIT,
+  };
+
+  use super::*;
+  use crate::TinyAlloc;
+
+  #[test]
+  fn leak_summary_groups_live_blocks_by_class() {
+    let layout = Layout::from_size_align(LARGE_SC_LIMIT / 4 * 3, 8).unwrap();
+    let class = find_class(layout.size(), layout.align()).unwrap();
+    let ptrs: Vec<usize> = (0..3)
+      .map(|_| unsafe { TinyAlloc.alloc(layout) } as usize)
+      .collect();
+
+    let summary = leak_summary();
+    assert!(summary.classes[class.id].blocks >= 3);
+    assert!(summary.classes[class.id].bytes >= 3 * class.size.0);
+    assert!(summary.total.blocks >= 3);
+
+    let mut out = Vec::new();
+    summary.write_to(&mut out).unwrap();
+    let report = String::from_utf8(out).unwrap();
+    assert!(report.contains(&format!("class {:>2}", class.id)));
+
+    for ptr in ptrs {
+      unsafe { TinyAlloc.dealloc(ptr as *mut u8, layout) };
+    }
+  }
+}
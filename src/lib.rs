@@ -3,13 +3,21 @@ use std::{
     GlobalAlloc,
     Layout,
   },
-  cell::UnsafeCell,
+  cell::{
+    Cell,
+    UnsafeCell,
+  },
+  io::Write,
   num::NonZeroUsize,
   ptr::NonNull,
-  sync::OnceLock,
-  thread::{
-    self,
+  sync::{
+    OnceLock,
+    atomic::{
+      AtomicBool,
+      Ordering,
+    },
   },
+  time::Duration,
 };
 
 use spin::Mutex;
@@ -18,25 +26,142 @@ use tinyalloc_alloc::{
     Allocation,
     AllocationOwner,
   },
+  guarded,
   heap::Heap,
+  large::Large,
+  options::{
+    quarantine_bytes,
+    redzone,
+  },
+  redzone,
+  report::{
+    FreeProblem,
+    invalid_free,
+    overflow,
+  },
+  segment::Segment,
+  static_::{
+    acquire_heap,
+    drain_unclaimed,
+    free_abandoned,
+    release_heap,
+    release_idle_arenas,
+    segment_from_ptr,
+    visit_abandoned,
+    visit_heaps,
+  },
+};
+use tinyalloc_config::{
+  classes::find_class,
+  config::{
+    LARGE_SC_LIMIT,
+    REDZONE_SIZE,
+  },
 };
+use tinyalloc_list::List;
 use tinyalloc_sys::{
   GLOBAL_MAPPER,
   MapError,
   mapper::Protection,
+  process::at_exit,
 };
 
 use crate::init::{
   is_td,
+  process_init,
   td_register,
 };
 
 #[cfg(feature = "ffi")]
 mod ffi;
 mod init;
+mod leak;
+mod oom;
+mod options;
+#[cfg(feature = "profile")]
+mod profile;
+mod trace;
+
+pub use leak::{
+  LEAK_EXIT_CODE,
+  LeakSummary,
+  Leaked,
+  enable_leak_report,
+  leak_summary,
+};
+pub use oom::{
+  OOM_RETRIES,
+  OomHandler,
+  reserve_emergency_pool,
+  set_oom_handler,
+  set_oom_retries,
+};
+#[cfg(feature = "profile")]
+pub use profile::{
+  PROFILE_INTERVAL,
+  ProfileFormat,
+  ProfileKind,
+  set_profile_interval,
+  write_profile,
+};
+pub use tinyalloc_alloc::{
+  options::{
+    FreePolicy,
+    LargeGuard,
+    set_free_policy,
+    set_large_guard,
+    set_quarantine_bytes,
+    set_sample_rate,
+  },
+  report::FreeProblem as InvalidFree,
+  stats::Stats,
+};
+pub use trace::{
+  EVENT_SIZE,
+  Event,
+  EventKind,
+  EventPath,
+  TRACE_MAGIC,
+  TraceReader,
+  disable_trace,
+  enable_trace,
+  flush_trace,
+};
+
+/// The calling thread's claim on a pooled heap. The heap is acquired on
+/// first use and abandoned back to the pool when the thread exits.
+struct LocalHeap {
+  heap: Cell<Option<NonNull<Heap>>>,
+}
+
+impl LocalHeap {
+  const fn new() -> Self {
+    Self {
+      heap: Cell::new(None),
+    }
+  }
+
+  fn get(&self) -> Option<NonNull<Heap>> {
+    if let Some(heap) = self.heap.get() {
+      return Some(heap);
+    }
+
+    let heap = acquire_heap()?;
+    self.heap.set(Some(heap));
+    Some(heap)
+  }
+}
+
+impl Drop for LocalHeap {
+  fn drop(&mut self) {
+    if let Some(heap) = self.heap.take() {
+      release_heap(heap);
+    }
+  }
+}
 
 thread_local! {
-    static LOCAL_HEAP: UnsafeCell<Heap> = UnsafeCell::new(Heap::new());
+    static LOCAL_HEAP: LocalHeap = const { LocalHeap::new() };
 }
 
 struct BootstrapHeap {
@@ -64,28 +189,57 @@ impl BootstrapHeap {
 
 static BOOTSTRAP_HEAP: OnceLock<BootstrapHeap> = OnceLock::new();
 
+/// Blocks mapped straight from the OS because no heap could serve them,
+/// tracked so [`visit_allocations`] can report them.
+struct Mapped(Mutex<List<Allocation>>);
+
+unsafe impl Sync for Mapped {}
+
+static MAPPED: Mapped = Mapped(Mutex::new(List::new()));
+
 fn with_heap<R>(f: impl FnOnce(&mut Heap) -> R) -> R {
-  td_register();
-  if is_td() {
+  if !td_register() || is_td() {
     let bootstrap = BOOTSTRAP_HEAP.get_or_init(BootstrapHeap::new);
     return bootstrap.with(f);
   }
 
-  match LOCAL_HEAP.try_with(|heap| heap.get() as *mut Heap) {
-    Ok(ptr) => {
-      let heap = unsafe { &mut *ptr };
+  match LOCAL_HEAP.try_with(LocalHeap::get) {
+    Ok(Some(ptr)) => {
+      let _guard = unsafe { ptr.as_ref() }.lock();
+      let heap = unsafe { &mut *ptr.as_ptr() };
       f(heap)
     }
-    Err(_) => {
+    _ => {
       let bootstrap = BOOTSTRAP_HEAP.get_or_init(BootstrapHeap::new);
       bootstrap.with(f)
     }
   }
 }
 
+/// How much [`TinyAlloc::collect`] gives back to the OS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollectLevel {
+  /// Frees the calling thread's pending remote blocks and returns its empty
+  /// segments to their arenas.
+  Heap,
+  /// As [`CollectLevel::Heap`], then unmaps every arena left without
+  /// segments instead of waiting out the release delay.
+  Full,
+}
+
 pub struct TinyAlloc;
 
 impl TinyAlloc {
+  /// Releases memory the allocator is holding on to but not using, and
+  /// returns how many bytes were decommitted or unmapped.
+  pub fn collect(&self, level: CollectLevel) -> usize {
+    let mut released = with_heap(Heap::collect);
+    if level == CollectLevel::Full {
+      released += release_idle_arenas(Duration::ZERO, true);
+    }
+    released
+  }
+
   fn os_alloc(
     &self,
     size: NonZeroUsize,
@@ -95,104 +249,1065 @@ impl TinyAlloc {
     Ok(mapped)
   }
 
-   fn os_dealloc(&self, ptr: NonNull<[u8]>) {
+  fn os_dealloc(&self, ptr: NonNull<[u8]>) {
     GLOBAL_MAPPER.unmap(ptr)
   }
 
   fn write_allocation(
     &self,
     owner: AllocationOwner,
+    total_layout: Layout,
     layout: Layout,
     mem: NonNull<[u8]>,
   ) -> *mut u8 {
-    unsafe {
-      let header_ptr = mem.as_ptr() as *mut Allocation;
-      let user_raw_ptr = Allocation::calc_user_ptr(header_ptr);
-      let alloc_ptr = header_ptr as *mut u8;
+    let header_ptr = mem.as_ptr() as *mut Allocation;
+    unsafe { Allocation::write(header_ptr, owner, total_layout, layout) }
+  }
 
-      let allocation = Allocation::new(owner, layout, alloc_ptr, user_raw_ptr);
-      header_ptr.write(allocation);
-      user_raw_ptr
-    }
+  fn is_small(layout: Layout) -> bool {
+    layout.size() <= LARGE_SC_LIMIT
+      && find_class(layout.size(), layout.align()).is_some()
   }
-}
 
-unsafe impl GlobalAlloc for TinyAlloc {
-  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-    let total_size = Allocation::total_size(layout);
+  fn alloc_small(&self, layout: Layout, zeroed: bool) -> Option<*mut u8> {
+    with_heap(|heap| {
+      if zeroed {
+        heap.allocate_zeroed(layout).ok()
+      } else {
+        heap.allocate(layout).ok()
+      }
+    })
+    .map(|mem| mem.as_ptr() as *mut u8)
+  }
+
+  fn alloc_header(
+    &self,
+    layout: Layout,
+    zeroed: bool,
+  ) -> Result<NonNull<u8>, MapError> {
     let total_layout =
-      unsafe { Layout::from_size_align_unchecked(total_size, layout.align()) };
+      Allocation::total_layout(layout).ok_or(MapError::InvalidSize)?;
 
     if let Some(ptr) = with_heap(|heap| {
-      heap.allocate(total_layout).ok().map(|mem| {
-        let heap_ptr = heap as *mut Heap;
-        self.write_allocation(
-          AllocationOwner::Heap(heap_ptr),
-          total_layout,
-          mem,
-        )
+      let mem = if zeroed {
+        heap.allocate_large_zeroed(total_layout)
+      } else {
+        heap.allocate_large(total_layout)
+      };
+      mem.ok().map(|mem| {
+        let owner = AllocationOwner::Heap(heap as *mut Heap);
+        let large = Large::from_user_ptr(mem.cast())
+          .map(|large| unsafe { large.as_ref() })
+          .filter(|large| large.guard().after());
+        match large {
+          // The header stays where the block starts, for `Large` to find,
+          // and the caller's bytes end against the trailing guard page.
+          Some(large) => unsafe {
+            let full = Layout::from_size_align_unchecked(
+              large.capacity(),
+              total_layout.align(),
+            );
+            let header_ptr = mem.as_ptr() as *mut Allocation;
+            Allocation::write_at_end(header_ptr, owner, full, layout)
+          },
+          None => self.write_allocation(owner, total_layout, layout, mem),
+        }
       })
     }) {
-      return ptr;
+      return NonNull::new(ptr).ok_or(MapError::InvalidSize);
     }
 
-    let size = match NonZeroUsize::new(total_size) {
-      Some(size) => size,
-      None => return std::ptr::null_mut(),
+    let size =
+      NonZeroUsize::new(total_layout.size()).ok_or(MapError::InvalidSize)?;
+
+    // A fresh anonymous mapping already reads as zero, so `zeroed` needs no
+    // extra work here.
+    let os_mem = self.os_alloc(size)?;
+    let ptr = self.write_allocation(
+      AllocationOwner::Mapper(os_mem),
+      total_layout,
+      layout,
+      os_mem,
+    );
+    MAPPED.0.lock().push(os_mem.cast());
+    NonNull::new(ptr).ok_or(MapError::InvalidSize)
+  }
+
+  /// Allocates `layout` with at least `REDZONE_SIZE` spare bytes after it,
+  /// and fills every byte past `layout.size()` with the redzone pattern.
+  fn alloc_redzoned(
+    &self,
+    layout: Layout,
+    zeroed: bool,
+  ) -> Result<NonNull<u8>, MapError> {
+    let padded = layout
+      .size()
+      .checked_add(REDZONE_SIZE)
+      .and_then(|size| Layout::from_size_align(size, layout.align()).ok())
+      .ok_or(MapError::InvalidSize)?;
+
+    let ptr = if Self::is_small(padded)
+      && let Some(ptr) = self.alloc_small(padded, zeroed).and_then(NonNull::new)
+    {
+      ptr
+    } else {
+      self.alloc_header(padded, zeroed)?
     };
+    if let Some(usable) = Self::usable_size(ptr) {
+      redzone::fill(ptr, layout.size(), usable);
+    }
+    Ok(ptr)
+  }
 
-    match self.os_alloc(size) {
-      Ok(os_mem) => self.write_allocation(
-        AllocationOwner::Mapper(os_mem),
-        total_layout,
-        os_mem,
-      ),
-      Err(_) => std::ptr::null_mut(),
+  /// Reports a write past the first `size` bytes of the block at `ptr`
+  /// into the redzone that follows them, returning the offset of the first
+  /// byte written.
+  fn check_redzone(ptr: NonNull<u8>, size: usize) -> Option<usize> {
+    let usable = Self::usable_size(ptr)?;
+    let offset = redzone::check(ptr, size, usable)?;
+    overflow(ptr.as_ptr(), size, offset);
+    Some(offset)
+  }
+
+  /// Bytes usable from `ptr`: the size of its slot, or the room left in
+  /// its block with a header.
+  fn usable_size(ptr: NonNull<u8>) -> Option<usize> {
+    if let Some(segment) = segment_from_ptr(ptr) {
+      return Some(unsafe { segment.as_ref() }.class().size.0);
     }
+    Allocation::from(ptr.as_ptr())
+      .map(|header| unsafe { &*header }.usable_size())
   }
 
-  unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+  fn dealloc_header(&self, ptr: *mut u8, layout: Layout) {
     let allocation = match Allocation::from(ptr) {
       Some(allocation) => allocation,
-      None => return,
+      None => {
+        let class = find_class(layout.size(), layout.align());
+        invalid_free(FreeProblem::Foreign, ptr, class);
+        return;
+      }
     };
 
     let allocation_ref = unsafe { &*allocation };
+    if layout.size() > allocation_ref.usable_size() {
+      invalid_free(FreeProblem::LayoutMismatch, ptr, None);
+    }
 
     if let Some(mapped_slice) = unsafe { allocation_ref.map_range() } {
+      let header = unsafe { NonNull::new_unchecked(allocation) };
+      MAPPED.0.lock().remove(header);
       self.os_dealloc(mapped_slice);
       return;
     }
 
-    let heap = match unsafe { allocation_ref.heap_ptr() } {
-      Some(heap) => heap,
-      None => return,
+    let owner = match allocation_ref.owned() {
+      AllocationOwner::Heap(owner) => owner,
+      AllocationOwner::Mapper(_) => return,
     };
 
-    let header_ptr = allocation as *mut u8;
-    let total_layout = allocation_ref.full();
+    let header_ptr = unsafe { NonNull::new_unchecked(allocation as *mut u8) };
+    self.release(owner, header_ptr, |heap| {
+      let _ = heap.deallocate_large(header_ptr);
+    });
+  }
+
+  /// Runs `local` on `owner` when the calling thread may operate on it,
+  /// either because it is the thread's own heap or because the heap is
+  /// idle and can be claimed briefly. Returns `None` when the heap is in
+  /// use by another thread.
+  fn with_owner<R>(
+    &self,
+    owner: *mut Heap,
+    local: impl FnOnce(&mut Heap) -> R,
+  ) -> Option<R> {
+    if let Some(bootstrap) = BOOTSTRAP_HEAP.get()
+      && std::ptr::eq(owner, bootstrap.heap.get())
+    {
+      return Some(bootstrap.with(local));
+    }
+
+    if with_heap(|heap| std::ptr::eq(heap, owner)) {
+      return Some(with_heap(local));
+    }
 
-    if let Some(bootstrap) = BOOTSTRAP_HEAP.get() {
-      if heap as *const _ == bootstrap.heap.get() {
-        bootstrap.with(|heap| unsafe {
-          let _ =
-            heap.deallocate(NonNull::new_unchecked(header_ptr), total_layout);
+    let heap = unsafe { &*owner };
+    if !heap.try_claim() {
+      return None;
+    }
+    let guard = heap.lock();
+    let result = local(unsafe { &mut *owner });
+    drop(guard);
+    heap.unclaim();
+    drain_unclaimed(NonNull::from(heap));
+    Some(result)
+  }
+
+  /// Hands the large block at `ptr` back to `owner`: directly when the
+  /// calling thread may use the heap, through the owner's remote stack
+  /// otherwise. A deferred free is counted on the calling thread's heap.
+  fn release(
+    &self,
+    owner: *mut Heap,
+    ptr: NonNull<u8>,
+    free: impl FnOnce(&mut Heap),
+  ) {
+    if self.with_owner(owner, free).is_none() {
+      let capacity = Large::from_user_ptr(ptr)
+        .map_or(0, |large| unsafe { large.as_ref() }.capacity());
+      with_heap(|heap| heap.counters().free(capacity));
+      unsafe { &*owner }.remote().push(ptr);
+    }
+  }
+
+  /// Frees a slot directly when the calling thread may use the segment's
+  /// heap, and otherwise leaves it on the segment's thread-free stack for
+  /// the owner to merge. Frees that bypass the owning heap are counted on
+  /// the calling thread's heap. With quarantine on, every slot goes to the
+  /// calling thread's quarantine first, whoever owns it; the segment marks
+  /// it there, so a second free from any thread is reported.
+  fn release_small(&self, segment: NonNull<Segment>, ptr: NonNull<u8>) {
+    if quarantine_bytes() > 0 {
+      let _ = with_heap(|heap| heap.deallocate_small(ptr));
+      return;
+    }
+
+    let size = unsafe { segment.as_ref() }.class().size.0;
+    loop {
+      let owner = unsafe { segment.as_ref() }.owner();
+      if !owner.is_null() {
+        let local = self.with_owner(owner, |heap| {
+          let _ = heap.deallocate_small(ptr);
         });
+        if local.is_none() {
+          with_heap(|heap| heap.counters().free(size));
+          unsafe { segment.as_ref() }.thread_free().push(ptr);
+        }
+        return;
+      }
+      if free_abandoned(segment, ptr) {
+        with_heap(|heap| heap.counters().free(size));
         return;
       }
     }
+  }
 
-    if allocation_ref.thread() == Some(thread::current().id()) {
-      with_heap(|heap| unsafe {
-        let _ =
-          heap.deallocate(NonNull::new_unchecked(header_ptr), total_layout);
-      })
+  fn realloc_small(
+    &self,
+    segment: NonNull<Segment>,
+    ptr: *mut u8,
+    layout: Layout,
+    new_layout: Layout,
+  ) -> *mut u8 {
+    let class = unsafe { segment.as_ref() }.class();
+    let new_size = new_layout.size();
+    let layout = if layout.size() > class.size.0 {
+      invalid_free(FreeProblem::LayoutMismatch, ptr, Some(class));
+      Layout::from_size_align(class.size.0, layout.align()).unwrap_or(layout)
+    } else {
+      layout
+    };
+
+    if new_size <= class.size.0
+      && let Some(new_class) = find_class(new_size, new_layout.align())
+      && new_class.size.0 * 2 >= class.size.0
+    {
+      return ptr;
+    }
+
+    unsafe { self.realloc_copy(ptr, layout, new_layout) }
+  }
+
+  fn realloc_header(
+    &self,
+    ptr: *mut u8,
+    layout: Layout,
+    new_layout: Layout,
+  ) -> *mut u8 {
+    let allocation = match Allocation::from(ptr) {
+      Some(allocation) => allocation,
+      None => {
+        let class = find_class(layout.size(), layout.align());
+        invalid_free(FreeProblem::Foreign, ptr, class);
+        return std::ptr::null_mut();
+      }
+    };
+
+    let allocation_ref = unsafe { &*allocation };
+    let user_offset = ptr as usize - allocation_ref.alloc_ptr() as usize;
+    let full = user_offset.checked_add(new_layout.size()).and_then(|size| {
+      Layout::from_size_align(size, allocation_ref.full().align()).ok()
+    });
+
+    let moved = full.and_then(|full| match allocation_ref.owned() {
+      AllocationOwner::Mapper(mapped) => {
+        self.remap_mapper(allocation, mapped, full)
+      }
+      AllocationOwner::Heap(owner) => self
+        .resize_large(owner, allocation_ref.alloc_ptr(), full)
+        .map(|(header_ptr, owned)| unsafe {
+          Allocation::rebase(header_ptr, owned, full)
+        }),
+    });
+
+    match moved {
+      Some(user_ptr) => user_ptr,
+      None => unsafe { self.realloc_copy(ptr, layout, new_layout) },
+    }
+  }
+
+  /// Resizes a block mapped straight from the OS and returns its new user
+  /// pointer. The block leaves the registry while its mapping may move.
+  fn remap_mapper(
+    &self,
+    header: *mut Allocation,
+    mapped: NonNull<[u8]>,
+    full: Layout,
+  ) -> Option<*mut u8> {
+    let size = NonZeroUsize::new(full.size())?;
+    let header = NonNull::new(header)?;
+    let mut registry = MAPPED.0.lock();
+    registry.remove(header);
+
+    match GLOBAL_MAPPER.remap(mapped, size, true) {
+      Ok(remapped) => {
+        let header_ptr = remapped.cast::<Allocation>();
+        let owned = AllocationOwner::Mapper(remapped);
+        let user_ptr =
+          unsafe { Allocation::rebase(header_ptr.as_ptr(), owned, full) };
+        registry.push(header_ptr);
+        Some(user_ptr)
+      }
+      Err(_) => {
+        registry.push(header);
+        None
+      }
+    }
+  }
+
+  fn resize_large(
+    &self,
+    owner: *mut Heap,
+    alloc_ptr: *mut u8,
+    full: Layout,
+  ) -> Option<(*mut Allocation, AllocationOwner)> {
+    let alloc_nn = NonNull::new(alloc_ptr)?;
+    let resized = match self
+      .with_owner(owner, |heap| heap.reallocate_large(alloc_nn, full))
+    {
+      Some(result) => result.ok()?,
+      None => {
+        let size = NonZeroUsize::new(full.size())?;
+        let large = Large::from_user_ptr(alloc_nn)?;
+        let old = unsafe { large.as_ref() }.capacity();
+        let resized =
+          unsafe { Large::resize(large, size, false).ok()?.as_ref() };
+        with_heap(|heap| heap.counters().resize_large(old, resized.capacity()));
+        resized.user_slice()
+      }
+    };
+
+    let header_ptr = resized.as_ptr() as *mut Allocation;
+    Some((header_ptr, AllocationOwner::Heap(owner)))
+  }
+
+  unsafe fn realloc_copy(
+    &self,
+    ptr: *mut u8,
+    layout: Layout,
+    new_layout: Layout,
+  ) -> *mut u8 {
+    let new_ptr = self.allocate(new_layout, false);
+    if !new_ptr.is_null() {
+      unsafe {
+        let copy = layout.size().min(new_layout.size());
+        std::ptr::copy_nonoverlapping(ptr, new_ptr, copy);
+        self.deallocate(ptr, layout);
+      }
+    }
+    new_ptr
+  }
+
+  fn allocate(&self, layout: Layout, zeroed: bool) -> *mut u8 {
+    process_init();
+    match self.try_allocate(layout, zeroed) {
+      Ok(ptr) => ptr.as_ptr(),
+      // Only running out of memory is worth reclaiming and retrying for; a
+      // layout too large to describe fails the same way every time.
+      Err(MapError::OutOfMemory) => oom::recover(layout, || {
+        self
+          .try_allocate(layout, zeroed)
+          .map_or(std::ptr::null_mut(), NonNull::as_ptr)
+      }),
+      Err(_) => std::ptr::null_mut(),
+    }
+  }
+
+  fn try_allocate(
+    &self,
+    layout: Layout,
+    zeroed: bool,
+  ) -> Result<NonNull<u8>, MapError> {
+    if let Some(ptr) = guarded::sample(layout) {
+      return Ok(ptr);
+    }
+    if redzone() {
+      return self.alloc_redzoned(layout, zeroed);
+    }
+
+    if Self::is_small(layout)
+      && let Some(ptr) = self.alloc_small(layout, zeroed).and_then(NonNull::new)
+    {
+      return Ok(ptr);
+    }
+
+    self.alloc_header(layout, zeroed)
+  }
+
+  unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+    let Some(ptr_nn) = NonNull::new(ptr) else {
+      return;
+    };
+    // Emergency pool blocks are never reused.
+    if oom::in_pool(ptr_nn) {
+      return;
+    }
+    if guarded::contains(ptr_nn) {
+      guarded::deallocate(ptr_nn);
+      return;
+    }
+    if redzone() {
+      let _ = Self::check_redzone(ptr_nn, layout.size());
+    }
+
+    // Only small blocks live in segments, so a block found in one is freed
+    // as a slot of its class whatever the layout claims.
+    if let Some(segment) = segment_from_ptr(ptr_nn) {
+      let class = unsafe { segment.as_ref() }.class();
+      if layout.size() > class.size.0 {
+        invalid_free(FreeProblem::LayoutMismatch, ptr, Some(class));
+      }
+      self.release_small(segment, ptr_nn);
+      return;
+    }
+
+    self.dealloc_header(ptr, layout);
+  }
+
+  unsafe fn reallocate(
+    &self,
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+  ) -> *mut u8 {
+    let new_layout = match Layout::from_size_align(new_size, layout.align()) {
+      Ok(new_layout) => new_layout,
+      Err(_) => return std::ptr::null_mut(),
+    };
+
+    let Some(ptr_nn) = NonNull::new(ptr) else {
+      return std::ptr::null_mut();
+    };
+    // Resizing in place would have to move the redzone; copying checks the
+    // old one on the way out and lays down a fresh one. Guarded and
+    // emergency pool blocks never grow in place either.
+    if redzone() || guarded::contains(ptr_nn) || oom::in_pool(ptr_nn) {
+      return unsafe { self.realloc_copy(ptr, layout, new_layout) };
+    }
+
+    if let Some(segment) = segment_from_ptr(ptr_nn) {
+      return self.realloc_small(segment, ptr, layout, new_layout);
+    }
+
+    self.realloc_header(ptr, layout, new_layout)
+  }
+}
+
+/// Gives every cached byte it can back to the OS. Shorthand for
+/// `TinyAlloc.collect(CollectLevel::Full)`.
+pub fn trim() -> usize {
+  TinyAlloc.collect(CollectLevel::Full)
+}
+
+/// Current allocator statistics, aggregated over every heap and arena.
+/// Always available, independent of the `metrics` feature.
+pub fn stats() -> Stats {
+  let mut stats = tinyalloc_alloc::static_::stats();
+  if let Some(bootstrap) = BOOTSTRAP_HEAP.get() {
+    stats.add_heap(unsafe { &*bootstrap.heap.get() });
+  }
+  stats
+}
+
+static STATS_REPORT: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn report_stats_at_exit() {
+  let stats = stats();
+  let _ = writeln!(
+    std::io::stderr().lock(),
+    "tinyalloc: {} bytes live, {} allocated in all\n\
+     tinyalloc: {} arenas reserving {} bytes, {} committed, {} in cached \
+     segments\n\
+     tinyalloc: {} bytes of large blocks, {} remote frees pending",
+    stats.live,
+    stats.allocated,
+    stats.arenas,
+    stats.reserved,
+    stats.committed,
+    stats.free_segments,
+    stats.large,
+    stats.pending_remote
+  );
+}
+
+/// Prints [`stats`] to stderr when the process exits.
+pub fn enable_stats_report() {
+  if !STATS_REPORT.swap(true, Ordering::AcqRel) {
+    at_exit(report_stats_at_exit);
+  }
+}
+
+/// A live allocation reported by [`visit_allocations`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllocationInfo {
+  /// The pointer handed to the caller.
+  pub address: NonNull<u8>,
+  /// Bytes usable from `address`, at least the size requested.
+  pub size: usize,
+  /// Size class of a small block, `None` for blocks with a header.
+  pub class: Option<usize>,
+  /// OS id of the thread whose heap holds the block, `None` when the block
+  /// sits in an abandoned segment or was mapped straight from the OS.
+  pub thread: Option<u64>,
+}
+
+fn visit_segment(
+  segment: &Segment,
+  thread: Option<u64>,
+  visit: &mut impl FnMut(&AllocationInfo),
+) {
+  let class = segment.class();
+  segment.for_each_used(|address| {
+    visit(&AllocationInfo {
+      address,
+      size: class.size.0,
+      class: Some(class.id),
+      thread,
+    })
+  });
+}
+
+fn visit_header(
+  header: *mut Allocation,
+  thread: Option<u64>,
+  visit: &mut impl FnMut(&AllocationInfo),
+) {
+  let header = unsafe { &*header };
+  if let Some(address) = NonNull::new(header.user_ptr()) {
+    visit(&AllocationInfo {
+      address,
+      size: header.usable_size(),
+      class: None,
+      thread,
+    });
+  }
+}
+
+fn visit_heap(heap: &Heap, visit: &mut impl FnMut(&AllocationInfo)) {
+  let thread = heap.thread();
+  for segment in heap.segments() {
+    visit_segment(segment, thread, visit);
+  }
+  for large in heap.larges() {
+    let start = large.user_slice().as_ptr() as *mut u8;
+    if let Some(header) = Allocation::at(start) {
+      visit_header(header, thread, visit);
+    }
+  }
+}
+
+/// Calls `visit` once for every live allocation: slots in use in every
+/// segment, large blocks held by every heap, and blocks mapped straight
+/// from the OS.
+///
+/// Each heap is locked while its blocks are reported, so the view of any
+/// one heap is consistent, while heaps are visited one after another.
+/// Blocks freed by another thread that their owner has not collected yet
+/// are still reported. `visit` runs with those locks held and must not
+/// allocate or free through tinyalloc.
+pub fn visit_allocations(mut visit: impl FnMut(&AllocationInfo)) {
+  visit_heaps(|heap| visit_heap(heap, &mut visit));
+  if let Some(bootstrap) = BOOTSTRAP_HEAP.get() {
+    bootstrap.with(|heap| visit_heap(heap, &mut visit));
+  }
+  visit_abandoned(|segment| visit_segment(segment, None, &mut visit));
+  guarded::for_each_live(|address, size| {
+    visit(&AllocationInfo {
+      address,
+      size,
+      class: None,
+      thread: None,
+    })
+  });
+  for header in MAPPED.0.lock().iter() {
+    let header = header as *const Allocation as *mut Allocation;
+    visit_header(header, None, &mut visit);
+  }
+}
+
+unsafe impl GlobalAlloc for TinyAlloc {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    let ptr = self.allocate(layout, false);
+    #[cfg(feature = "profile")]
+    profile::record_alloc(ptr, layout.size());
+    trace::record(
+      EventKind::Alloc,
+      ptr,
+      std::ptr::null_mut(),
+      layout.size(),
+      layout.align(),
+      ptr,
+    );
+    ptr
+  }
+
+  unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+    let ptr = self.allocate(layout, true);
+    #[cfg(feature = "profile")]
+    profile::record_alloc(ptr, layout.size());
+    trace::record(
+      EventKind::AllocZeroed,
+      ptr,
+      std::ptr::null_mut(),
+      layout.size(),
+      layout.align(),
+      ptr,
+    );
+    ptr
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    #[cfg(feature = "profile")]
+    profile::record_dealloc(ptr);
+    trace::record(
+      EventKind::Dealloc,
+      ptr,
+      std::ptr::null_mut(),
+      layout.size(),
+      layout.align(),
+      ptr,
+    );
+    unsafe { self.deallocate(ptr, layout) };
+  }
+
+  unsafe fn realloc(
+    &self,
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+  ) -> *mut u8 {
+    // Forgotten up front, as once moved the old address may be handed out
+    // again by another thread. A failed resize loses the sample.
+    #[cfg(feature = "profile")]
+    profile::record_dealloc(ptr);
+    let new_ptr = unsafe { self.reallocate(ptr, layout, new_size) };
+    #[cfg(feature = "profile")]
+    profile::record_alloc(new_ptr, new_size);
+    trace::record(
+      EventKind::Realloc,
+      new_ptr,
+      ptr,
+      new_size,
+      layout.align(),
+      new_ptr,
+    );
+    new_ptr
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+  };
+
+  use super::*;
+
+  const MIB: usize = 1024 * 1024;
+
+  fn check_alignment(size: usize, align: usize) {
+    let layout = Layout::from_size_align(size, align).unwrap();
+    let ptr = unsafe { TinyAlloc.alloc(layout) };
+    assert!(!ptr.is_null(), "alloc failed for size={size} align={align}");
+    assert_eq!(
+      ptr as usize % align,
+      0,
+      "misaligned pointer for size={size} align={align}"
+    );
+    if TinyAlloc::is_small(layout) {
+      let segment = segment_from_ptr(NonNull::new(ptr).unwrap());
+      assert!(segment.is_some(), "small block outside of any segment");
     } else {
-      let remote_list = heap.remote();
-      let mut remote_guard = remote_list.write();
-      if let Some(allocation_nn) = NonNull::new(allocation) {
-        remote_guard.push(allocation_nn);
+      assert_eq!(
+        Allocation::from(ptr).map(|header| unsafe { (*header).user_ptr() }),
+        Some(ptr),
+        "header not recoverable for size={size} align={align}"
+      );
+    }
+
+    unsafe {
+      ptr.write_bytes(0xA5, size);
+      TinyAlloc.dealloc(ptr, layout);
+    }
+  }
+
+  #[test]
+  fn alloc_honors_every_alignment() {
+    let mut align = 1;
+    while align <= 8 * MIB {
+      check_alignment(1, align);
+      check_alignment(24, align);
+      check_alignment(align, align);
+      align <<= 1;
+    }
+  }
+
+  #[test]
+  fn alloc_honors_alignment_for_large_sizes() {
+    for align in [4096, 64 * 1024, 2 * MIB, 4 * MIB] {
+      check_alignment(3 * MIB, align);
+    }
+  }
+
+  #[test]
+  fn small_allocations_are_header_free() {
+    let layout = Layout::new::<u64>();
+    let ptr = unsafe { TinyAlloc.alloc(layout) };
+    let segment = segment_from_ptr(NonNull::new(ptr).unwrap())
+      .expect("small allocation must live in a segment");
+    assert_eq!(unsafe { segment.as_ref() }.class().size.0, 8);
+    assert!(Allocation::from(ptr).is_none());
+    unsafe { TinyAlloc.dealloc(ptr, layout) };
+  }
+
+  #[test]
+  fn cross_thread_frees_are_returned_to_owner() {
+    let layout = Layout::from_size_align(48, 16).unwrap();
+    let ptrs: Vec<usize> = (0..512)
+      .map(|_| unsafe { TinyAlloc.alloc(layout) } as usize)
+      .collect();
+
+    std::thread::spawn(move || {
+      for ptr in ptrs {
+        unsafe { TinyAlloc.dealloc(ptr as *mut u8, layout) };
       }
+    })
+    .join()
+    .unwrap();
+
+    for _ in 0..1024 {
+      let ptr = unsafe { TinyAlloc.alloc(layout) };
+      assert!(!ptr.is_null());
+      unsafe { TinyAlloc.dealloc(ptr, layout) };
     }
   }
+
+  #[test]
+  fn frees_after_owner_thread_exits() {
+    let small = Layout::from_size_align(40, 8).unwrap();
+    let large = Layout::from_size_align(LARGE_SC_LIMIT + 1, 8).unwrap();
+
+    for _ in 0..8 {
+      let (smalls, big) = std::thread::spawn(move || {
+        let smalls: Vec<usize> = (0..256)
+          .map(|_| unsafe { TinyAlloc.alloc(small) } as usize)
+          .collect();
+        let big = unsafe { TinyAlloc.alloc(large) } as usize;
+        (smalls, big)
+      })
+      .join()
+      .unwrap();
+
+      for ptr in smalls {
+        unsafe { TinyAlloc.dealloc(ptr as *mut u8, small) };
+      }
+      unsafe { TinyAlloc.dealloc(big as *mut u8, large) };
+    }
+  }
+
+  #[test]
+  fn realloc_keeps_pointer_within_class() {
+    let layout = Layout::from_size_align(20, 8).unwrap();
+    let ptr = unsafe { TinyAlloc.alloc(layout) };
+    let class = unsafe {
+      segment_from_ptr(NonNull::new(ptr).unwrap())
+        .unwrap()
+        .as_ref()
+        .class()
+    };
+
+    let grown = unsafe { TinyAlloc.realloc(ptr, layout, class.size.0) };
+    assert_eq!(grown, ptr);
+
+    let new_layout = Layout::from_size_align(class.size.0, 8).unwrap();
+    unsafe { TinyAlloc.dealloc(grown, new_layout) };
+  }
+
+  #[test]
+  fn realloc_preserves_contents_across_growth() {
+    let mut layout = Layout::from_size_align(8, 8).unwrap();
+    let mut ptr = unsafe { TinyAlloc.alloc(layout) };
+    unsafe { ptr.write(0x42) };
+
+    while layout.size() < 16 * MIB {
+      let new_size = layout.size() * 2;
+      ptr = unsafe { TinyAlloc.realloc(ptr, layout, new_size) };
+      assert!(!ptr.is_null());
+      assert_eq!(unsafe { ptr.read() }, 0x42);
+      unsafe { ptr.add(new_size - 1).write(0x17) };
+      layout = Layout::from_size_align(new_size, 8).unwrap();
+    }
+
+    while layout.size() > 8 {
+      let new_size = layout.size() / 4;
+      ptr = unsafe { TinyAlloc.realloc(ptr, layout, new_size) };
+      assert_eq!(unsafe { ptr.read() }, 0x42);
+      layout = Layout::from_size_align(new_size, 8).unwrap();
+    }
+
+    unsafe { TinyAlloc.dealloc(ptr, layout) };
+  }
+
+  #[test]
+  fn realloc_large_in_place_when_capacity_allows() {
+    let layout = Layout::from_size_align(MIB, 4096).unwrap();
+    let ptr = unsafe { TinyAlloc.alloc(layout) };
+    let grown = unsafe { TinyAlloc.realloc(ptr, layout, MIB + 16) };
+    assert_eq!(grown, ptr, "page rounding slack must absorb small growth");
+
+    let new_layout = Layout::from_size_align(MIB + 16, 4096).unwrap();
+    unsafe { TinyAlloc.dealloc(grown, new_layout) };
+  }
+
+  #[test]
+  fn alloc_zeroed_returns_zeroed_memory() {
+    for &size in &[24, 4096, LARGE_SC_LIMIT + 1, 4 * MIB] {
+      let layout = Layout::from_size_align(size, 8).unwrap();
+      let dirty = unsafe { TinyAlloc.alloc(layout) };
+      unsafe { dirty.write_bytes(0xAA, size) };
+      unsafe { TinyAlloc.dealloc(dirty, layout) };
+
+      let ptr = unsafe { TinyAlloc.alloc_zeroed(layout) };
+      assert!(!ptr.is_null());
+      let bytes = unsafe { std::slice::from_raw_parts(ptr, size) };
+      assert!(bytes.iter().all(|&b| b == 0), "size {size} not zeroed");
+      unsafe { TinyAlloc.dealloc(ptr, layout) };
+    }
+  }
+
+  #[test]
+  fn collect_returns_cached_segments() {
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    let ptrs: Vec<usize> = (0..2048)
+      .map(|_| unsafe { TinyAlloc.alloc(layout) } as usize)
+      .collect();
+    for ptr in ptrs {
+      unsafe { TinyAlloc.dealloc(ptr as *mut u8, layout) };
+    }
+
+    assert!(TinyAlloc.collect(CollectLevel::Heap) > 0);
+    assert_eq!(TinyAlloc.collect(CollectLevel::Heap), 0);
+    let _ = trim();
+  }
+
+  #[test]
+  fn stats_report_large_blocks_and_arenas() {
+    let layout = Layout::from_size_align(4 * MIB, 8).unwrap();
+    let small = Layout::new::<u64>();
+    let ptr = unsafe { TinyAlloc.alloc(layout) };
+    let small_ptr = unsafe { TinyAlloc.alloc(small) };
+
+    let stats = stats();
+    assert!(stats.large >= 4 * MIB);
+    assert!(stats.arenas >= 1);
+    assert!(stats.reserved >= stats.committed);
+    assert!(stats.allocated >= stats.live);
+
+    unsafe {
+      TinyAlloc.dealloc(small_ptr, small);
+      TinyAlloc.dealloc(ptr, layout);
+    }
+  }
+
+  #[test]
+  fn visit_allocations_reports_live_blocks() {
+    let small = Layout::from_size_align(40, 8).unwrap();
+    let large = Layout::from_size_align(LARGE_SC_LIMIT + 1, 8).unwrap();
+    let small_ptr = unsafe { TinyAlloc.alloc(small) };
+    let large_ptr = unsafe { TinyAlloc.alloc(large) };
+    let thread = Some(tinyalloc_sys::thread::current_id());
+
+    let mut found = Vec::with_capacity(2);
+    visit_allocations(|info| {
+      if [small_ptr, large_ptr].contains(&info.address.as_ptr()) {
+        found.push(*info);
+      }
+    });
+    let find = |ptr| found.iter().find(|info| info.address.as_ptr() == ptr);
+
+    let class = find_class(40, 8).unwrap();
+    let small_info = find(small_ptr).expect("small block not visited");
+    assert_eq!(small_info.class, Some(class.id));
+    assert_eq!(small_info.size, class.size.0);
+    assert_eq!(small_info.thread, thread);
+
+    let large_info = find(large_ptr).expect("large block not visited");
+    assert_eq!(large_info.class, None);
+    assert!(large_info.size >= large.size());
+    assert_eq!(large_info.thread, thread);
+
+    unsafe {
+      TinyAlloc.dealloc(small_ptr, small);
+      TinyAlloc.dealloc(large_ptr, large);
+    }
+
+    visit_allocations(|info| {
+      assert_ne!(info.address.as_ptr(), large_ptr, "freed block visited");
+    });
+  }
+
+  /// Runs the ignored test `name` in a child process with `env` set, for
+  /// options that would reach the blocks of other tests if set here.
+  fn run_child(name: &str, env: &[(&str, &str)]) -> std::process::Output {
+    std::process::Command::new(std::env::current_exe().unwrap())
+      .args(["--exact", name, "--ignored", "--nocapture"])
+      .envs(env.iter().copied())
+      .output()
+      .unwrap()
+  }
+
+  #[test]
+  fn redzoned_blocks_report_writes_past_their_end() {
+    for size in [100, 3 * MIB] {
+      let layout = Layout::from_size_align(size, 8).unwrap();
+      let ptr = TinyAlloc.alloc_redzoned(layout, false).unwrap();
+      assert_eq!(TinyAlloc::check_redzone(ptr, size), None);
+
+      unsafe { ptr.as_ptr().add(size).write(0) };
+      assert_eq!(TinyAlloc::check_redzone(ptr, size), Some(size));
+      unsafe { TinyAlloc.deallocate(ptr.as_ptr(), layout) };
+    }
+  }
+
+  #[test]
+  #[ignore = "run in a child by redzoned_realloc_reports_the_old_block"]
+  fn redzoned_realloc_child() {
+    if std::env::var_os("TINYALLOC_REDZONE").is_none() {
+      return;
+    }
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let new_layout = Layout::from_size_align(200, 8).unwrap();
+    unsafe {
+      let ptr = TinyAlloc.alloc(layout);
+      ptr.add(layout.size()).write(0);
+      let grown = TinyAlloc.realloc(ptr, layout, new_layout.size());
+      grown.add(new_layout.size()).write(0);
+      TinyAlloc.dealloc(grown, new_layout);
+    }
+  }
+
+  #[test]
+  fn redzoned_realloc_reports_the_old_block() {
+    let output = run_child(
+      "tests::redzoned_realloc_child",
+      &[("TINYALLOC_REDZONE", "1"), ("TINYALLOC_FREE_POLICY", "log")],
+    );
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for size in [100, 200] {
+      let expected = format!("({size} B) was written at offset {size}");
+      assert!(stderr.contains(&expected), "{stderr}");
+    }
+  }
+
+  #[test]
+  #[ignore = "run in a child by oversized_layouts_skip_oom_recovery"]
+  fn oversized_layout_child() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    if std::env::var_os("TINYALLOC_OPTIONS").is_none() {
+      return;
+    }
+    set_oom_handler(Some(|_| {
+      CALLS.fetch_add(1, Ordering::Relaxed);
+      false
+    }));
+    // A valid layout, but one the block header no longer fits beside.
+    let layout = Layout::from_size_align(isize::MAX as usize - 7, 8).unwrap();
+    assert!(unsafe { TinyAlloc.alloc(layout) }.is_null());
+    assert!(unsafe { TinyAlloc.alloc_zeroed(layout) }.is_null());
+    assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+  }
+
+  #[test]
+  fn oversized_layouts_skip_oom_recovery() {
+    let output = run_child(
+      "tests::oversized_layout_child",
+      &[("TINYALLOC_OPTIONS", "oom_retries=1")],
+    );
+    assert!(
+      output.status.success(),
+      "{}",
+      String::from_utf8_lossy(&output.stdout)
+    );
+  }
+
+  #[test]
+  fn redzone_spans_the_slack_of_every_block() {
+    for size in [100, 3 * MIB] {
+      let layout = Layout::from_size_align(size, 8).unwrap();
+      let ptr = NonNull::new(unsafe { TinyAlloc.alloc(layout) }).unwrap();
+      let usable = TinyAlloc::usable_size(ptr).unwrap();
+      assert!(usable >= size);
+
+      redzone::fill(ptr, size, usable);
+      assert_eq!(redzone::check(ptr, size, usable), None);
+      if usable > size {
+        unsafe { ptr.as_ptr().add(size).write(0) };
+        assert_eq!(redzone::check(ptr, size, usable), Some(size));
+      }
+      unsafe { TinyAlloc.dealloc(ptr.as_ptr(), layout) };
+    }
+  }
+
+  #[test]
+  #[ignore = "run in a child by guarded_large_blocks_fault_past_the_end"]
+  fn guarded_large_child() {
+    if std::env::var_os("TINYALLOC_LARGE_GUARD").is_none() {
+      return;
+    }
+    let layout = Layout::from_size_align(2 * MIB, 8).unwrap();
+    let ptr = unsafe { TinyAlloc.alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe {
+      ptr.add(layout.size() - 1).write_volatile(1);
+      ptr.add(layout.size()).write_volatile(1);
+    }
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn guarded_large_blocks_fault_past_the_end() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let output = run_child(
+      "tests::guarded_large_child",
+      &[("TINYALLOC_LARGE_GUARD", "after")],
+    );
+    assert!(
+      matches!(output.status.signal(), Some(libc::SIGSEGV | libc::SIGBUS)),
+      "no fault past the block: {:?}",
+      output.status
+    );
+  }
 }
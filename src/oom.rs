@@ -0,0 +1,250 @@
+use std::{
+  alloc::Layout,
+  cell::Cell,
+  num::NonZeroUsize,
+  ptr::NonNull,
+  sync::atomic::{
+    AtomicBool,
+    AtomicUsize,
+    Ordering,
+  },
+  time::Duration,
+};
+
+use spin::Mutex;
+use tinyalloc_alloc::{
+  allocation::{
+    Allocation,
+    AllocationOwner,
+  },
+  static_::{
+    collect_heaps,
+    release_idle_arenas,
+  },
+};
+use tinyalloc_config::helper::align_up;
+use tinyalloc_sys::{
+  GLOBAL_MAPPER,
+  MapError,
+  mapper::Protection,
+  process::write_stderr,
+  size::page_size,
+};
+
+use crate::BOOTSTRAP_HEAP;
+
+/// Called with the layout that could not be served once the allocator has
+/// given back everything it could. Returning true asks for another try,
+/// so a handler that freed nothing should return false.
+pub type OomHandler = fn(Layout) -> bool;
+
+/// Times the handler is called for one failed allocation by default.
+pub const OOM_RETRIES: usize = 3;
+
+static HANDLER: Mutex<Option<OomHandler>> = Mutex::new(None);
+static RETRIES: AtomicUsize = AtomicUsize::new(OOM_RETRIES);
+
+/// Bounds and fill level of the emergency pool, zero until reserved.
+static POOL_BASE: AtomicUsize = AtomicUsize::new(0);
+static POOL_LEN: AtomicUsize = AtomicUsize::new(0);
+static POOL_USED: AtomicUsize = AtomicUsize::new(0);
+static POOL_WARNED: AtomicBool = AtomicBool::new(false);
+static POOL_LOCK: Mutex<()> = Mutex::new(());
+
+thread_local! {
+  /// Set while this thread runs the handler, so an allocation the handler
+  /// makes that also fails does not call it again.
+  static IN_HANDLER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Installs `handler`, or removes the current one with `None`, and
+/// returns the one it replaces.
+pub fn set_oom_handler(handler: Option<OomHandler>) -> Option<OomHandler> {
+  core::mem::replace(&mut *HANDLER.lock(), handler)
+}
+
+/// Sets how many times the handler may be called, and the allocation
+/// retried, before giving up on one allocation. Defaults to
+/// [`OOM_RETRIES`].
+pub fn set_oom_retries(retries: usize) {
+  RETRIES.store(retries, Ordering::Relaxed);
+}
+
+/// Maps and commits `bytes` up front to serve allocations once the OS
+/// refuses any more, so the program can still report what happened.
+/// Blocks served from the pool are never reused. Does nothing if a pool
+/// is already reserved.
+pub fn reserve_emergency_pool(bytes: usize) -> Result<(), MapError> {
+  let size = NonZeroUsize::new(bytes).ok_or(MapError::InvalidSize)?;
+  let _guard = POOL_LOCK.lock();
+  if POOL_BASE.load(Ordering::Acquire) != 0 {
+    return Ok(());
+  }
+
+  let mapped = GLOBAL_MAPPER.map(size)?;
+  if let Err(error) =
+    GLOBAL_MAPPER.protect(mapped, Protection::Read | Protection::Write)
+  {
+    GLOBAL_MAPPER.unmap(mapped);
+    return Err(error);
+  }
+  let base = mapped.as_ptr() as *mut u8;
+  // Touch every page so the memory is there when it is needed.
+  for offset in (0..mapped.len()).step_by(page_size()) {
+    unsafe { base.add(offset).write_volatile(0) };
+  }
+
+  POOL_LEN.store(mapped.len(), Ordering::Relaxed);
+  POOL_BASE.store(base as usize, Ordering::Release);
+  Ok(())
+}
+
+/// Whether `ptr` was served from the emergency pool.
+pub fn in_pool(ptr: NonNull<u8>) -> bool {
+  let base = POOL_BASE.load(Ordering::Acquire);
+  let addr = ptr.as_ptr() as usize;
+  base != 0 && addr >= base && addr < base + POOL_LEN.load(Ordering::Relaxed)
+}
+
+/// Carves a block for `layout` off the pool. The memory reads as zero.
+fn pool_allocate(layout: Layout) -> *mut u8 {
+  let base = POOL_BASE.load(Ordering::Acquire);
+  let total = match Allocation::total_layout(layout) {
+    Some(total) if base != 0 => total,
+    _ => return std::ptr::null_mut(),
+  };
+  let len = POOL_LEN.load(Ordering::Relaxed);
+
+  let mut used = POOL_USED.load(Ordering::Relaxed);
+  let start = loop {
+    let start = align_up(base + used, total.align()) - base;
+    let Some(end) = start.checked_add(total.size()).filter(|&end| end <= len)
+    else {
+      return std::ptr::null_mut();
+    };
+    match POOL_USED.compare_exchange_weak(
+      used,
+      end,
+      Ordering::Relaxed,
+      Ordering::Relaxed,
+    ) {
+      Ok(_) => break start,
+      Err(current) => used = current,
+    }
+  };
+
+  if !POOL_WARNED.swap(true, Ordering::Relaxed) {
+    write_stderr(
+      b"tinyalloc: out of memory, serving from the emergency pool\n",
+    );
+  }
+  let block = (base + start) as *mut u8;
+  let mem = NonNull::slice_from_raw_parts(
+    unsafe { NonNull::new_unchecked(block) },
+    total.size(),
+  );
+  unsafe {
+    Allocation::write(
+      block as *mut Allocation,
+      AllocationOwner::Mapper(mem),
+      total,
+      layout,
+    )
+  }
+}
+
+/// Frees the pending cross-thread blocks of every heap, returns their
+/// cached empty segments and unmaps every arena left without segments.
+fn reclaim() {
+  collect_heaps();
+  if let Some(bootstrap) = BOOTSTRAP_HEAP.get() {
+    bootstrap.with(|heap| heap.collect());
+  }
+  release_idle_arenas(Duration::ZERO, true);
+}
+
+/// Runs the handler for `layout` unless this thread is already in it.
+fn call_handler(handler: OomHandler, layout: Layout) -> bool {
+  let entered = IN_HANDLER
+    .try_with(|flag| !flag.replace(true))
+    .unwrap_or(false);
+  if !entered {
+    return false;
+  }
+  let again = handler(layout);
+  let _ = IN_HANDLER.try_with(|flag| flag.set(false));
+  again
+}
+
+/// Called once `allocate` came back null for `layout`. Reclaims what the
+/// allocator holds and calls `allocate` again, then lets the handler free
+/// memory between retries, then falls back to the emergency pool.
+#[cold]
+pub fn recover(layout: Layout, allocate: impl Fn() -> *mut u8) -> *mut u8 {
+  reclaim();
+  let ptr = allocate();
+  if !ptr.is_null() {
+    return ptr;
+  }
+
+  let handler = *HANDLER.lock();
+  if let Some(handler) = handler {
+    for _ in 0..RETRIES.load(Ordering::Relaxed) {
+      if !call_handler(handler, layout) {
+        break;
+      }
+      let ptr = allocate();
+      if !ptr.is_null() {
+        return ptr;
+      }
+    }
+  }
+
+  pool_allocate(layout)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn recovery_retries_until_the_handler_gives_up() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    // Too big for any emergency pool another test reserves.
+    let layout = Layout::from_size_align(1 << 30, 8).unwrap();
+    let attempts = Cell::new(0);
+    let failing = || {
+      attempts.set(attempts.get() + 1);
+      std::ptr::null_mut()
+    };
+
+    let previous =
+      set_oom_handler(Some(|_| CALLS.fetch_add(1, Ordering::Relaxed) < 1));
+    assert!(recover(layout, failing).is_null());
+    set_oom_handler(previous);
+
+    assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+    // Once after reclaiming, once after the handler asked for a retry.
+    assert_eq!(attempts.get(), 2);
+  }
+
+  #[test]
+  fn pool_blocks_carry_a_header() {
+    reserve_emergency_pool(64 * 1024).unwrap();
+    let layout = Layout::from_size_align(1000, 64).unwrap();
+    let first = pool_allocate(layout);
+    let second = pool_allocate(layout);
+
+    for ptr in [first, second] {
+      let block = NonNull::new(ptr).unwrap();
+      assert!(in_pool(block));
+      assert!((ptr as usize).is_multiple_of(64));
+      let header = Allocation::from(ptr).unwrap();
+      assert!(unsafe { &*header }.usable_size() >= 1000);
+    }
+    assert!(second as usize >= first as usize + 1000);
+
+    let huge = Layout::from_size_align(1 << 20, 8).unwrap();
+    assert!(pool_allocate(huge).is_null());
+  }
+}
@@ -0,0 +1,298 @@
+use std::{
+  ffi::CStr,
+  str::FromStr,
+};
+
+use tinyalloc_alloc::options::{
+  FreePolicy,
+  LargeGuard,
+  set_arena_growth,
+  set_arena_initial_size,
+  set_arena_release_delay,
+  set_free_policy,
+  set_large_guard,
+  set_quarantine_bytes,
+  set_redzone,
+  set_remote_batch,
+  set_remote_check,
+  set_retained_segments,
+  set_sample_rate,
+};
+use tinyalloc_config::config::ARENA_RELEASE_DELAY_MS;
+use tinyalloc_sys::process::{
+  env,
+  write_stderr,
+};
+
+use crate::{
+  enable_stats_report,
+  leak::enable_leak_report,
+  oom::{
+    reserve_emergency_pool,
+    set_oom_retries,
+  },
+};
+
+/// Comma-separated `key=value` pairs, read once before the first
+/// allocation. The single-purpose `TINYALLOC_*` variables are read after
+/// it and win where both are set. Sizes and counts take an optional `k`,
+/// `m` or `g` suffix, the growth factor and delay are plain numbers, and
+/// switches take `1`, `0`, `on` or `off`:
+///
+/// - `arena_size`, `arena_growth`: size of the first arenas and the factor
+///   later ones grow by
+/// - `retain_segments`: empty segments each class keeps cached
+/// - `remote_batch`, `remote_check`: remote frees a heap lets pile up, and
+///   operations between its checks for them
+/// - `arena_release`: `eager`, `delayed` or `never`, when arenas left
+///   without live segments are unmapped, or `arena_release_delay` in
+///   milliseconds. A segment handed back to its arena is always
+///   decommitted at once; that is not configurable, though
+///   `retain_segments` keeps some empty segments from reaching the arena
+/// - `oom_retries`: times an out-of-memory handler may ask for a retry
+/// - `emergency_pool`: bytes to reserve for when memory runs out
+/// - `stats`: print allocator statistics at exit
+/// - `leak`: print a leak report at exit, or `fail` to also fail the exit
+/// - `debug`: turn redzones, aborting on bad frees and guard pages after
+///   large blocks on or off together
+/// - `quarantine`, `free_policy`, `redzone`, `sample_rate`, `large_guard`:
+///   as their own variables
+const OPTIONS_ENV: &CStr = c"TINYALLOC_OPTIONS";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Setting {
+  ArenaSize(usize),
+  ArenaGrowth(usize),
+  RetainSegments(usize),
+  RemoteBatch(usize),
+  RemoteCheck(usize),
+  ReleaseDelay(u64),
+  OomRetries(usize),
+  EmergencyPool(usize),
+  Stats(bool),
+  Leak { on: bool, fail: bool },
+  Debug(bool),
+  Quarantine(usize),
+  FreePolicy(FreePolicy),
+  Redzone(bool),
+  SampleRate(usize),
+  LargeGuard(LargeGuard),
+}
+
+/// A number with an optional binary `k`, `m` or `g` suffix.
+fn size(value: &[u8]) -> Option<usize> {
+  let (digits, shift) = match value.last()?.to_ascii_lowercase() {
+    b'k' => (&value[..value.len() - 1], 10),
+    b'm' => (&value[..value.len() - 1], 20),
+    b'g' => (&value[..value.len() - 1], 30),
+    _ => (value, 0),
+  };
+  let number: usize = str::from_utf8(digits).ok()?.parse().ok()?;
+  number.checked_mul(1 << shift)
+}
+
+/// A plain number, for values that are not byte sizes.
+fn number<T: FromStr>(value: &[u8]) -> Option<T> {
+  str::from_utf8(value).ok()?.parse().ok()
+}
+
+fn switch(value: &[u8]) -> Option<bool> {
+  match value {
+    b"1" | b"on" => Some(true),
+    b"0" | b"off" => Some(false),
+    _ => None,
+  }
+}
+
+fn setting(key: &[u8], value: &[u8]) -> Option<Setting> {
+  Some(match key {
+    b"arena_size" => Setting::ArenaSize(size(value)?),
+    b"arena_growth" => Setting::ArenaGrowth(number(value)?),
+    b"retain_segments" => Setting::RetainSegments(size(value)?),
+    b"remote_batch" => Setting::RemoteBatch(size(value)?),
+    b"remote_check" => Setting::RemoteCheck(size(value)?),
+    b"arena_release" => Setting::ReleaseDelay(match value {
+      b"eager" => 0,
+      b"delayed" => ARENA_RELEASE_DELAY_MS,
+      b"never" => u64::MAX,
+      _ => return None,
+    }),
+    b"arena_release_delay" => Setting::ReleaseDelay(number(value)?),
+    b"oom_retries" => Setting::OomRetries(size(value)?),
+    b"emergency_pool" => Setting::EmergencyPool(size(value)?),
+    b"stats" => Setting::Stats(switch(value)?),
+    b"leak" => match value {
+      b"fail" => Setting::Leak {
+        on: true,
+        fail: true,
+      },
+      _ => Setting::Leak {
+        on: switch(value)?,
+        fail: false,
+      },
+    },
+    b"debug" => Setting::Debug(switch(value)?),
+    b"quarantine" => Setting::Quarantine(size(value)?),
+    b"free_policy" => Setting::FreePolicy(FreePolicy::parse(value)?),
+    b"redzone" => Setting::Redzone(switch(value)?),
+    b"sample_rate" => Setting::SampleRate(size(value)?),
+    b"large_guard" => Setting::LargeGuard(LargeGuard::parse(value)?),
+    _ => return None,
+  })
+}
+
+/// Each entry of `options` in order, or the text of an entry that is not
+/// a known `key=value` pair. Empty entries are skipped.
+fn settings(
+  options: &[u8],
+) -> impl Iterator<Item = Result<Setting, &[u8]>> + '_ {
+  options
+    .split(|&byte| byte == b',')
+    .filter(|entry| !entry.is_empty())
+    .map(|entry| {
+      let mut parts = entry.splitn(2, |&byte| byte == b'=');
+      let key = parts.next().unwrap_or_default();
+      parts
+        .next()
+        .and_then(|value| setting(key, value))
+        .ok_or(entry)
+    })
+}
+
+fn apply(setting: Setting) {
+  match setting {
+    Setting::ArenaSize(bytes) => set_arena_initial_size(bytes),
+    Setting::ArenaGrowth(factor) => set_arena_growth(factor),
+    Setting::RetainSegments(count) => set_retained_segments(count),
+    Setting::RemoteBatch(count) => set_remote_batch(count),
+    Setting::RemoteCheck(operations) => set_remote_check(operations),
+    Setting::ReleaseDelay(millis) => set_arena_release_delay(millis),
+    Setting::OomRetries(retries) => set_oom_retries(retries),
+    Setting::EmergencyPool(bytes) => {
+      if reserve_emergency_pool(bytes).is_err() {
+        write_stderr(b"tinyalloc: cannot reserve the emergency pool\n");
+      }
+    }
+    Setting::Stats(on) => {
+      if on {
+        enable_stats_report();
+      }
+    }
+    Setting::Leak { on, fail } => {
+      if on {
+        enable_leak_report(fail);
+      }
+    }
+    Setting::Debug(on) => {
+      set_redzone(on);
+      set_free_policy(if on {
+        FreePolicy::Abort
+      } else {
+        FreePolicy::Ignore
+      });
+      set_large_guard(if on {
+        LargeGuard::After
+      } else {
+        LargeGuard::Off
+      });
+    }
+    Setting::Quarantine(bytes) => set_quarantine_bytes(bytes),
+    Setting::FreePolicy(policy) => set_free_policy(policy),
+    Setting::Redzone(on) => set_redzone(on),
+    Setting::SampleRate(rate) => set_sample_rate(rate),
+    Setting::LargeGuard(guard) => set_large_guard(guard),
+  }
+}
+
+/// Applies `TINYALLOC_OPTIONS`, reporting entries it does not understand
+/// on stderr. Allocates nothing, so it can run ahead of the first
+/// allocation.
+pub fn init_from_env() {
+  let Some(options) = env(OPTIONS_ENV) else {
+    return;
+  };
+  for setting in settings(options.to_bytes()) {
+    match setting {
+      Ok(setting) => apply(setting),
+      Err(entry) => {
+        write_stderr(b"tinyalloc: ignoring TINYALLOC_OPTIONS entry `");
+        write_stderr(entry);
+        write_stderr(b"`\n");
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn options_parse_in_order() {
+    let parsed: Vec<_> = settings(
+      b"arena_size=16m,retain_segments=4,,arena_release=never,leak=fail,\
+        debug=1,free_policy=log,arena_release_delay=250,emergency_pool=1m",
+    )
+    .collect();
+    assert_eq!(
+      parsed,
+      [
+        Ok(Setting::ArenaSize(16 << 20)),
+        Ok(Setting::RetainSegments(4)),
+        Ok(Setting::ReleaseDelay(u64::MAX)),
+        Ok(Setting::Leak {
+          on: true,
+          fail: true,
+        }),
+        Ok(Setting::Debug(true)),
+        Ok(Setting::FreePolicy(FreePolicy::Log)),
+        Ok(Setting::ReleaseDelay(250)),
+        Ok(Setting::EmergencyPool(1 << 20)),
+      ]
+    );
+  }
+
+  #[test]
+  fn bad_entries_are_returned_whole() {
+    let parsed: Vec<_> =
+      settings(b"stats=maybe,colour=blue,remote_batch,sample_rate=2K")
+        .collect();
+    assert_eq!(
+      parsed,
+      [
+        Err(&b"stats=maybe"[..]),
+        Err(&b"colour=blue"[..]),
+        Err(&b"remote_batch"[..]),
+        Ok(Setting::SampleRate(2048)),
+      ]
+    );
+  }
+
+  #[test]
+  fn factors_and_delays_take_no_suffix() {
+    let parsed: Vec<_> = settings(
+      b"arena_growth=2,arena_growth=2k,arena_release_delay=1500,\
+        arena_release_delay=1k",
+    )
+    .collect();
+    assert_eq!(
+      parsed,
+      [
+        Ok(Setting::ArenaGrowth(2)),
+        Err(&b"arena_growth=2k"[..]),
+        Ok(Setting::ReleaseDelay(1500)),
+        Err(&b"arena_release_delay=1k"[..]),
+      ]
+    );
+  }
+
+  #[test]
+  fn sizes_take_binary_suffixes() {
+    assert_eq!(size(b"64"), Some(64));
+    assert_eq!(size(b"4k"), Some(4096));
+    assert_eq!(size(b"2G"), Some(2 << 30));
+    assert_eq!(size(b"m"), None);
+    assert_eq!(size(b"-1"), None);
+    assert_eq!(size(b"99999999999999999999g"), None);
+  }
+}
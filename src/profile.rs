@@ -0,0 +1,856 @@
+use std::{
+  cell::Cell,
+  collections::{
+    BTreeMap,
+    HashMap,
+  },
+  ffi::{
+    CStr,
+    c_void,
+  },
+  io::{
+    self,
+    Write,
+  },
+  mem,
+  num::NonZeroUsize,
+  sync::atomic::{
+    AtomicU16,
+    AtomicUsize,
+    Ordering,
+  },
+  time::{
+    SystemTime,
+    UNIX_EPOCH,
+  },
+};
+
+use spin::Mutex;
+use tinyalloc_sys::{
+  process::env,
+  region::Region,
+  thread,
+};
+
+/// Set to `1` to sample about every [`PROFILE_INTERVAL`] bytes allocated,
+/// or to a number of bytes to sample at that interval instead.
+const PROFILE_ENV: &CStr = c"TINYALLOC_PROFILE";
+/// Mean number of bytes allocated between two samples by default.
+pub const PROFILE_INTERVAL: usize = 512 * 1024;
+
+/// Frames kept per sampled stack, the allocator's own included.
+const DEPTH: usize = 64;
+const STACK_SLOTS: usize = 4096;
+const OBJECT_SLOTS: usize = 16384;
+const FILTER_BUCKETS: usize = 4096;
+
+static INTERVAL: AtomicUsize = AtomicUsize::new(0);
+/// Sampled objects not yet freed, so frees skip the lookup when there are
+/// none.
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+/// Sampled objects not yet freed per address bucket, letting most frees
+/// skip the lock.
+static FILTER: [AtomicU16; FILTER_BUCKETS] =
+  [const { AtomicU16::new(0) }; FILTER_BUCKETS];
+static PROFILE: Mutex<Option<Tables>> = Mutex::new(None);
+/// Held by tests that turn sampling on, as the interval is shared.
+#[cfg(test)]
+pub(crate) static SAMPLING: Mutex<()> = Mutex::new(());
+
+/// Which totals a profile reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProfileKind {
+  /// Sampled objects that have not been freed yet.
+  Live,
+  /// Every sampled allocation since profiling started, freed or not.
+  Cumulative,
+}
+
+/// How a profile is written out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProfileFormat {
+  /// An uncompressed `profile.proto` message, as read by `pprof`.
+  Pprof,
+  /// One `caller;...;callee bytes` line per stack, as read by flame graph
+  /// tools.
+  Collapsed,
+}
+
+/// Estimated objects and bytes a sample stands for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Totals {
+  objects: u64,
+  bytes: u64,
+}
+
+impl Totals {
+  /// Scales one allocation of `size` bytes by the odds of sampling it, so
+  /// that summing samples estimates the totals of every allocation.
+  fn sampled(size: usize, interval: usize) -> Self {
+    let odds = -(-(size as f64) / interval as f64).exp_m1();
+    Self {
+      objects: (1.0 / odds).round() as u64,
+      bytes: (size as f64 / odds).round() as u64,
+    }
+  }
+
+  fn add(&mut self, other: Self) {
+    self.objects += other.objects;
+    self.bytes += other.bytes;
+  }
+
+  fn sub(&mut self, other: Self) {
+    self.objects -= other.objects;
+    self.bytes -= other.bytes;
+  }
+}
+
+#[derive(Clone, Copy)]
+struct Stack {
+  /// Zero for an unused slot.
+  depth: usize,
+  frames: [usize; DEPTH],
+  allocated: Totals,
+  live: Totals,
+}
+
+impl Stack {
+  fn frames(&self) -> &[usize] {
+    &self.frames[..self.depth]
+  }
+}
+
+#[derive(Clone, Copy)]
+struct Object {
+  /// Zero for an unused slot.
+  address: usize,
+  stack: usize,
+  weight: Totals,
+}
+
+impl Object {
+  const EMPTY: Self = Self {
+    address: 0,
+    stack: 0,
+    weight: Totals {
+      objects: 0,
+      bytes: 0,
+    },
+  };
+}
+
+fn mix(mut value: u64) -> u64 {
+  value ^= value >> 30;
+  value = value.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+  value ^= value >> 27;
+  value = value.wrapping_mul(0x94D0_49BB_1331_11EB);
+  value ^ (value >> 31)
+}
+
+/// Sampled stacks, deduplicated, and the sampled objects still live. Both
+/// are open addressing tables in a mapping of their own, so recording a
+/// sample never calls back into the allocator.
+struct Tables {
+  _region: Region,
+  stacks: &'static mut [Stack],
+  objects: &'static mut [Object],
+  stacks_used: usize,
+  objects_used: usize,
+}
+
+// Only reached through `PROFILE`, which serialises every access.
+unsafe impl Send for Tables {}
+
+impl Tables {
+  /// Maps tables for `stack_slots` stacks and `object_slots` objects, both
+  /// powers of two.
+  fn new(stack_slots: usize, object_slots: usize) -> Option<Self> {
+    let stacks_len = stack_slots * mem::size_of::<Stack>();
+    let objects_len = object_slots * mem::size_of::<Object>();
+    let mut region =
+      Region::new(NonZeroUsize::new(stacks_len + objects_len)?).ok()?;
+    region.activate().ok()?;
+
+    // A fresh mapping reads as zero, which is an unused slot in both.
+    let base = region.as_ptr();
+    let stacks = unsafe {
+      std::slice::from_raw_parts_mut(base.cast::<Stack>(), stack_slots)
+    };
+    let objects = unsafe {
+      std::slice::from_raw_parts_mut(
+        base.add(stacks_len).cast::<Object>(),
+        object_slots,
+      )
+    };
+    Some(Self {
+      _region: region,
+      stacks,
+      objects,
+      stacks_used: 0,
+      objects_used: 0,
+    })
+  }
+
+  /// Index of the slot holding `frames`, claiming one if the stack is new.
+  fn stack(&mut self, frames: &[usize]) -> Option<usize> {
+    let hash = frames
+      .iter()
+      .fold(0, |hash, &frame| mix(hash ^ frame as u64));
+    let full = (self.stacks_used + 1) * 4 > self.stacks.len() * 3;
+    let mask = self.stacks.len() - 1;
+    let mut index = hash as usize & mask;
+    loop {
+      let stack = &mut self.stacks[index];
+      if stack.depth == 0 {
+        if frames.is_empty() || full {
+          return None;
+        }
+        stack.depth = frames.len();
+        stack.frames[..frames.len()].copy_from_slice(frames);
+        self.stacks_used += 1;
+        return Some(index);
+      }
+      if stack.frames() == frames {
+        return Some(index);
+      }
+      index = (index + 1) & mask;
+    }
+  }
+
+  fn home(&self, address: usize) -> usize {
+    mix(address as u64) as usize & (self.objects.len() - 1)
+  }
+
+  /// Records an allocation at `address` made from `frames`. Returns false
+  /// if only its stack's cumulative totals could be updated.
+  fn record(
+    &mut self,
+    address: usize,
+    frames: &[usize],
+    weight: Totals,
+  ) -> bool {
+    let Some(stack) = self.stack(frames) else {
+      return false;
+    };
+    self.stacks[stack].allocated.add(weight);
+    if (self.objects_used + 1) * 4 > self.objects.len() * 3 {
+      return false;
+    }
+
+    let mask = self.objects.len() - 1;
+    let mut index = self.home(address);
+    while self.objects[index].address != 0 {
+      index = (index + 1) & mask;
+    }
+    self.objects[index] = Object {
+      address,
+      stack,
+      weight,
+    };
+    self.objects_used += 1;
+    self.stacks[stack].live.add(weight);
+    true
+  }
+
+  /// Forgets the object at `address`, returning it if it was sampled.
+  fn remove(&mut self, address: usize) -> Option<Object> {
+    let mask = self.objects.len() - 1;
+    let mut index = self.home(address);
+    while self.objects[index].address != address {
+      if self.objects[index].address == 0 {
+        return None;
+      }
+      index = (index + 1) & mask;
+    }
+
+    let removed = self.objects[index];
+    self.stacks[removed.stack].live.sub(removed.weight);
+    self.objects_used -= 1;
+
+    // Shifts later entries of the probe run back into the hole, so lookups
+    // never stop short at it. Entries whose home lies between the hole and
+    // themselves stay put.
+    let mut hole = index;
+    let mut next = index;
+    loop {
+      next = (next + 1) & mask;
+      let address = self.objects[next].address;
+      if address == 0 {
+        break;
+      }
+      let home = self.home(address);
+      if next.wrapping_sub(home) & mask >= next.wrapping_sub(hole) & mask {
+        self.objects[hole] = self.objects[next];
+        hole = next;
+      }
+    }
+    self.objects[hole] = Object::EMPTY;
+    Some(removed)
+  }
+
+  /// Frames and totals of every stack with any, per `kind`.
+  fn snapshot(&self, kind: ProfileKind) -> Vec<(Vec<usize>, Totals)> {
+    self
+      .stacks
+      .iter()
+      .filter(|stack| stack.depth > 0)
+      .map(|stack| {
+        let totals = match kind {
+          ProfileKind::Live => stack.live,
+          ProfileKind::Cumulative => stack.allocated,
+        };
+        (stack.frames().to_vec(), totals)
+      })
+      .filter(|(_, totals)| totals.objects > 0)
+      .collect()
+  }
+}
+
+struct Sampler {
+  /// Bytes left to allocate before the next sample, zero until drawn.
+  left: Cell<usize>,
+  seed: Cell<u64>,
+  /// Set while the thread is inside the profiler, whose own allocations
+  /// are neither sampled nor looked up.
+  busy: Cell<bool>,
+}
+
+thread_local! {
+  static SAMPLER: Sampler = const {
+    Sampler {
+      left: Cell::new(0),
+      seed: Cell::new(0),
+      busy: Cell::new(false),
+    }
+  };
+}
+
+impl Sampler {
+  /// Uniform in `(0, 1]`.
+  fn uniform(&self) -> f64 {
+    let mut seed = self.seed.get();
+    if seed == 0 {
+      // Thread ids are small, and xorshift takes a while to spread them.
+      seed = mix(thread::current_id()) | 1;
+    }
+    seed ^= seed << 13;
+    seed ^= seed >> 7;
+    seed ^= seed << 17;
+    self.seed.set(seed);
+    ((seed >> 11) + 1) as f64 / (1u64 << 53) as f64
+  }
+
+  /// Draws the bytes until the next sample, exponentially distributed with
+  /// mean `interval`, so every byte is equally likely to be sampled.
+  fn draw(&self, interval: usize) -> usize {
+    (-self.uniform().ln() * interval as f64) as usize + 1
+  }
+
+  fn sample(&self, size: usize, interval: usize) -> bool {
+    let mut left = self.left.get();
+    if left == 0 {
+      left = self.draw(interval);
+    }
+    if size < left {
+      self.left.set(left - size);
+      return false;
+    }
+    self.left.set(self.draw(interval));
+    true
+  }
+}
+
+/// Runs `f` with the calling thread marked as inside the profiler.
+fn unsampled<R>(f: impl FnOnce() -> R) -> R {
+  let was_busy = SAMPLER
+    .try_with(|sampler| sampler.busy.replace(true))
+    .unwrap_or(true);
+  let result = f();
+  if !was_busy {
+    let _ = SAMPLER.try_with(|sampler| sampler.busy.set(false));
+  }
+  result
+}
+
+fn bucket(address: usize) -> &'static AtomicU16 {
+  &FILTER[mix(address as u64) as usize & (FILTER_BUCKETS - 1)]
+}
+
+fn track(address: usize) {
+  bucket(address).fetch_add(1, Ordering::Relaxed);
+  LIVE.fetch_add(1, Ordering::Relaxed);
+}
+
+fn untrack(address: usize) {
+  bucket(address).fetch_sub(1, Ordering::Relaxed);
+  LIVE.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Samples the allocation of `size` bytes at `ptr` when it crosses the
+/// calling thread's next sampling point.
+#[inline]
+pub fn record_alloc(ptr: *mut u8, size: usize) {
+  let interval = INTERVAL.load(Ordering::Relaxed);
+  if interval == 0 || ptr.is_null() {
+    return;
+  }
+  let sampled = SAMPLER
+    .try_with(|sampler| !sampler.busy.get() && sampler.sample(size, interval))
+    .unwrap_or(false);
+  if sampled {
+    unsampled(|| sample(ptr as usize, size, interval));
+  }
+}
+
+#[cold]
+fn sample(address: usize, size: usize, interval: usize) {
+  let mut frames = [0; DEPTH];
+  let mut depth = 0;
+  // Only walks the stack; symbols are resolved when a profile is written.
+  unsafe {
+    backtrace::trace_unsynchronized(|frame| {
+      let ip = frame.ip() as usize;
+      if ip == 0 {
+        return false;
+      }
+      frames[depth] = ip;
+      depth += 1;
+      depth < DEPTH
+    })
+  };
+
+  let mut profile = PROFILE.lock();
+  if profile.is_none() {
+    *profile = Tables::new(STACK_SLOTS, OBJECT_SLOTS);
+  }
+  let Some(tables) = profile.as_mut() else {
+    return;
+  };
+  // A sample that outlived its object, freed while the profiler was busy.
+  if tables.remove(address).is_some() {
+    untrack(address);
+  }
+  let weight = Totals::sampled(size, interval);
+  if tables.record(address, &frames[..depth], weight) {
+    track(address);
+  }
+}
+
+/// Drops the sample of the object at `ptr`, if there is one, as it is
+/// freed.
+#[inline]
+pub fn record_dealloc(ptr: *mut u8) {
+  if LIVE.load(Ordering::Relaxed) == 0
+    || bucket(ptr as usize).load(Ordering::Relaxed) == 0
+  {
+    return;
+  }
+  forget(ptr as usize);
+}
+
+#[cold]
+fn forget(address: usize) {
+  // The profiler's own frees, some made with the lock held, are never of
+  // sampled objects.
+  if SAMPLER
+    .try_with(|sampler| sampler.busy.get())
+    .unwrap_or(false)
+  {
+    return;
+  }
+  let mut profile = PROFILE.lock();
+  if let Some(tables) = profile.as_mut()
+    && tables.remove(address).is_some()
+  {
+    untrack(address);
+  }
+}
+
+/// Starts sampling about once every `interval` bytes allocated, or stops
+/// with zero. Samples already taken stay in the profile.
+pub fn set_profile_interval(interval: usize) {
+  INTERVAL.store(interval, Ordering::Relaxed);
+}
+
+/// Turns sampling on when the environment asks for it.
+pub fn init_from_env() {
+  let Some(value) = env(PROFILE_ENV) else {
+    return;
+  };
+  match value.to_bytes() {
+    b"" | b"0" => {}
+    b"1" => set_profile_interval(PROFILE_INTERVAL),
+    _ => {
+      if let Some(interval) = value.to_str().ok().and_then(|v| v.parse().ok()) {
+        set_profile_interval(interval);
+      }
+    }
+  }
+}
+
+/// One function a return address resolved to. Inlined calls resolve to
+/// several, innermost first.
+struct Frame {
+  name: String,
+  file: String,
+  line: u64,
+}
+
+type Symbols = HashMap<usize, Vec<Frame>>;
+
+fn symbolize(ip: usize) -> Vec<Frame> {
+  let mut frames = Vec::new();
+  // A return address points past its call, possibly into the next line.
+  backtrace::resolve(ip.saturating_sub(1) as *mut c_void, |symbol| {
+    frames.push(Frame {
+      name: symbol
+        .name()
+        .map_or_else(|| format!("{ip:#x}"), |name| format!("{name:#}")),
+      file: symbol
+        .filename()
+        .map(|file| file.display().to_string())
+        .unwrap_or_default(),
+      line: symbol.lineno().map_or(0, u64::from),
+    });
+  });
+  if frames.is_empty() {
+    frames.push(Frame {
+      name: format!("{ip:#x}"),
+      file: String::new(),
+      line: 0,
+    });
+  }
+  frames
+}
+
+/// Whether `name` is an entry point of the global allocator or of the
+/// exported C functions, every frame below which is the allocator's or the
+/// profiler's own.
+fn is_allocator(name: &str) -> bool {
+  name.contains("as core::alloc::global::GlobalAlloc>::")
+    || name.contains("as core::alloc::Allocator>::")
+    || name.starts_with("alloc::alloc::")
+    || ["__rust_alloc", "__rust_alloc_zeroed", "__rust_realloc"]
+      .iter()
+      .any(|entry| name.ends_with(entry))
+    || [
+      "malloc",
+      "calloc",
+      "realloc",
+      "free",
+      "aligned_alloc",
+      "posix_memalign",
+    ]
+    .contains(&name)
+}
+
+/// Symbolizes every stack and cuts it at the outermost allocator entry
+/// point, leaving the caller that allocated as the leaf.
+fn resolve(
+  stacks: Vec<(Vec<usize>, Totals)>,
+) -> (Vec<(Vec<usize>, Totals)>, Symbols) {
+  let mut symbols = Symbols::new();
+  let stacks = stacks
+    .into_iter()
+    .map(|(mut ips, totals)| {
+      for &ip in &ips {
+        symbols.entry(ip).or_insert_with(|| symbolize(ip));
+      }
+      let cut = ips
+        .iter()
+        .rposition(|ip| symbols[ip].iter().any(|f| is_allocator(&f.name)))
+        .map_or(0, |index| index + 1);
+      ips.drain(..cut);
+      (ips, totals)
+    })
+    .filter(|(ips, _)| !ips.is_empty())
+    .collect();
+  (stacks, symbols)
+}
+
+fn write_collapsed(
+  stacks: &[(Vec<usize>, Totals)],
+  symbols: &Symbols,
+  out: &mut impl Write,
+) -> io::Result<()> {
+  let mut lines = BTreeMap::<String, u64>::new();
+  for (ips, totals) in stacks {
+    let line = ips
+      .iter()
+      .rev()
+      .flat_map(|ip| symbols[ip].iter().rev())
+      .map(|frame| frame.name.replace(';', ","))
+      .collect::<Vec<_>>()
+      .join(";");
+    *lines.entry(line).or_default() += totals.bytes;
+  }
+  for (line, bytes) in lines {
+    writeln!(out, "{line} {bytes}")?;
+  }
+  Ok(())
+}
+
+/// Just enough of a protobuf writer for `profile.proto`.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+  fn varint(&mut self, mut value: u64) {
+    while value >= 0x80 {
+      self.0.push(value as u8 | 0x80);
+      value >>= 7;
+    }
+    self.0.push(value as u8);
+  }
+
+  fn int(&mut self, field: u64, value: u64) {
+    if value != 0 {
+      self.varint(field << 3);
+      self.varint(value);
+    }
+  }
+
+  fn bytes(&mut self, field: u64, bytes: &[u8]) {
+    self.varint((field << 3) | 2);
+    self.varint(bytes.len() as u64);
+    self.0.extend_from_slice(bytes);
+  }
+
+  fn message(&mut self, field: u64, message: Message) {
+    self.bytes(field, &message.0);
+  }
+
+  fn packed(&mut self, field: u64, values: impl IntoIterator<Item = u64>) {
+    let mut packed = Message::default();
+    for value in values {
+      packed.varint(value);
+    }
+    self.bytes(field, &packed.0);
+  }
+}
+
+#[derive(Default)]
+struct Strings {
+  table: Vec<String>,
+  index: HashMap<String, u64>,
+}
+
+impl Strings {
+  fn id(&mut self, string: &str) -> u64 {
+    if self.table.is_empty() {
+      self.table.push(String::new());
+      self.index.insert(String::new(), 0);
+    }
+    if let Some(&id) = self.index.get(string) {
+      return id;
+    }
+    let id = self.table.len() as u64;
+    self.table.push(string.to_owned());
+    self.index.insert(string.to_owned(), id);
+    id
+  }
+}
+
+fn encode_pprof(
+  kind: ProfileKind,
+  stacks: &[(Vec<usize>, Totals)],
+  symbols: &Symbols,
+) -> Vec<u8> {
+  let mut strings = Strings::default();
+  let mut profile = Message::default();
+  let (objects, space) = match kind {
+    ProfileKind::Live => ("inuse_objects", "inuse_space"),
+    ProfileKind::Cumulative => ("alloc_objects", "alloc_space"),
+  };
+  for (name, unit) in [(objects, "count"), (space, "bytes")] {
+    let mut value_type = Message::default();
+    value_type.int(1, strings.id(name));
+    value_type.int(2, strings.id(unit));
+    profile.message(1, value_type);
+  }
+
+  let mut locations = Message::default();
+  let mut location_ids = HashMap::<usize, u64>::new();
+  let mut functions = Message::default();
+  let mut function_ids = HashMap::<(&str, &str), u64>::new();
+  for (ips, totals) in stacks {
+    let mut sample_locations = Vec::with_capacity(ips.len());
+    for &ip in ips {
+      let next_id = location_ids.len() as u64 + 1;
+      let id = *location_ids.entry(ip).or_insert_with(|| {
+        let mut location = Message::default();
+        location.int(1, next_id);
+        location.int(3, ip as u64);
+        for frame in &symbols[&ip] {
+          let key = (frame.name.as_str(), frame.file.as_str());
+          let next_id = function_ids.len() as u64 + 1;
+          let function_id = *function_ids.entry(key).or_insert_with(|| {
+            let mut function = Message::default();
+            function.int(1, next_id);
+            function.int(2, strings.id(&frame.name));
+            function.int(3, strings.id(&frame.name));
+            function.int(4, strings.id(&frame.file));
+            functions.message(5, function);
+            next_id
+          });
+          let mut line = Message::default();
+          line.int(1, function_id);
+          line.int(2, frame.line);
+          location.message(4, line);
+        }
+        locations.message(4, location);
+        next_id
+      });
+      sample_locations.push(id);
+    }
+
+    let mut sample = Message::default();
+    sample.packed(1, sample_locations);
+    sample.packed(2, [totals.objects, totals.bytes]);
+    profile.message(2, sample);
+  }
+
+  let time = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |time| time.as_nanos() as u64);
+  profile.int(9, time);
+  let mut period_type = Message::default();
+  period_type.int(1, strings.id("space"));
+  period_type.int(2, strings.id("bytes"));
+  profile.message(11, period_type);
+  profile.int(12, INTERVAL.load(Ordering::Relaxed) as u64);
+
+  profile.0.extend_from_slice(&locations.0);
+  profile.0.extend_from_slice(&functions.0);
+  for string in &strings.table {
+    profile.bytes(6, string.as_bytes());
+  }
+  profile.0
+}
+
+/// Writes the sampled heap profile to `out`. Values are estimates for
+/// every allocation, scaled up from the samples, and stacks are resolved
+/// against the running binary's debug info.
+pub fn write_profile(
+  kind: ProfileKind,
+  format: ProfileFormat,
+  out: &mut impl Write,
+) -> io::Result<()> {
+  unsampled(|| {
+    let stacks = PROFILE
+      .lock()
+      .as_ref()
+      .map_or_else(Vec::new, |tables| tables.snapshot(kind));
+    let (stacks, symbols) = resolve(stacks);
+    match format {
+      ProfileFormat::Pprof => {
+        out.write_all(&encode_pprof(kind, &stacks, &symbols))
+      }
+      ProfileFormat::Collapsed => write_collapsed(&stacks, &symbols, out),
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::alloc::{
+    GlobalAlloc,
+    Layout,
+  };
+
+  use super::*;
+  use crate::TinyAlloc;
+
+  #[test]
+  fn tables_track_live_and_cumulative_totals() {
+    let mut tables = Tables::new(16, 16).unwrap();
+    let weight = Totals {
+      objects: 2,
+      bytes: 100,
+    };
+    let addresses: Vec<usize> = (1..=12).map(|i| i * 64).collect();
+    for (index, &address) in addresses.iter().enumerate() {
+      let frames: &[usize] = if index % 2 == 0 { &[1, 2] } else { &[3] };
+      assert!(tables.record(address, frames, weight));
+    }
+    assert!(!tables.record(13 * 64, &[1, 2], weight), "table is full");
+
+    // Removing out of insertion order must keep every other entry findable.
+    for &address in addresses.iter().rev().step_by(3) {
+      assert!(tables.remove(address).is_some());
+      assert!(tables.remove(address).is_none());
+    }
+    for &address in addresses.iter().rev().skip(1).step_by(3) {
+      assert!(tables.remove(address).is_some());
+    }
+
+    let live = tables.snapshot(ProfileKind::Live);
+    let cumulative = tables.snapshot(ProfileKind::Cumulative);
+    let total = |stacks: &[(Vec<usize>, Totals)]| {
+      stacks.iter().map(|(_, totals)| totals.bytes).sum::<u64>()
+    };
+    assert_eq!(total(&live), 400);
+    assert_eq!(total(&cumulative), 1300);
+    assert_eq!(cumulative.len(), 2);
+  }
+
+  #[test]
+  fn sampler_samples_about_once_per_interval() {
+    let sampler = Sampler {
+      left: Cell::new(0),
+      seed: Cell::new(0),
+      busy: Cell::new(false),
+    };
+    let samples = (0..1_000_000).filter(|_| sampler.sample(100, 1000)).count();
+    assert!((90_000..110_000).contains(&samples), "{samples} samples");
+
+    let weight = Totals::sampled(100, 1000);
+    assert_eq!(weight.objects, 11);
+    assert!((1000..1100).contains(&weight.bytes));
+  }
+
+  const BLOCK: Layout = Layout::new::<[u8; 4096]>();
+
+  #[inline(never)]
+  fn allocate_profiled() -> Vec<usize> {
+    (0..16 * 1024)
+      .map(|_| unsafe { TinyAlloc.alloc(BLOCK) } as usize)
+      .collect()
+  }
+
+  #[test]
+  fn profile_attributes_live_bytes_to_the_allocating_function() {
+    let _sampling = SAMPLING.lock();
+    set_profile_interval(PROFILE_INTERVAL);
+    let blocks = allocate_profiled();
+
+    let mut collapsed = Vec::new();
+    write_profile(ProfileKind::Live, ProfileFormat::Collapsed, &mut collapsed)
+      .unwrap();
+    let collapsed = String::from_utf8(collapsed).unwrap();
+    let profiled = collapsed
+      .lines()
+      .filter(|line| line.contains("allocate_profiled"))
+      .map(|line| line.rsplit_once(' ').unwrap().1.parse::<u64>().unwrap())
+      .sum::<u64>();
+    assert!(profiled > 16 << 20, "{profiled} bytes in\n{collapsed}");
+    assert!(
+      collapsed
+        .lines()
+        .all(|line| !line.contains("GlobalAlloc>::alloc"))
+    );
+
+    let mut pprof = Vec::new();
+    write_profile(ProfileKind::Cumulative, ProfileFormat::Pprof, &mut pprof)
+      .unwrap();
+    let contains =
+      |needle: &[u8]| pprof.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(b"alloc_space"));
+    assert!(contains(b"allocate_profiled"));
+
+    set_profile_interval(0);
+    for block in blocks {
+      unsafe { TinyAlloc.dealloc(block as *mut u8, BLOCK) };
+    }
+  }
+}
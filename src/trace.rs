@@ -0,0 +1,507 @@
+use std::{
+  cell::Cell,
+  ffi::CStr,
+  fs::File,
+  io::{
+    self,
+    Read,
+    Write,
+  },
+  num::NonZeroUsize,
+  ptr::NonNull,
+  sync::{
+    OnceLock,
+    atomic::{
+      AtomicBool,
+      Ordering,
+    },
+  },
+  time::Instant,
+};
+
+use spin::Mutex;
+use tinyalloc_alloc::{
+  allocation::{
+    Allocation,
+    AllocationOwner,
+  },
+  guarded,
+  static_::segment_from_ptr,
+};
+use tinyalloc_config::config::SIZES;
+use tinyalloc_sys::{
+  GLOBAL_MAPPER,
+  mapper::Protection,
+  process::{
+    at_exit,
+    env,
+    write_stderr,
+  },
+  thread,
+};
+
+use crate::init::td_register;
+
+/// Path of a file to write a binary trace of every allocation to.
+const TRACE_ENV: &CStr = c"TINYALLOC_TRACE";
+/// First bytes of every trace file, ahead of its events.
+pub const TRACE_MAGIC: [u8; 8] = *b"TATRACE2";
+/// Bytes each event takes in a trace file.
+pub const EVENT_SIZE: usize = 48;
+/// Bytes of events each thread buffers before writing them out.
+const BUFFER_SIZE: usize = 64 * 1024;
+const BUFFER_EVENTS: usize = BUFFER_SIZE / EVENT_SIZE;
+// Small events store their class id in two bytes.
+const _: () = assert!(SIZES <= 1 << u16::BITS);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+static START: OnceLock<Instant> = OnceLock::new();
+static OUTPUT: Mutex<Option<File>> = Mutex::new(None);
+
+/// What the program asked of the allocator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+  Alloc,
+  AllocZeroed,
+  Dealloc,
+  Realloc,
+}
+
+/// Where the block an event is about came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventPath {
+  /// A slot of the small size class with this id.
+  Small(u16),
+  /// A large block carved from an arena.
+  Large,
+  /// A block mapped straight from the OS.
+  Mapper,
+  /// A block from the guarded pool.
+  Guarded,
+  /// A failed allocation, or a pointer tinyalloc does not own.
+  Unknown,
+}
+
+/// One allocator call, as written to a trace file.
+///
+/// `ptr` is the block handed out or freed. For a realloc it is the new
+/// block, `old` the one it replaced and `size` the new size. `path` is
+/// where the block in `ptr` came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+  /// Nanoseconds since tracing started.
+  pub time: u64,
+  pub thread: u64,
+  pub kind: EventKind,
+  pub path: EventPath,
+  pub ptr: u64,
+  pub old: u64,
+  pub size: u64,
+  pub align: u64,
+}
+
+impl Event {
+  /// Little-endian, fixed size, so a trace can be read back on any host.
+  pub fn encode(&self) -> [u8; EVENT_SIZE] {
+    let mut bytes = [0; EVENT_SIZE];
+    bytes[0..8].copy_from_slice(&self.time.to_le_bytes());
+    bytes[8..16].copy_from_slice(&self.thread.to_le_bytes());
+    bytes[16..24].copy_from_slice(&self.ptr.to_le_bytes());
+    bytes[24..32].copy_from_slice(&self.old.to_le_bytes());
+    bytes[32..40].copy_from_slice(&self.size.to_le_bytes());
+    bytes[40] = self.kind as u8;
+    let class = match self.path {
+      EventPath::Small(class) => class,
+      _ => 0,
+    };
+    bytes[41] = match self.path {
+      EventPath::Small(_) => 0,
+      EventPath::Large => 1,
+      EventPath::Mapper => 2,
+      EventPath::Guarded => 3,
+      EventPath::Unknown => 4,
+    };
+    bytes[43] = self.align.trailing_zeros() as u8;
+    bytes[44..46].copy_from_slice(&class.to_le_bytes());
+    bytes
+  }
+
+  /// Reads back an event written by [`Event::encode`], or `None` if the
+  /// bytes do not hold one.
+  pub fn decode(bytes: &[u8; EVENT_SIZE]) -> Option<Self> {
+    let word = |at: usize| {
+      u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap_or_default())
+    };
+    let kind = match bytes[40] {
+      0 => EventKind::Alloc,
+      1 => EventKind::AllocZeroed,
+      2 => EventKind::Dealloc,
+      3 => EventKind::Realloc,
+      _ => return None,
+    };
+    let path = match bytes[41] {
+      0 => EventPath::Small(u16::from_le_bytes([bytes[44], bytes[45]])),
+      1 => EventPath::Large,
+      2 => EventPath::Mapper,
+      3 => EventPath::Guarded,
+      4 => EventPath::Unknown,
+      _ => return None,
+    };
+    Some(Self {
+      time: word(0),
+      thread: word(8),
+      kind,
+      path,
+      ptr: word(16),
+      old: word(24),
+      size: word(32),
+      align: 1u64.checked_shl(u32::from(bytes[43]))?,
+    })
+  }
+}
+
+/// Reads the events of a trace file in the order they were written.
+///
+/// Each thread's events are in the order it made them, but threads write
+/// theirs out in batches; sort by [`Event::time`] to interleave them.
+pub struct TraceReader<R> {
+  inner: R,
+}
+
+impl<R: Read> TraceReader<R> {
+  /// Checks that `inner` starts with [`TRACE_MAGIC`].
+  pub fn new(mut inner: R) -> io::Result<Self> {
+    let mut magic = [0; TRACE_MAGIC.len()];
+    inner.read_exact(&mut magic)?;
+    if magic != TRACE_MAGIC {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "not a tinyalloc trace",
+      ));
+    }
+    Ok(Self { inner })
+  }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+  type Item = io::Result<Event>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let mut bytes = [0; EVENT_SIZE];
+    let mut read = 0;
+    while read < EVENT_SIZE {
+      match self.inner.read(&mut bytes[read..]) {
+        Ok(0) if read == 0 => return None,
+        Ok(0) => return Some(Err(io::ErrorKind::UnexpectedEof.into())),
+        Ok(count) => read += count,
+        Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+        Err(error) => return Some(Err(error)),
+      }
+    }
+    Some(Event::decode(&bytes).ok_or_else(|| {
+      io::Error::new(io::ErrorKind::InvalidData, "malformed trace event")
+    }))
+  }
+}
+
+/// Where the block at `block` came from. Must run before a block is freed.
+fn path_of(block: *mut u8) -> EventPath {
+  let Some(ptr) = NonNull::new(block) else {
+    return EventPath::Unknown;
+  };
+  if guarded::contains(ptr) {
+    return EventPath::Guarded;
+  }
+  if let Some(segment) = segment_from_ptr(ptr) {
+    return EventPath::Small(unsafe { segment.as_ref() }.class().id as u16);
+  }
+  match Allocation::from(block).map(|header| unsafe { &*header }.owned()) {
+    Some(AllocationOwner::Heap(_)) => EventPath::Large,
+    Some(AllocationOwner::Mapper(_)) => EventPath::Mapper,
+    None => EventPath::Unknown,
+  }
+}
+
+/// Events the calling thread has recorded but not yet written out. The
+/// buffer is mapped straight from the OS, so recording never allocates.
+struct Buffer {
+  data: Cell<Option<NonNull<[u8]>>>,
+  len: Cell<usize>,
+}
+
+thread_local! {
+  static BUFFER: Buffer = const {
+    Buffer {
+      data: Cell::new(None),
+      len: Cell::new(0),
+    }
+  };
+}
+
+impl Buffer {
+  fn push(&self, event: &Event) {
+    let data = match self.data.get() {
+      Some(data) => data,
+      None => {
+        let Some(data) = map_buffer() else {
+          write_events(&event.encode());
+          return;
+        };
+        self.data.set(Some(data));
+        data
+      }
+    };
+
+    let len = self.len.get();
+    unsafe {
+      data
+        .cast::<u8>()
+        .add(len * EVENT_SIZE)
+        .cast::<[u8; EVENT_SIZE]>()
+        .write(event.encode());
+    }
+    self.len.set(len + 1);
+    if len + 1 == BUFFER_EVENTS {
+      self.flush();
+    }
+  }
+
+  fn flush(&self) {
+    let len = self.len.replace(0);
+    if let Some(data) = self.data.get()
+      && len > 0
+    {
+      let bytes = unsafe {
+        std::slice::from_raw_parts(data.cast::<u8>().as_ptr(), len * EVENT_SIZE)
+      };
+      write_events(bytes);
+    }
+  }
+}
+
+impl Drop for Buffer {
+  fn drop(&mut self) {
+    self.flush();
+    if let Some(data) = self.data.take() {
+      GLOBAL_MAPPER.unmap(data);
+    }
+  }
+}
+
+fn map_buffer() -> Option<NonNull<[u8]>> {
+  let size = NonZeroUsize::new(BUFFER_SIZE)?;
+  let data = GLOBAL_MAPPER.map(size).ok()?;
+  if GLOBAL_MAPPER
+    .protect(data, Protection::Read | Protection::Write)
+    .is_err()
+  {
+    GLOBAL_MAPPER.unmap(data);
+    return None;
+  }
+  Some(data)
+}
+
+/// Appends whole events to the trace file. Tracing stops if the file
+/// cannot take them.
+fn write_events(bytes: &[u8]) {
+  let mut output = OUTPUT.lock();
+  if let Some(file) = output.as_mut()
+    && file.write_all(bytes).is_err()
+  {
+    ENABLED.store(false, Ordering::Relaxed);
+    *output = None;
+    write_stderr(b"tinyalloc: trace write failed, tracing stopped\n");
+  }
+}
+
+#[inline]
+pub fn enabled() -> bool {
+  ENABLED.load(Ordering::Relaxed)
+}
+
+/// Records an allocator call when tracing is on. `block` is the start of
+/// the underlying allocation, which tells the path; it differs from `ptr`
+/// only when a caller adds a header of its own.
+#[inline]
+pub fn record(
+  kind: EventKind,
+  ptr: *mut u8,
+  old: *mut u8,
+  size: usize,
+  align: usize,
+  block: *mut u8,
+) {
+  if enabled() {
+    push(kind, ptr, old, size, align, block);
+  }
+}
+
+#[inline(never)]
+fn push(
+  kind: EventKind,
+  ptr: *mut u8,
+  old: *mut u8,
+  size: usize,
+  align: usize,
+  block: *mut u8,
+) {
+  let event = Event {
+    time: START
+      .get()
+      .map_or(0, |start| start.elapsed().as_nanos() as u64),
+    thread: thread::current_id(),
+    kind,
+    path: path_of(block),
+    ptr: ptr as u64,
+    old: old as u64,
+    size: size as u64,
+    align: align as u64,
+  };
+  // Before the thread's buffer can be set up and once it is gone, events
+  // go out one by one.
+  if !td_register() || BUFFER.try_with(|buffer| buffer.push(&event)).is_err() {
+    write_events(&event.encode());
+  }
+}
+
+/// Touches the calling thread's buffer so its destructor is registered.
+pub(crate) fn register() {
+  let _ = BUFFER.try_with(|_| {});
+}
+
+/// Writes out the events the calling thread has buffered. Other threads
+/// write theirs when their buffer fills or they exit.
+pub fn flush_trace() {
+  if td_register() {
+    let _ = BUFFER.try_with(Buffer::flush);
+  }
+}
+
+extern "C" fn finish_at_exit() {
+  ENABLED.store(false, Ordering::Relaxed);
+  flush_trace();
+}
+
+/// Stops tracing and closes the trace file once the calling thread's
+/// buffered events are written. Other threads' buffered events are lost.
+pub fn disable_trace() {
+  ENABLED.store(false, Ordering::Relaxed);
+  flush_trace();
+  if let Some(mut file) = OUTPUT.lock().take() {
+    let _ = file.flush();
+  }
+}
+
+/// Starts tracing every allocation to `file`, which should be empty. The
+/// calling thread's buffered events go out when the process exits, those
+/// of other threads when they exit; threads still running at exit lose
+/// theirs unless they call [`flush_trace`].
+pub fn enable_trace(mut file: File) -> io::Result<()> {
+  file.write_all(&TRACE_MAGIC)?;
+  START.get_or_init(Instant::now);
+  flush_trace();
+  *OUTPUT.lock() = Some(file);
+  if !REGISTERED.swap(true, Ordering::AcqRel) {
+    at_exit(finish_at_exit);
+  }
+  ENABLED.store(true, Ordering::Relaxed);
+  Ok(())
+}
+
+/// Starts tracing when the environment names a trace file.
+pub fn init_from_env() {
+  let Some(path) = env(TRACE_ENV).and_then(|value| value.to_str().ok()) else {
+    return;
+  };
+  if path.is_empty() {
+    return;
+  }
+  if File::create(path).and_then(enable_trace).is_err() {
+    write_stderr(b"tinyalloc: cannot open trace file, tracing disabled\n");
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::alloc::{
+    GlobalAlloc,
+    Layout,
+  };
+
+  use tinyalloc_config::classes::find_class;
+
+  use super::*;
+  use crate::TinyAlloc;
+
+  #[test]
+  fn events_roundtrip_through_a_trace_file() {
+    let events = [
+      Event {
+        time: 1,
+        thread: 7,
+        kind: EventKind::AllocZeroed,
+        path: EventPath::Small(12),
+        ptr: 0x1000,
+        old: 0,
+        size: 96,
+        align: 8,
+      },
+      Event {
+        time: 2,
+        thread: 7,
+        kind: EventKind::Dealloc,
+        path: EventPath::Small(300),
+        ptr: 0x1000,
+        old: 0,
+        size: 96,
+        align: 8,
+      },
+      Event {
+        time: 3,
+        thread: 8,
+        kind: EventKind::Realloc,
+        path: EventPath::Mapper,
+        ptr: 0x7000_0000,
+        old: 0x1000,
+        size: 1 << 30,
+        align: 1 << 20,
+      },
+    ];
+    let mut file = TRACE_MAGIC.to_vec();
+    for event in &events {
+      file.extend_from_slice(&event.encode());
+    }
+
+    let read: Vec<Event> = TraceReader::new(file.as_slice())
+      .unwrap()
+      .collect::<io::Result<_>>()
+      .unwrap();
+    assert_eq!(read, events);
+
+    file.pop();
+    let read: Vec<_> = TraceReader::new(file.as_slice()).unwrap().collect();
+    assert_eq!(read.len(), events.len());
+    assert!(read[..events.len() - 1].iter().all(Result::is_ok));
+    assert!(read[events.len() - 1].is_err());
+    assert!(TraceReader::new(&b"not a trace"[..]).is_err());
+  }
+
+  #[test]
+  fn path_of_tells_where_a_block_came_from() {
+    let small = Layout::from_size_align(96, 8).unwrap();
+    let large = Layout::from_size_align(1 << 20, 8).unwrap();
+    let class = find_class(small.size(), small.align()).unwrap();
+
+    let small_ptr = unsafe { TinyAlloc.alloc(small) };
+    let large_ptr = unsafe { TinyAlloc.alloc(large) };
+    assert_eq!(path_of(small_ptr), EventPath::Small(class.id as u16));
+    assert_eq!(path_of(large_ptr), EventPath::Large);
+    assert_eq!(path_of(std::ptr::null_mut()), EventPath::Unknown);
+
+    unsafe {
+      TinyAlloc.dealloc(small_ptr, small);
+      TinyAlloc.dealloc(large_ptr, large);
+    }
+  }
+}
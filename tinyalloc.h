@@ -12,6 +12,23 @@
 #include <stdint.h>
 #include <stdlib.h>
 
+#define LEAK_EXIT_CODE 23
+
+/**
+ * Times the handler is called for one failed allocation by default.
+ */
+#define OOM_RETRIES 3
+
+/**
+ * Mean number of bytes allocated between two samples by default.
+ */
+#define PROFILE_INTERVAL (512 * 1024)
+
+/**
+ * Bytes each event takes in a trace file.
+ */
+#define EVENT_SIZE 48
+
 void *malloc(size_t size);
 
 void *calloc(size_t nmemb, size_t size);
@@ -26,4 +43,10 @@ void *realloc(void *ptr, size_t size);
 
 size_t malloc_usable_size(void *ptr);
 
+/**
+ * Releases cached memory back to the OS. `pad` is accepted for glibc
+ * compatibility and ignored. Returns 1 if any memory was released.
+ */
+int malloc_trim(size_t pad);
+
 #endif  /* TINYALLOC_H */